@@ -0,0 +1,1948 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    fs::File,
+    io::Read,
+    num::NonZeroU64,
+    ops::Deref,
+    path::Path,
+    rc::Rc,
+    time::Duration,
+};
+
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+use tracing_serde_structured as tss;
+
+use crate::{cursor::SpanCursor, format::Source, Location, Report, ReportError, ReportPayload, ReportReader, ValueExt};
+
+/// A decoded report, and the input it came from.
+#[derive(Clone)]
+pub struct Element {
+    pub rpt: Rc<Report<'static>>,
+    /// Which input this report came from, when several captures are merged.
+    pub source: usize,
+}
+
+impl Element {
+    pub fn thread_key(&self) -> ThreadKey {
+        ThreadKey {
+            source: self.source,
+            thread_id: self.thread_id,
+        }
+    }
+}
+
+impl Deref for Element {
+    type Target = Report<'static>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rpt
+    }
+}
+
+/// Thread ids are only unique within one capture, so merged captures key
+/// threads on their source as well.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ThreadKey {
+    pub source: usize,
+    pub thread_id: u64,
+}
+
+impl fmt::Display for ThreadKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.source == 0 {
+            write!(f, "{}", self.thread_id)
+        } else {
+            write!(f, "{}:{}", self.source, self.thread_id)
+        }
+    }
+}
+
+/// Every report from one or more streams, in order, for analysis.
+pub struct Elements {
+    pub rpts: Vec<Element>,
+    /// The process behind each input, indexed by `Element::source`. `None`
+    /// for streams older than format version 5.
+    pub sources: Vec<Option<Source>>,
+}
+
+/// The span trees of one thread, rebuilt from its enters and exits by
+/// `Elements::spanner`.
+#[derive(Serialize, Deserialize)]
+pub struct TlSpans {
+    pub spans: Vec<Span>,
+    /// Events recorded outside any span.
+    #[serde(with = "crate::owned::events")]
+    pub events: Vec<tss::SerializeEvent<'static>>,
+    pub diagnostics: Diagnostics,
+}
+
+/// Problems found while pairing enters with exits. Traces from crashed or
+/// aborted tasks are routinely unbalanced, so these are reported rather than
+/// treated as fatal.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Diagnostics {
+    /// Exits with no matching open span, which are ignored.
+    pub orphan_exits: usize,
+    /// Spans that never exited. They end when an enclosing span exits, or at
+    /// the last tick of the trace.
+    pub unclosed_spans: usize,
+    /// Spans that exited before they were entered, going by their ticks.
+    /// Their end is set to their start.
+    pub backwards_spans: usize,
+    /// Spans longer than `MAX_PLAUSIBLE_DURATION`.
+    pub absurd_spans: usize,
+    /// Spans entered with no `OnNewSpan` for them. They're left out, and
+    /// what happened inside them goes to the enclosing span.
+    pub unknown_spans: usize,
+    /// Spans nested deeper than the maximum depth, left out the same way.
+    pub too_deep_spans: usize,
+}
+
+/// Anything longer than this is more likely a clock problem than a real
+/// span.
+pub const MAX_PLAUSIBLE_DURATION: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+/// How deep `Elements::spanner` nests spans. Reconstruction itself doesn't
+/// recurse, but walking, serializing and dropping a span tree does, so an
+/// untrusted trace could otherwise overflow the stack.
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+impl Diagnostics {
+    pub fn is_clean(&self) -> bool {
+        self.orphan_exits == 0 && self.unclosed_spans == 0
+    }
+
+    pub fn has_clock_anomalies(&self) -> bool {
+        self.backwards_spans != 0 || self.absurd_spans != 0
+    }
+}
+
+/// One entry into a span, from enter to exit, with everything recorded
+/// inside it. Serializes with its duration and event counts as well.
+#[derive(Deserialize)]
+pub struct Span {
+    pub start: u64,
+    pub end: u64,
+    pub spans: Vec<Span>,
+    #[serde(deserialize_with = "crate::owned::events::deserialize")]
+    pub events: Vec<tss::SerializeEvent<'static>>,
+    #[serde(deserialize_with = "crate::owned::attrs::deserialize")]
+    pub attrs: tss::SerializeAttributes<'static>,
+    /// Field values, from creation and later `record` calls, by name.
+    pub fields: Vec<(String, String)>,
+    /// Number of spans this one is nested in.
+    pub depth: usize,
+    /// Whether the span has no parent at all, unlike a top-level span of
+    /// one thread whose parent is on another thread, or had already exited.
+    pub is_root: bool,
+    /// Locations of the enclosing spans, outermost first. Only filled in by
+    /// `Elements::spanner_with_ancestors` and `Elements::spans_by_thread`.
+    pub ancestors: Option<Vec<String>>,
+    /// CPU time the thread spent inside the span, for captures with
+    /// `record_cpu_time`. Spans timed from creation to close add up all
+    /// their enters.
+    #[serde(default)]
+    pub cpu_ns: Option<u64>,
+}
+
+impl Serialize for Span {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (own_events, child_events) = self.count_events_rec();
+        let mut s = serializer.serialize_struct("Span", 13)?;
+        s.serialize_field("start", &self.start)?;
+        s.serialize_field("end", &self.end)?;
+        s.serialize_field("duration", &(self.end - self.start))?;
+        s.serialize_field("own_events", &own_events)?;
+        s.serialize_field("child_events", &child_events)?;
+        s.serialize_field("spans", &self.spans)?;
+        s.serialize_field("events", &self.events)?;
+        s.serialize_field("attrs", &self.attrs)?;
+        s.serialize_field("fields", &self.fields)?;
+        s.serialize_field("depth", &self.depth)?;
+        s.serialize_field("is_root", &self.is_root)?;
+        s.serialize_field("ancestors", &self.ancestors)?;
+        s.serialize_field("cpu_ns", &self.cpu_ns)?;
+        s.end()
+    }
+}
+
+impl TlSpans {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("span trees always serialize")
+    }
+
+    /// Like `Span::prune_shorter_than`, for every span in the trees. The
+    /// events of top-level spans that are removed go to `events`.
+    pub fn prune_shorter_than(&mut self, min_ns: u64) -> usize {
+        let (spans, pruned) = prune(std::mem::take(&mut self.spans), min_ns, &mut self.events);
+        self.spans = spans;
+        pruned
+    }
+}
+
+/// Removes the spans shorter than `min_ns` from `spans`, at any depth,
+/// moving their events to `events`. Returns the spans left, and how many
+/// were removed.
+fn prune(spans: Vec<Span>, min_ns: u64, events: &mut Vec<tss::SerializeEvent<'static>>) -> (Vec<Span>, usize) {
+    let mut kept = vec![];
+    let mut pruned = 0;
+    for mut span in spans {
+        pruned += span.prune_shorter_than(min_ns);
+        if span.end - span.start >= min_ns {
+            kept.push(span);
+            continue;
+        }
+        pruned += 1;
+        events.append(&mut span.events);
+        for child in span.spans.iter_mut() {
+            child.lift(span.depth);
+        }
+        kept.append(&mut span.spans);
+    }
+    (kept, pruned)
+}
+
+impl Span {
+    pub fn location(&self) -> String {
+        Location::of(&self.attrs.metadata).to_string()
+    }
+
+    /// What exporters group the span under. A span without the field given
+    /// to `CategoryBy::Field` goes by its target.
+    pub fn category(&self, by: &CategoryBy) -> String {
+        match by {
+            CategoryBy::Field(name) => match self.fields.iter().find(|(key, _)| key == name) {
+                Some((_, val)) => val.clone(),
+                None => self.attrs.metadata.target.to_string(),
+            },
+            CategoryBy::Target => self.attrs.metadata.target.to_string(),
+            CategoryBy::Location => self.location(),
+        }
+    }
+
+    /// The span's name, or its location for spans without one, followed by
+    /// its field values.
+    pub fn label(&self) -> String {
+        let mut label = match self.attrs.metadata.name.as_str() {
+            "" => self.location(),
+            name => name.to_string(),
+        };
+        for (key, val) in self.fields.iter() {
+            label.push_str(&format!(" {}={}", key, val));
+        }
+        label
+    }
+
+    pub fn count_events_rec(&self) -> (usize, usize) {
+        let own_events = self.events.len();
+        let mut child_events = 0;
+        self.spans.iter().for_each(|s| {
+            let (own, child) = s.count_events_rec();
+            child_events += own;
+            child_events += child;
+        });
+        (own_events, child_events)
+    }
+
+    /// Spans directly inside this one, and spans nested at any depth,
+    /// including the direct ones.
+    pub fn count_spans_rec(&self) -> (usize, usize) {
+        let own_spans = self.spans.len();
+        let all_spans = own_spans + self.spans.iter().map(|s| s.count_spans_rec().1).sum::<usize>();
+        (own_spans, all_spans)
+    }
+
+    /// Calls `f` with this span and every span nested in it, parents first.
+    pub fn walk<'s>(&'s self, f: &mut impl FnMut(&'s Span)) {
+        f(self);
+        for span in self.spans.iter() {
+            span.walk(f);
+        }
+    }
+
+    /// This span and every span nested in it that match `predicate`,
+    /// parents first.
+    pub fn find_all(&self, predicate: impl Fn(&Span) -> bool) -> Vec<&Span> {
+        let mut found = vec![];
+        self.walk(&mut |span| {
+            if predicate(span) {
+                found.push(span);
+            }
+        });
+        found
+    }
+
+    /// Removes the spans nested in this one, at any depth, that lasted less
+    /// than `min_ns`, and returns how many. What happened inside a removed
+    /// span is folded into its parent: its events are added to the
+    /// parent's, and the spans nested in it that lasted long enough, which
+    /// only happens when spans are timed from creation to close, take its
+    /// place. Its time stays in the parent's duration, as the parent's own.
+    pub fn prune_shorter_than(&mut self, min_ns: u64) -> usize {
+        let (spans, pruned) = prune(std::mem::take(&mut self.spans), min_ns, &mut self.events);
+        self.spans = spans;
+        pruned
+    }
+
+    /// Moves this span and everything in it up a level, out of the span at
+    /// `depth`.
+    fn lift(&mut self, depth: usize) {
+        self.depth -= 1;
+        if let Some(ancestors) = self.ancestors.as_mut().filter(|ancestors| depth < ancestors.len()) {
+            ancestors.remove(depth);
+        }
+        for span in self.spans.iter_mut() {
+            span.lift(depth);
+        }
+    }
+
+    /// Folds `f` over this span and every span nested in it, parents first.
+    pub fn fold<T>(&self, init: T, f: &mut impl FnMut(T, &Span) -> T) -> T {
+        let acc = f(init, self);
+        self.spans.iter().fold(acc, |acc, span| span.fold(acc, f))
+    }
+
+    /// The span as a Chrome trace "complete" event on `thread`, with its
+    /// fields as `args`. The spans nested in it aren't included; use `walk`
+    /// (or `find_all`) to export those too.
+    pub fn to_chrome_complete_event(&self, thread: ThreadKey) -> serde_json::Value {
+        self.to_chrome_complete_event_with(thread, &ExportConfig::default())
+    }
+
+    /// Like `to_chrome_complete_event`, with its `cat` chosen by `cfg`.
+    pub fn to_chrome_complete_event_with(&self, thread: ThreadKey, cfg: &ExportConfig) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.location(),
+            "cat": self.category(&cfg.category_by),
+            "ph": "X",
+            "ts": self.start as f64 / 1000.0,
+            "dur": (self.end - self.start) as f64 / 1000.0,
+            "pid": thread.source,
+            "tid": thread.thread_id,
+            "args": self.fields.iter().cloned().collect::<BTreeMap<_, _>>(),
+        })
+    }
+}
+
+/// Sorts by tick, with `seq` breaking ties. Each source's ticks count from
+/// its own start, so they're lined up by when each started, where the
+/// stream says; streams older than format version 17 keep their bare ticks.
+fn sort_by_wall_time(rpts: &mut [Element], sources: &[Option<Source>]) {
+    let start = |source: usize| {
+        sources
+            .get(source)
+            .and_then(|src| src.as_ref()?.start_wall_ns)
+            .unwrap_or(0)
+    };
+    rpts.sort_by_key(|rpt| (start(rpt.source).saturating_add(rpt.tick), rpt.seq));
+}
+
+/// Span ids reassigned with `OnIdChange`, mapped back to the id the span
+/// was created with.
+#[derive(Default)]
+pub(crate) struct IdRemap {
+    aliases: HashMap<NonZeroU64, NonZeroU64>,
+}
+
+impl IdRemap {
+    /// Call with every report, in order.
+    pub(crate) fn note(&mut self, payload: &ReportPayload<'_>) {
+        match payload {
+            ReportPayload::OnIdChange { old, new } => {
+                let original = self.resolve(old.id);
+                self.aliases.insert(new.id, original);
+            },
+            // The id was freed and reused for a new span.
+            ReportPayload::OnNewSpan { id, .. } => {
+                self.aliases.remove(&id.id);
+            },
+            _ => {},
+        }
+    }
+
+    pub(crate) fn resolve(&self, id: NonZeroU64) -> NonZeroU64 {
+        self.aliases.get(&id).copied().unwrap_or(id)
+    }
+}
+
+/// Takes the thread an exit on `thread` pairs with out of the threads a
+/// span is entered on: `thread` itself if it entered the span, and
+/// otherwise the last one to.
+pub(crate) fn pop_entered(threads: &mut Vec<ThreadKey>, thread: ThreadKey) -> Option<ThreadKey> {
+    let pos = threads
+        .iter()
+        .rposition(|entered| *entered == thread)
+        .or(threads.len().checked_sub(1))?;
+    Some(threads.remove(pos))
+}
+
+/// What `Capture` knows of every span, from all threads.
+struct SpanInfo {
+    attrs: HashMap<SpanKey, tss::SerializeAttributes<'static>>,
+    fields: HashMap<SpanKey, BTreeMap<String, String>>,
+    parents: HashMap<SpanKey, SpanKey>,
+}
+
+/// Rebuilds span trees from reports, without recursing, so a deeply nested
+/// trace can't overflow the stack.
+struct Capture<'a> {
+    /// What the reports themselves say about their spans, and what the
+    /// whole capture does, for spans created on another thread. Span ids
+    /// can repeat across processes sharing a stream, so the former wins.
+    own: &'a SpanInfo,
+    all: &'a SpanInfo,
+    /// Spans entered and not exited yet, innermost last.
+    open: Vec<Open<'a>>,
+    ids: IdRemap,
+    /// Unknown or too deep spans that were entered, and not exited yet.
+    skipped: Vec<NonZeroU64>,
+    /// The span ended by the last report, if it was an exit, for its
+    /// `CpuTime` right after.
+    exited: Option<NonZeroU64>,
+    first_unknown: Option<NonZeroU64>,
+    ancestry: bool,
+    max_depth: usize,
+    last_tick: u64,
+    diagnostics: Diagnostics,
+    /// Top-level spans and events, outside any open span.
+    spans: Vec<Span>,
+    events: Vec<tss::SerializeEvent<'static>>,
+}
+
+struct Open<'a> {
+    key: SpanKey,
+    attrs: &'a tss::SerializeAttributes<'static>,
+    start: u64,
+    ancestors: Option<Vec<String>>,
+    spans: Vec<Span>,
+    events: Vec<tss::SerializeEvent<'static>>,
+}
+
+impl<'a> Capture<'a> {
+    fn info(&self, key: SpanKey) -> &'a SpanInfo {
+        match self.own.attrs.contains_key(&key) {
+            true => self.own,
+            false => self.all,
+        }
+    }
+
+    fn location(attrs: &tss::SerializeAttributes<'_>) -> String {
+        Location::of(&attrs.metadata).to_string()
+    }
+
+    fn run(&mut self, rpts: &[Element]) {
+        for rpt in rpts.iter() {
+            self.ids.note(&rpt.payload);
+            let exited = self.exited.take();
+            match &rpt.payload {
+                ReportPayload::OnEvent { event } => {
+                    // Goes to its recorded span if that's open here, and
+                    // otherwise to the innermost open span.
+                    let parent = event.parent.as_ref().map(|p| self.ids.resolve(p.id));
+                    let at = parent
+                        .and_then(|id| self.open.iter().rposition(|open| open.key.1 == id))
+                        .or(self.open.len().checked_sub(1));
+                    let events = match at {
+                        Some(at) => &mut self.open[at].events,
+                        None => &mut self.events,
+                    };
+                    events.push(event.to_owned());
+                },
+                ReportPayload::OnEnter { span } => self.enter(rpt.source, span.id, rpt.tick),
+                ReportPayload::OnExit { span } => self.exit(span.id, rpt.tick),
+                ReportPayload::CpuTime { span, cpu_ns } if exited == Some(self.ids.resolve(span.id)) => {
+                    let spans = match self.open.last_mut() {
+                        Some(parent) => &mut parent.spans,
+                        None => &mut self.spans,
+                    };
+                    if let Some(span) = spans.last_mut() {
+                        span.cpu_ns = Some(*cpu_ns);
+                    }
+                },
+                _ => {},
+            }
+        }
+        // Whatever is still open ends with the trace.
+        while !self.open.is_empty() {
+            self.diagnostics.unclosed_spans += 1;
+            self.close(self.last_tick);
+        }
+    }
+
+    /// Spans with no `OnNewSpan`, or nested deeper than `max_depth`, are
+    /// skipped: what happens inside them goes to the enclosing span.
+    fn enter(&mut self, source: usize, id: NonZeroU64, tick: u64) {
+        let id = self.ids.resolve(id);
+        let Some(attrs) = self.info((source, id)).attrs.get(&(source, id)) else {
+            self.diagnostics.unknown_spans += 1;
+            self.first_unknown.get_or_insert(id);
+            self.skipped.push(id);
+            return;
+        };
+        if self.open.len() >= self.max_depth {
+            self.diagnostics.too_deep_spans += 1;
+            self.skipped.push(id);
+            return;
+        }
+        let ancestors = self
+            .ancestry
+            .then(|| self.open.iter().map(|open| Self::location(open.attrs)).collect());
+        self.open.push(Open {
+            key: (source, id),
+            attrs,
+            start: tick,
+            ancestors,
+            spans: vec![],
+            events: vec![],
+        });
+    }
+
+    fn exit(&mut self, id: NonZeroU64, tick: u64) {
+        let id = self.ids.resolve(id);
+        if self.open.last().is_some_and(|open| open.key.1 == id) {
+            self.close(tick);
+            self.exited = Some(id);
+        } else if let Some(pos) = self.skipped.iter().rposition(|skipped| *skipped == id) {
+            self.skipped.remove(pos);
+        } else if let Some(pos) = self.open.iter().rposition(|open| open.key.1 == id) {
+            // Enclosing spans exited first: end the ones inside them here.
+            while self.open.len() > pos + 1 {
+                self.diagnostics.unclosed_spans += 1;
+                self.close(tick);
+            }
+            self.close(tick);
+            self.exited = Some(id);
+        } else {
+            self.diagnostics.orphan_exits += 1;
+        }
+    }
+
+    /// Ends the innermost open span at `end`.
+    fn close(&mut self, end: u64) {
+        let Some(open) = self.open.pop() else {
+            return;
+        };
+        let (source, id) = open.key;
+        let start = open.start;
+        let end = if end < start {
+            self.diagnostics.backwards_spans += 1;
+            start
+        } else {
+            end
+        };
+        if end - start > MAX_PLAUSIBLE_DURATION {
+            self.diagnostics.absurd_spans += 1;
+        }
+
+        let span = Span {
+            start,
+            end,
+            spans: open.spans,
+            events: open.events,
+            attrs: open.attrs.to_owned(),
+            fields: self
+                .info((source, id))
+                .fields
+                .get(&(source, id))
+                .map(|f| f.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+                .unwrap_or_default(),
+            depth: self.open.len(),
+            is_root: !self.info((source, id)).parents.contains_key(&(source, id)),
+            ancestors: open.ancestors,
+            cpu_ns: None,
+        };
+        match self.open.last_mut() {
+            Some(parent) => parent.spans.push(span),
+            None => self.spans.push(span),
+        }
+    }
+}
+
+/// At-a-glance numbers for a set of reports, usually one thread's.
+pub struct ThreadSummary {
+    pub spans: usize,
+    pub events: usize,
+    /// Time spent inside at least one span.
+    pub busy_ns: u64,
+    /// Ticks of the first and last reports.
+    pub first_tick: u64,
+    pub last_tick: u64,
+}
+
+/// The whole capture at a glance, from `Elements::overview`.
+pub struct Overview {
+    pub reports: usize,
+    pub threads: usize,
+    pub spans: usize,
+    pub events: usize,
+    /// From the earliest tick to the latest.
+    pub duration_ns: u64,
+    /// The thread that spent the most time inside spans, and how long.
+    pub busiest_thread: Option<(ThreadKey, u64)>,
+    /// The `file:line` with the most reports, and how many, as counted by
+    /// `callsite_counts`.
+    pub busiest_location: Option<(String, usize)>,
+}
+
+/// Integrity checks for one thread, from `Elements::validate`.
+pub struct ThreadValidation {
+    pub thread: ThreadKey,
+    pub spans: usize,
+    pub events: usize,
+    /// Spans still entered at the end of the trace, and never closed.
+    pub unmatched_enters: usize,
+    /// Exits of spans that weren't entered on this thread.
+    pub orphan_exits: usize,
+    /// Reports with an earlier tick than the one before them.
+    pub backwards_ticks: usize,
+}
+
+impl ThreadValidation {
+    pub fn is_ok(&self) -> bool {
+        self.unmatched_enters == 0 && self.orphan_exits == 0 && self.backwards_ticks == 0
+    }
+}
+
+/// Span ids are only unique within one capture.
+pub type SpanKey = (usize, NonZeroU64);
+
+/// How the span at one end of a `SpanEdge` led to the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EdgeKind {
+    /// `from` is `to`'s parent.
+    Parent,
+    /// `to` follows from `from`.
+    FollowsFrom,
+}
+
+impl fmt::Display for EdgeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EdgeKind::Parent => "parent",
+            EdgeKind::FollowsFrom => "follows_from",
+        })
+    }
+}
+
+/// One causal link between two spans, by the ids they were created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanEdge {
+    pub from: SpanKey,
+    pub to: SpanKey,
+    pub kind: EdgeKind,
+}
+
+/// How many events there were at each level.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LevelCounts {
+    pub error: usize,
+    pub warn: usize,
+    pub info: usize,
+    pub debug: usize,
+    pub trace: usize,
+}
+
+impl LevelCounts {
+    fn add(&mut self, level: tss::SerializeLevel) {
+        match level {
+            tss::SerializeLevel::Error => self.error += 1,
+            tss::SerializeLevel::Warn => self.warn += 1,
+            tss::SerializeLevel::Info => self.info += 1,
+            tss::SerializeLevel::Debug => self.debug += 1,
+            tss::SerializeLevel::Trace => self.trace += 1,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.error + self.warn + self.info + self.debug + self.trace
+    }
+}
+
+/// A pause between two consecutive reports on one thread.
+pub struct Gap {
+    pub thread: ThreadKey,
+    pub before: Element,
+    pub after: Element,
+    /// Heartbeats written during the gap, or `None` if the stream has none.
+    /// With some, the process was alive and the thread just quiet; with
+    /// none, while they were due, the writer may have stalled.
+    pub heartbeats: Option<usize>,
+}
+
+impl Gap {
+    pub fn duration(&self) -> u64 {
+        self.after.tick.saturating_sub(self.before.tick)
+    }
+}
+
+/// p50, p95 and p99 of a set of nanosecond durations, by nearest rank.
+#[derive(Debug, Clone)]
+pub struct Percentiles {
+    pub count: usize,
+    /// All the samples added up.
+    pub total: u64,
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl Percentiles {
+    /// `None` if there are no samples.
+    pub fn new(mut samples: Vec<u64>) -> Option<Percentiles> {
+        samples.sort_unstable();
+        let max = *samples.last()?;
+        let rank = |p: usize| samples[(samples.len() * p).div_ceil(100) - 1];
+        Some(Percentiles {
+            count: samples.len(),
+            total: samples.iter().sum(),
+            p50: rank(50),
+            p95: rank(95),
+            p99: rank(99),
+            max,
+        })
+    }
+}
+
+/// How long the instances of one span callsite took, from
+/// `Elements::span_latencies`. Each enter/exit pair is an instance.
+pub struct SpanLatency {
+    /// `file:line` of the span.
+    pub location: String,
+    pub name: String,
+    /// From enter to exit.
+    pub completion: Percentiles,
+    /// From enter to the first event inside the span, or any span nested in
+    /// it, for the instances that had one.
+    pub first_event: Option<Percentiles>,
+}
+
+/// How the span callsites of a candidate capture compare to a baseline,
+/// from `Elements::diff`.
+#[derive(Debug, Clone)]
+pub struct DiffReport {
+    /// Largest increase in total time first.
+    pub spans: Vec<SpanDiff>,
+}
+
+/// One span callsite, and how long it took from enter to exit in each
+/// capture. `None` in a capture it doesn't appear in.
+#[derive(Debug, Clone)]
+pub struct SpanDiff {
+    pub location: String,
+    pub name: String,
+    pub baseline: Option<Percentiles>,
+    pub candidate: Option<Percentiles>,
+}
+
+impl SpanDiff {
+    fn change(&self, f: impl Fn(&Percentiles) -> u64) -> i64 {
+        let get = |p: &Option<Percentiles>| p.as_ref().map_or(0, &f) as i64;
+        get(&self.candidate) - get(&self.baseline)
+    }
+
+    pub fn count_change(&self) -> i64 {
+        self.change(|p| p.count as u64)
+    }
+
+    /// In nanoseconds.
+    pub fn total_change(&self) -> i64 {
+        self.change(|p| p.total)
+    }
+
+    /// In nanoseconds. Against zero, where one side has no instances.
+    pub fn p95_change(&self) -> i64 {
+        self.change(|p| p.p95)
+    }
+}
+
+/// Which stretch of a span's life exporters draw, for
+/// `Elements::spans_by_thread`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimingMode {
+    /// Each enter to its exit, so time a span spends waiting, e.g. on an
+    /// `.await`, is a gap. What matters for CPU cost.
+    #[default]
+    Busy,
+    /// From creation to close, idle time included.
+    Wall,
+}
+
+/// What exporters group spans and events by, e.g. as the `cat` of a Chrome
+/// trace event, which Perfetto colors by.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum CategoryBy {
+    /// The value of this field, e.g. `subsystem`.
+    Field(String),
+    #[default]
+    Target,
+    /// The `file:line` of the callsite.
+    Location,
+}
+
+/// Options shared by the exporters.
+#[derive(Debug, Clone, Default)]
+pub struct ExportConfig {
+    pub category_by: CategoryBy,
+}
+
+impl Elements {
+    /// Decodes a whole stream. Frames that fail to decode are skipped.
+    pub fn from_reader(r: impl Read) -> Result<Elements, ReportError> {
+        Self::from_reader_with_errors(r).map(|(elements, _)| elements)
+    }
+
+    /// Like `from_reader`, but also returns the errors for the frames that
+    /// failed to decode.
+    pub fn from_reader_with_errors(r: impl Read) -> Result<(Elements, Vec<ReportError>), ReportError> {
+        let reader = ReportReader::new(r)?;
+        let source = reader.source().cloned();
+        let mut rpts = vec![];
+        let mut errors = vec![];
+        for rpt in reader {
+            match rpt {
+                Ok(rpt) => rpts.push(rpt.into()),
+                Err(e @ ReportError::Decode(_)) => errors.push(e),
+                Err(e) => return Err(e),
+            }
+        }
+        let elements = Elements {
+            rpts,
+            sources: vec![source],
+        };
+        Ok((elements, errors))
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Elements, ReportError> {
+        Self::from_reader(File::open(path)?)
+    }
+
+    pub fn source(&self, index: usize) -> Option<&Source> {
+        self.sources.get(index)?.as_ref()
+    }
+
+    /// The labels set with `ReportLayerBuilder::label`, across all sources.
+    /// Where merged sources disagree on a key, the first source wins; use
+    /// `source` for the labels of just one.
+    pub fn labels(&self) -> BTreeMap<&str, &str> {
+        let mut labels = BTreeMap::new();
+        for source in self.sources.iter().flatten() {
+            for (k, v) in source.labels.iter() {
+                labels.entry(k.as_str()).or_insert(v.as_str());
+            }
+        }
+        labels
+    }
+
+    /// The name of each named thread, by `thread_id`. Where merged sources
+    /// reuse a `thread_id`, the first name wins.
+    pub fn thread_names(&self) -> HashMap<u64, String> {
+        let mut names = HashMap::new();
+        for rpt in self.rpts.iter() {
+            if let ReportPayload::ThreadName { name } = &rpt.payload {
+                names.entry(rpt.thread_id).or_insert_with(|| name.clone());
+            }
+        }
+        names
+    }
+
+    /// Calls `f` with every report, along with the spans entered on that
+    /// report's thread just before it, innermost last. Returns the spans
+    /// still entered on each thread at the end.
+    pub fn walk_with_stack(&self, mut f: impl FnMut(&Element, &[NonZeroU64])) -> HashMap<ThreadKey, Vec<NonZeroU64>> {
+        let mut stacks: HashMap<ThreadKey, Vec<NonZeroU64>> = HashMap::new();
+        for rpt in self.rpts.iter() {
+            let stack = stacks.entry(rpt.thread_key()).or_default();
+            f(rpt, stack);
+            match &rpt.payload {
+                ReportPayload::OnEnter { span } => stack.push(span.id),
+                ReportPayload::OnExit { span } => {
+                    if let Some(pos) = stack.iter().rposition(|id| *id == span.id) {
+                        stack.remove(pos);
+                    }
+                },
+                _ => {},
+            }
+        }
+        stacks
+    }
+
+    /// The spans each thread was inside of at `tick`, outermost first, by
+    /// name (or location, for spans without one). Threads with no reports
+    /// by then are left out, and idle threads have no spans.
+    pub fn active_at(&self, tick: u64) -> Vec<(ThreadKey, Vec<String>)> {
+        let mut names: HashMap<SpanKey, String> = HashMap::new();
+        let mut started = HashSet::new();
+        let mut active: HashMap<ThreadKey, Vec<NonZeroU64>> = HashMap::new();
+        let last = self.walk_with_stack(|rpt, stack| {
+            let thread = rpt.thread_key();
+            if rpt.tick <= tick {
+                started.insert(thread);
+                if let ReportPayload::OnNewSpan { attrs, id } = &rpt.payload {
+                    let name = match attrs.metadata.name.as_str() {
+                        "" => Location::of(&attrs.metadata).to_string(),
+                        name => name.to_string(),
+                    };
+                    names.insert((rpt.source, id.id), name);
+                }
+            } else if started.contains(&thread) {
+                active.entry(thread).or_insert_with(|| stack.to_vec());
+            }
+        });
+        for (thread, stack) in last {
+            if started.contains(&thread) {
+                active.entry(thread).or_insert(stack);
+            }
+        }
+
+        let mut active: Vec<_> = active
+            .into_iter()
+            .map(|(thread, stack)| {
+                let spans = stack
+                    .iter()
+                    .map(|id| names.get(&(thread.source, *id)).cloned().unwrap_or_else(|| "???".to_string()))
+                    .collect();
+                (thread, spans)
+            })
+            .collect();
+        active.sort_unstable_by_key(|(thread, _)| *thread);
+        active
+    }
+
+    /// Counts spans created and events recorded, and how long spans were
+    /// entered for, across all threads.
+    pub fn thread_summary(&self) -> ThreadSummary {
+        let mut summary = ThreadSummary {
+            spans: 0,
+            events: 0,
+            busy_ns: 0,
+            first_tick: self.rpts.first().map_or(0, |rpt| rpt.tick),
+            last_tick: self.rpts.last().map_or(0, |rpt| rpt.tick),
+        };
+        let mut busy_since: HashMap<ThreadKey, u64> = HashMap::new();
+        self.walk_with_stack(|rpt, stack| match &rpt.payload {
+            ReportPayload::OnNewSpan { .. } => summary.spans += 1,
+            ReportPayload::OnEvent { .. } => summary.events += 1,
+            ReportPayload::OnEnter { .. } if stack.is_empty() => {
+                busy_since.insert(rpt.thread_key(), rpt.tick);
+            },
+            ReportPayload::OnExit { span } if stack == [span.id] => {
+                if let Some(since) = busy_since.remove(&rpt.thread_key()) {
+                    summary.busy_ns += rpt.tick.saturating_sub(since);
+                }
+            },
+            _ => {},
+        });
+        // Spans still entered at the end were busy until then.
+        for since in busy_since.into_values() {
+            summary.busy_ns += summary.last_tick.saturating_sub(since);
+        }
+        summary
+    }
+
+    /// Totals for the whole capture, e.g. to paste into a bug report.
+    pub fn overview(&self) -> Overview {
+        let summary = self.thread_summary();
+        let threads = self.split_by_entering_thread();
+        let busiest_thread = threads
+            .iter()
+            .map(|(thread, elements)| (*thread, elements.thread_summary().busy_ns))
+            .filter(|(_, busy)| *busy > 0)
+            // The first thread wins a tie.
+            .max_by_key(|(thread, busy)| (*busy, std::cmp::Reverse(*thread)));
+        let first = self.rpts.iter().map(|rpt| rpt.tick).min().unwrap_or(0);
+        let last = self.rpts.iter().map(|rpt| rpt.tick).max().unwrap_or(0);
+        Overview {
+            reports: self.rpts.len(),
+            threads: threads.len(),
+            spans: summary.spans,
+            events: summary.events,
+            duration_ns: last - first,
+            busiest_thread,
+            busiest_location: self.callsite_counts().into_iter().next(),
+        }
+    }
+
+    /// Checks every thread's reports for unbalanced enters and exits, and
+    /// for ticks going backwards, ordered by thread.
+    pub fn validate(&self) -> Vec<ThreadValidation> {
+        let mut threads: HashMap<ThreadKey, ThreadValidation> = HashMap::new();
+        let mut last_ticks: HashMap<ThreadKey, u64> = HashMap::new();
+        let mut closed = HashSet::new();
+        let stacks = self.walk_with_stack(|rpt, stack| {
+            let thread = rpt.thread_key();
+            let v = threads.entry(thread).or_insert(ThreadValidation {
+                thread,
+                spans: 0,
+                events: 0,
+                unmatched_enters: 0,
+                orphan_exits: 0,
+                backwards_ticks: 0,
+            });
+            if last_ticks.insert(thread, rpt.tick).is_some_and(|last| rpt.tick < last) {
+                v.backwards_ticks += 1;
+            }
+            match &rpt.payload {
+                ReportPayload::OnNewSpan { .. } => v.spans += 1,
+                ReportPayload::OnEvent { .. } => v.events += 1,
+                ReportPayload::OnExit { span } if !stack.contains(&span.id) => v.orphan_exits += 1,
+                ReportPayload::OnClose { span, .. } => {
+                    closed.insert((rpt.source, span.id));
+                },
+                _ => {},
+            }
+        });
+        for (thread, stack) in stacks {
+            if let Some(v) = threads.get_mut(&thread) {
+                v.unmatched_enters = stack.iter().filter(|id| !closed.contains(&(thread.source, **id))).count();
+            }
+        }
+
+        let mut threads: Vec<_> = threads.into_values().collect();
+        threads.sort_unstable_by_key(|v| v.thread);
+        threads
+    }
+
+    /// Each span's parent: its explicit parent if it has one, otherwise the
+    /// span that was entered on its thread when it was created.
+    pub fn span_parents(&self) -> HashMap<SpanKey, SpanKey> {
+        let mut parents = HashMap::new();
+        self.walk_with_stack(|rpt, stack| {
+            if let ReportPayload::OnNewSpan { attrs, id } = &rpt.payload {
+                let parent = match &attrs.parent {
+                    Some(parent) => Some(parent.id),
+                    None if attrs.is_root => None,
+                    None => stack.last().copied(),
+                };
+                if let Some(parent) = parent {
+                    parents.insert((rpt.source, id.id), (rpt.source, parent));
+                }
+            }
+        });
+        parents
+    }
+
+    /// Every parent-child and follows-from link between spans, in the order
+    /// they were made. Spans whose id changed are referred to by the id they
+    /// were created with, so each span is one node.
+    pub fn span_edges(&self) -> Vec<SpanEdge> {
+        let mut ids: HashMap<usize, IdRemap> = HashMap::new();
+        let mut edges = vec![];
+        self.walk_with_stack(|rpt, stack| {
+            let ids = ids.entry(rpt.source).or_default();
+            ids.note(&rpt.payload);
+            let key = |id| (rpt.source, ids.resolve(id));
+            match &rpt.payload {
+                ReportPayload::OnNewSpan { attrs, id } => {
+                    let parent = match &attrs.parent {
+                        Some(parent) => Some(parent.id),
+                        None if attrs.is_root => None,
+                        None => stack.last().copied(),
+                    };
+                    if let Some(parent) = parent {
+                        edges.push(SpanEdge { from: key(parent), to: key(id.id), kind: EdgeKind::Parent });
+                    }
+                },
+                ReportPayload::OnFollowsFrom { span, follows } => {
+                    edges.push(SpanEdge { from: key(follows.id), to: key(span.id), kind: EdgeKind::FollowsFrom });
+                },
+                _ => {},
+            }
+        });
+        edges
+    }
+
+    /// Keeps only the spans matching `predicate`, everything nested under
+    /// them, and the events recorded inside them.
+    pub fn filter_span_subtrees(&self, predicate: impl Fn(&tss::SerializeAttributes) -> bool) -> Elements {
+        let parents = self.span_parents();
+        let matching: HashSet<SpanKey> = self
+            .rpts
+            .iter()
+            .filter_map(|rpt| match &rpt.payload {
+                ReportPayload::OnNewSpan { attrs, id } if predicate(attrs) => Some((rpt.source, id.id)),
+                _ => None,
+            })
+            .collect();
+
+        let mut kept: HashMap<SpanKey, bool> = HashMap::new();
+        let mut is_kept = |key: SpanKey| -> bool {
+            let mut chain = vec![];
+            let mut cur = Some(key);
+            let result = loop {
+                let Some(k) = cur else { break false };
+                if let Some(&known) = kept.get(&k) {
+                    break known;
+                }
+                if matching.contains(&k) {
+                    break true;
+                }
+                chain.push(k);
+                cur = parents.get(&k).copied();
+            };
+            for k in chain {
+                kept.insert(k, result);
+            }
+            result
+        };
+
+        let mut rpts = vec![];
+        self.walk_with_stack(|rpt, stack| {
+            let span = match &rpt.payload {
+                ReportPayload::OnNewSpan { id, .. } => Some(id.id),
+                ReportPayload::OnRecord { span, .. }
+                | ReportPayload::OnFollowsFrom { span, .. }
+                | ReportPayload::OnEnter { span }
+                | ReportPayload::OnExit { span }
+                | ReportPayload::OnClose { span, .. }
+                | ReportPayload::CpuTime { span, .. } => Some(span.id),
+                ReportPayload::OnIdChange { old, .. } => Some(old.id),
+                ReportPayload::OnEvent { event } => event
+                    .parent
+                    .as_ref()
+                    .map(|p| p.id)
+                    .or_else(|| stack.last().copied()),
+                // Markers and heartbeats are about the whole trace, and
+                // thread names about the whole thread, so they're always kept.
+                ReportPayload::Marker { .. } | ReportPayload::ThreadName { .. } | ReportPayload::Heartbeat { .. } => {
+                    rpts.push(rpt.clone());
+                    return;
+                },
+            };
+            if span.is_some_and(|id| is_kept((rpt.source, id))) {
+                rpts.push(rpt.clone());
+            }
+        });
+
+        Elements {
+            rpts,
+            sources: self.sources.clone(),
+        }
+    }
+
+    /// Every event recorded inside a span matching `predicate`, on any
+    /// thread, along with the innermost such span. Returns `(span, event
+    /// report)` in report order.
+    pub fn events_in_span(&self, predicate: impl Fn(&tss::SerializeAttributes) -> bool) -> Vec<(SpanKey, Element)> {
+        let parents = self.span_parents();
+        let matching: HashSet<SpanKey> = self
+            .rpts
+            .iter()
+            .filter_map(|rpt| match &rpt.payload {
+                ReportPayload::OnNewSpan { attrs, id } if predicate(attrs) => Some((rpt.source, id.id)),
+                _ => None,
+            })
+            .collect();
+
+        let mut out = vec![];
+        self.walk_with_stack(|rpt, stack| {
+            let ReportPayload::OnEvent { event } = &rpt.payload else {
+                return;
+            };
+            let mut cur = event
+                .parent
+                .as_ref()
+                .map(|p| p.id)
+                .or_else(|| stack.last().copied())
+                .map(|id| (rpt.source, id));
+            while let Some(key) = cur {
+                if matching.contains(&key) {
+                    out.push((key, rpt.clone()));
+                    break;
+                }
+                cur = parents.get(&key).copied();
+            }
+        });
+        out
+    }
+
+    /// Consecutive reports on the same thread more than `threshold` apart,
+    /// longest first. Heartbeats aren't on any thread, so they don't count.
+    pub fn find_gaps(&self, threshold: Duration) -> Vec<Gap> {
+        let threshold = u64::try_from(threshold.as_nanos()).unwrap_or(u64::MAX);
+        let mut heartbeats: HashMap<usize, Vec<u64>> = HashMap::new();
+        for rpt in self.rpts.iter() {
+            if let ReportPayload::Heartbeat { .. } = rpt.payload {
+                heartbeats.entry(rpt.source).or_default().push(rpt.tick);
+            }
+        }
+        heartbeats.values_mut().for_each(|ticks| ticks.sort_unstable());
+
+        let mut last: HashMap<ThreadKey, &Element> = HashMap::new();
+        let mut gaps = vec![];
+        for rpt in self.rpts.iter() {
+            if let ReportPayload::Heartbeat { .. } = rpt.payload {
+                continue;
+            }
+            if let Some(prev) = last.insert(rpt.thread_key(), rpt) {
+                if rpt.tick.saturating_sub(prev.tick) > threshold {
+                    let heartbeats = heartbeats.get(&rpt.source).map(|ticks| {
+                        let start = ticks.partition_point(|&tick| tick <= prev.tick);
+                        let end = ticks.partition_point(|&tick| tick < rpt.tick);
+                        end.saturating_sub(start)
+                    });
+                    gaps.push(Gap {
+                        thread: rpt.thread_key(),
+                        before: prev.clone(),
+                        after: rpt.clone(),
+                        heartbeats,
+                    });
+                }
+            }
+        }
+        gaps.sort_by_key(|gap| std::cmp::Reverse(gap.duration()));
+        gaps
+    }
+
+    /// Latency percentiles for every span callsite, by location. Spans whose
+    /// id changed count under the callsite they were created at.
+    pub fn span_latencies(&self) -> Vec<SpanLatency> {
+        struct Entered {
+            id: NonZeroU64,
+            tick: u64,
+            first_event: Option<u64>,
+        }
+
+        let mut ids: HashMap<usize, IdRemap> = HashMap::new();
+        let mut names: HashMap<SpanKey, (String, String)> = HashMap::new();
+        let mut stacks: HashMap<ThreadKey, Vec<Entered>> = HashMap::new();
+        let mut samples: BTreeMap<(String, String), (Vec<u64>, Vec<u64>)> = BTreeMap::new();
+        for rpt in self.rpts.iter() {
+            let ids = ids.entry(rpt.source).or_default();
+            ids.note(&rpt.payload);
+            let stack = stacks.entry(rpt.thread_key()).or_default();
+            match &rpt.payload {
+                ReportPayload::OnNewSpan { attrs, id } => {
+                    let location = Location::of(&attrs.metadata).to_string();
+                    names.insert((rpt.source, id.id), (location, attrs.metadata.name.as_str().to_string()));
+                },
+                ReportPayload::OnEnter { span } => stack.push(Entered {
+                    id: ids.resolve(span.id),
+                    tick: rpt.tick,
+                    first_event: None,
+                }),
+                ReportPayload::OnEvent { .. } => {
+                    for entered in stack.iter_mut().filter(|e| e.first_event.is_none()) {
+                        entered.first_event = Some(rpt.tick.saturating_sub(entered.tick));
+                    }
+                },
+                ReportPayload::OnExit { span } => {
+                    let id = ids.resolve(span.id);
+                    let Some(pos) = stack.iter().rposition(|e| e.id == id) else {
+                        continue;
+                    };
+                    let entered = stack.remove(pos);
+                    let Some(key) = names.get(&(rpt.source, id)) else {
+                        continue;
+                    };
+                    let (completion, first_event) = samples.entry(key.clone()).or_default();
+                    completion.push(rpt.tick.saturating_sub(entered.tick));
+                    first_event.extend(entered.first_event);
+                },
+                _ => {},
+            }
+        }
+
+        samples
+            .into_iter()
+            .filter_map(|((location, name), (completion, first_event))| {
+                Some(SpanLatency {
+                    location,
+                    name,
+                    completion: Percentiles::new(completion)?,
+                    first_event: Percentiles::new(first_event),
+                })
+            })
+            .collect()
+    }
+
+    /// Compares `candidate` against this capture as the baseline, by span
+    /// callsite, e.g. to check that a change made things faster.
+    pub fn diff(&self, candidate: &Elements) -> DiffReport {
+        let mut both: BTreeMap<(String, String), (Option<Percentiles>, Option<Percentiles>)> = BTreeMap::new();
+        for latency in self.span_latencies() {
+            both.entry((latency.location, latency.name)).or_default().0 = Some(latency.completion);
+        }
+        for latency in candidate.span_latencies() {
+            both.entry((latency.location, latency.name)).or_default().1 = Some(latency.completion);
+        }
+
+        let mut spans: Vec<SpanDiff> = both
+            .into_iter()
+            .map(|((location, name), (baseline, candidate))| SpanDiff {
+                location,
+                name,
+                baseline,
+                candidate,
+            })
+            .collect();
+        spans.sort_by_key(|span| std::cmp::Reverse((span.total_change(), span.p95_change())));
+        DiffReport { spans }
+    }
+
+    /// The span trees of each thread, timed by `timing`, with every span
+    /// given its ancestors. Ordered by thread.
+    pub fn spans_by_thread(&self, timing: TimingMode) -> Vec<(ThreadKey, Vec<Span>)> {
+        match timing {
+            TimingMode::Busy => self
+                .spanners(true)
+                .into_iter()
+                .map(|(thread, tl_spans)| (thread, tl_spans.spans))
+                .collect(),
+            TimingMode::Wall => self.span_lifetimes(),
+        }
+    }
+
+    /// Every span once, from creation to close, on the thread that created
+    /// it, nested under its parent if that was created on the same thread.
+    /// Spans never closed end at the last tick. Each span gets its ancestors,
+    /// and the events recorded inside it on any thread.
+    pub fn span_lifetimes(&self) -> Vec<(ThreadKey, Vec<Span>)> {
+        struct Life {
+            key: SpanKey,
+            thread: ThreadKey,
+            attrs: tss::SerializeAttributes<'static>,
+            start: u64,
+            end: Option<u64>,
+            fields: BTreeMap<String, String>,
+            events: Vec<tss::SerializeEvent<'static>>,
+            cpu_ns: Option<u64>,
+            parent: Option<usize>,
+            spans: Vec<Span>,
+        }
+
+        let parents = self.span_parents();
+        let mut ids = IdRemap::default();
+        let mut lives: Vec<Life> = vec![];
+        let mut index: HashMap<SpanKey, usize> = HashMap::new();
+        self.walk_with_stack(|rpt, stack| {
+            ids.note(&rpt.payload);
+            let at = |index: &HashMap<SpanKey, usize>, id: NonZeroU64| index.get(&(rpt.source, ids.resolve(id))).copied();
+            match &rpt.payload {
+                ReportPayload::OnNewSpan { attrs, id } => {
+                    let key = (rpt.source, id.id);
+                    let parent = parents
+                        .get(&key)
+                        .and_then(|parent| index.get(parent).copied())
+                        .filter(|&parent| lives[parent].thread == rpt.thread_key());
+                    index.insert(key, lives.len());
+                    lives.push(Life {
+                        key,
+                        thread: rpt.thread_key(),
+                        attrs: attrs.to_owned(),
+                        start: rpt.tick,
+                        end: None,
+                        fields: BTreeMap::new(),
+                        events: vec![],
+                        cpu_ns: None,
+                        parent,
+                        spans: vec![],
+                    });
+                },
+                ReportPayload::OnRecord { span, values: tss::SerializeRecord::De(values) } => {
+                    if let Some(i) = at(&index, span.id) {
+                        for (key, val) in values.iter() {
+                            lives[i].fields.insert(key.as_str().to_string(), val.display().to_string());
+                        }
+                    }
+                },
+                ReportPayload::OnEvent { event } => {
+                    let parent = event.parent.as_ref().map(|p| p.id).or_else(|| stack.last().copied());
+                    if let Some(i) = parent.and_then(|id| at(&index, id)) {
+                        lives[i].events.push(event.to_owned());
+                    }
+                },
+                ReportPayload::OnClose { span, .. } => {
+                    if let Some(i) = at(&index, span.id) {
+                        lives[i].end.get_or_insert(rpt.tick);
+                    }
+                },
+                ReportPayload::CpuTime { span, cpu_ns } => {
+                    if let Some(i) = at(&index, span.id) {
+                        *lives[i].cpu_ns.get_or_insert(0) += cpu_ns;
+                    }
+                },
+                _ => {},
+            }
+        });
+
+        // Parents always come before their children, so depths and
+        // ancestors can be filled in front to back. Too deep spans become
+        // roots, so walking the trees can't overflow the stack.
+        let mut depths = vec![0; lives.len()];
+        let mut ancestors: Vec<Vec<String>> = vec![vec![]; lives.len()];
+        for i in 0..lives.len() {
+            let Some(parent) = lives[i].parent else {
+                continue;
+            };
+            if depths[parent] + 1 >= DEFAULT_MAX_DEPTH {
+                lives[i].parent = None;
+                continue;
+            }
+            depths[i] = depths[parent] + 1;
+            ancestors[i] = ancestors[parent].clone();
+            ancestors[i].push(Location::of(&lives[parent].attrs.metadata).to_string());
+        }
+
+        // Back to front, each span is complete by the time it's reached.
+        let last_tick = self.rpts.iter().map(|rpt| rpt.tick).max().unwrap_or(0);
+        let mut threads: BTreeMap<ThreadKey, Vec<Span>> = BTreeMap::new();
+        while let Some(mut life) = lives.pop() {
+            let i = lives.len();
+            life.spans.reverse();
+            let span = Span {
+                start: life.start,
+                end: life.end.unwrap_or(last_tick).max(life.start),
+                spans: life.spans,
+                events: life.events,
+                attrs: life.attrs,
+                fields: life.fields.into_iter().collect(),
+                depth: depths[i],
+                is_root: !parents.contains_key(&life.key),
+                ancestors: Some(std::mem::take(&mut ancestors[i])),
+                cpu_ns: life.cpu_ns,
+            };
+            match life.parent {
+                Some(parent) => lives[parent].spans.push(span),
+                None => threads.entry(life.thread).or_default().push(span),
+            }
+        }
+        threads
+            .into_iter()
+            .map(|(thread, mut spans)| {
+                spans.reverse();
+                (thread, spans)
+            })
+            .collect()
+    }
+
+    /// Walks the reports, yielding each span entry's bounds as it goes,
+    /// without building span trees, for traces too large to hold them.
+    pub fn span_cursor(&self) -> SpanCursor<'_> {
+        SpanCursor::new(&self.rpts)
+    }
+
+    /// Rebuilds span trees from one thread's reports, nesting spans by the
+    /// order they're entered and exited in. Split a capture of several
+    /// threads with `split_by_entering_thread` first.
+    pub fn spanner(&self) -> TlSpans {
+        self.spanner_impl(false, DEFAULT_MAX_DEPTH).0
+    }
+
+    /// Like `spanner`, but every span also gets the chain of its ancestors.
+    pub fn spanner_with_ancestors(&self) -> TlSpans {
+        self.spanner_impl(true, DEFAULT_MAX_DEPTH).0
+    }
+
+    /// Like `spanner`, but spans nested more than `max_depth` deep are left
+    /// out of the tree, instead of `DEFAULT_MAX_DEPTH`, and what happens
+    /// inside them goes to their deepest enclosing span.
+    pub fn spanner_with_max_depth(&self, max_depth: usize) -> TlSpans {
+        self.spanner_impl(false, max_depth).0
+    }
+
+    /// Like `spanner`, but fails instead of working around spans entered
+    /// with no `OnNewSpan`, spans nested too deep, or enters and exits that
+    /// don't pair up.
+    pub fn try_spanner(&self) -> Result<TlSpans, ReportError> {
+        let (tl_spans, first_unknown) = self.spanner_impl(false, DEFAULT_MAX_DEPTH);
+        if let Some(id) = first_unknown {
+            return Err(ReportError::UnknownSpanId(id));
+        }
+        let diag = &tl_spans.diagnostics;
+        if diag.too_deep_spans > 0 {
+            return Err(ReportError::TooDeep {
+                max_depth: DEFAULT_MAX_DEPTH,
+            });
+        }
+        if !diag.is_clean() {
+            return Err(ReportError::UnbalancedSpan {
+                orphan_exits: diag.orphan_exits,
+                unclosed_spans: diag.unclosed_spans,
+            });
+        }
+        Ok(tl_spans)
+    }
+
+    /// Also returns the first unknown span.
+    fn spanner_impl(&self, ancestry: bool, max_depth: usize) -> (TlSpans, Option<NonZeroU64>) {
+        let info = self.span_info();
+        self.capture(&info, &info, ancestry, max_depth)
+    }
+
+    /// The span trees of each thread, as `spanner` rebuilds them from each
+    /// of `split_by_entering_thread`, except that spans created on one
+    /// thread and entered on another keep their attributes and fields.
+    /// Ordered by thread.
+    pub fn spanner_by_thread(&self) -> Vec<(ThreadKey, TlSpans)> {
+        self.spanners(false)
+    }
+
+    fn spanners(&self, ancestry: bool) -> Vec<(ThreadKey, TlSpans)> {
+        let info = self.span_info();
+        self.split_by_entering_thread()
+            .into_iter()
+            .map(|(thread, elements)| {
+                let own = elements.span_info();
+                (thread, elements.capture(&own, &info, ancestry, DEFAULT_MAX_DEPTH).0)
+            })
+            .collect()
+    }
+
+    fn span_info(&self) -> SpanInfo {
+        let mut attrs = HashMap::new();
+        let mut fields: HashMap<SpanKey, BTreeMap<String, String>> = HashMap::new();
+        let mut ids: HashMap<usize, IdRemap> = HashMap::new();
+        self.rpts.iter().for_each(|rpt| {
+            let ids = ids.entry(rpt.source).or_default();
+            ids.note(&rpt.payload);
+            match &rpt.payload {
+                ReportPayload::OnNewSpan { attrs: a, id } => {
+                    attrs.insert((rpt.source, id.id), a.to_owned());
+                },
+                ReportPayload::OnRecord { span, values: tss::SerializeRecord::De(values) } => {
+                    let fields = fields.entry((rpt.source, ids.resolve(span.id))).or_default();
+                    for (key, val) in values.iter() {
+                        fields.insert(key.as_str().to_string(), val.display().to_string());
+                    }
+                },
+                _ => {},
+            }
+        });
+        SpanInfo {
+            attrs,
+            fields,
+            parents: self.span_parents(),
+        }
+    }
+
+    /// Rebuilds span trees from these reports, whose `span_info` is `own`,
+    /// looking up spans they didn't create in `all`.
+    fn capture(&self, own: &SpanInfo, all: &SpanInfo, ancestry: bool, max_depth: usize) -> (TlSpans, Option<NonZeroU64>) {
+        let mut capture = Capture {
+            own,
+            all,
+            open: vec![],
+            ids: IdRemap::default(),
+            skipped: vec![],
+            exited: None,
+            first_unknown: None,
+            ancestry,
+            max_depth,
+            last_tick: self.rpts.iter().map(|rpt| rpt.tick).max().unwrap_or(0),
+            diagnostics: Diagnostics::default(),
+            spans: vec![],
+            events: vec![],
+        };
+        capture.run(&self.rpts);
+
+        let tl_spans = TlSpans {
+            spans: capture.spans,
+            events: capture.events,
+            diagnostics: capture.diagnostics,
+        };
+        (tl_spans, capture.first_unknown)
+    }
+
+    /// Combines several captures into one. Each input's sources are kept,
+    /// renumbered after those of the inputs before it, so merging an
+    /// already merged `Elements` keeps its threads and spans apart.
+    pub fn merge(others: Vec<Elements>) -> Elements {
+        let mut sources = vec![];
+        let mut rpts: Vec<Element> = vec![];
+        for mut elements in others {
+            let offset = sources.len();
+            let count = elements.rpts.iter().map(|rpt| rpt.source + 1).max().unwrap_or(0);
+            if elements.sources.len() < count {
+                elements.sources.resize(count, None);
+            }
+            sources.append(&mut elements.sources);
+            rpts.extend(elements.rpts.into_iter().map(|mut rpt| {
+                rpt.source += offset;
+                rpt
+            }));
+        }
+
+        sort_by_wall_time(&mut rpts, &sources);
+
+        Elements { rpts, sources }
+    }
+
+    /// Groups events by callsite and by the set of field names they carry, to
+    /// find callsites that emit inconsistently shaped events. Returns
+    /// `(file:line, field names, count)`, sorted by location then shape.
+    pub fn events_by_shape(&self) -> Vec<(String, Vec<String>, usize)> {
+        let mut chunky: HashMap<(String, Vec<String>), usize> = HashMap::new();
+
+        self.rpts.iter().for_each(|rpt| {
+            if let ReportPayload::OnEvent { ref event } = &rpt.rpt.payload {
+                let key = Location::of(&event.metadata).to_string();
+                let mut shape: Vec<String> = match &event.fields {
+                    tss::SerializeRecordFields::De(fields) => {
+                        fields.keys().map(|k| k.as_str().to_string()).collect()
+                    },
+                    tss::SerializeRecordFields::Ser(_) => vec![],
+                };
+                shape.sort_unstable();
+
+                *chunky.entry((key, shape)).or_default() += 1;
+            }
+        });
+
+        let mut shapes: Vec<_> = chunky
+            .into_iter()
+            .map(|((key, shape), count)| (key, shape, count))
+            .collect();
+        shapes.sort_unstable();
+        shapes
+    }
+
+    /// Counts events (and span enters, if `include_spans`) per fixed-size
+    /// bucket of `tick`, keyed by the bucket's start tick. Buckets nothing
+    /// fell in are left out, so a tiny bucket over a long capture stays
+    /// cheap.
+    pub fn event_histogram(&self, bucket: Duration, include_spans: bool) -> BTreeMap<u64, usize> {
+        let bucket = u64::try_from(bucket.as_nanos()).unwrap_or(u64::MAX).max(1);
+        let mut counts = BTreeMap::new();
+        for rpt in self.rpts.iter() {
+            match rpt.payload {
+                ReportPayload::OnEvent { .. } => {},
+                ReportPayload::OnEnter { .. } if include_spans => {},
+                _ => continue,
+            }
+            *counts.entry(rpt.tick / bucket * bucket).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Every report from every thread, ordered by tick, with `seq` breaking
+    /// ties. Merged sources are lined up by wall-clock start, as in `merge`.
+    pub fn timeline(&self) -> Vec<Element> {
+        let mut rpts = self.rpts.clone();
+        sort_by_wall_time(&mut rpts, &self.sources);
+        rpts
+    }
+
+    /// The most instances of each span callsite that were entered at once,
+    /// across all threads, by `file:line`, most first. Instances that only
+    /// touch, one ending on the tick the next starts, don't overlap.
+    pub fn peak_concurrency(&self) -> Vec<(String, usize)> {
+        // `false` sorts first, so ends come before starts on the same tick.
+        let mut bounds: Vec<(u64, bool, String)> = vec![];
+        for (_, tl_spans) in self.spanner_by_thread() {
+            for root in tl_spans.spans.iter() {
+                root.walk(&mut |span| {
+                    bounds.push((span.start, true, span.location()));
+                    bounds.push((span.end, false, span.location()));
+                });
+            }
+        }
+        bounds.sort_unstable();
+
+        let mut active: HashMap<String, usize> = HashMap::new();
+        let mut peaks: HashMap<String, usize> = HashMap::new();
+        for (_, start, location) in bounds {
+            let n = active.entry(location.clone()).or_default();
+            match start {
+                true => {
+                    *n += 1;
+                    let peak = peaks.entry(location).or_default();
+                    *peak = (*peak).max(*n);
+                },
+                false => *n = n.saturating_sub(1),
+            }
+        }
+
+        let mut peaks: Vec<_> = peaks.into_iter().collect();
+        peaks.sort_unstable_by(|(a_key, a), (b_key, b)| b.cmp(a).then_with(|| a_key.cmp(b_key)));
+        peaks
+    }
+
+    /// How many events each thread recorded at each level, by the `file:line`
+    /// they came from.
+    pub fn level_counts(&self) -> BTreeMap<ThreadKey, BTreeMap<String, LevelCounts>> {
+        let mut counts: BTreeMap<ThreadKey, BTreeMap<String, LevelCounts>> = BTreeMap::new();
+        for rpt in self.rpts.iter() {
+            if let ReportPayload::OnEvent { event } = &rpt.payload {
+                counts
+                    .entry(rpt.thread_key())
+                    .or_default()
+                    .entry(Location::of(&event.metadata).to_string())
+                    .or_default()
+                    .add(event.metadata.level);
+            }
+        }
+        counts
+    }
+
+    /// How many reports each `file:line` produced, counting span operations
+    /// against the span's callsite, most first. Reports about spans created
+    /// before the trace started count as `???:0`, and reports about spans
+    /// whose id changed count against the callsite they were created at.
+    /// Markers, thread names and heartbeats aren't counted.
+    pub fn callsite_counts(&self) -> Vec<(String, usize)> {
+        let mut ids: HashMap<usize, IdRemap> = HashMap::new();
+        let mut spans: HashMap<SpanKey, String> = HashMap::new();
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        let unknown = || "???:0".to_string();
+
+        self.rpts.iter().for_each(|rpt| {
+            let ids = ids.entry(rpt.source).or_default();
+            ids.note(&rpt.payload);
+            let key = match &rpt.payload {
+                ReportPayload::OnNewSpan { attrs, id } => {
+                    let key = Location::of(&attrs.metadata).to_string();
+                    spans.insert((rpt.source, id.id), key.clone());
+                    key
+                },
+                ReportPayload::OnEvent { event } => Location::of(&event.metadata).to_string(),
+                ReportPayload::OnRecord { span, .. }
+                | ReportPayload::OnFollowsFrom { span, .. }
+                | ReportPayload::OnEnter { span }
+                | ReportPayload::OnExit { span }
+                | ReportPayload::OnClose { span, .. }
+                | ReportPayload::CpuTime { span, .. } => {
+                    spans.get(&(rpt.source, ids.resolve(span.id))).cloned().unwrap_or_else(unknown)
+                },
+                ReportPayload::OnIdChange { old, .. } => {
+                    spans.get(&(rpt.source, ids.resolve(old.id))).cloned().unwrap_or_else(unknown)
+                },
+                ReportPayload::Marker { .. } | ReportPayload::ThreadName { .. } | ReportPayload::Heartbeat { .. } => {
+                    return
+                },
+            };
+            *counts.entry(key).or_default() += 1;
+        });
+
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_unstable_by(|(a_key, a), (b_key, b)| b.cmp(a).then_with(|| a_key.cmp(b_key)));
+        counts
+    }
+
+    /// The reports of each thread, ordered by thread.
+    pub fn split_by_thread_id(&self) -> Vec<(ThreadKey, Elements)> {
+        let mut chunky = BTreeMap::new();
+
+        self.rpts.iter().for_each(|rpt| {
+            chunky.entry(rpt.thread_key()).or_insert_with(Vec::new).push(rpt.clone());
+        });
+
+        chunky
+            .into_iter()
+            .map(|(id, vr)| {
+                (
+                    id,
+                    Elements {
+                        rpts: vr,
+                        sources: self.sources.clone(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Like `split_by_thread_id`, but for rebuilding span trees: an exit
+    /// from a span its thread never entered is paired by span id with an
+    /// enter on another thread, and goes (with its `CpuTime`) to that
+    /// thread, so a span entered on one thread and exited on another still
+    /// ends where it started instead of being left open.
+    pub fn split_by_entering_thread(&self) -> Vec<(ThreadKey, Elements)> {
+        let mut ids: HashMap<usize, IdRemap> = HashMap::new();
+        // The threads each span is entered on, innermost last.
+        let mut entered: HashMap<SpanKey, Vec<ThreadKey>> = HashMap::new();
+        // Where each span's last exit went.
+        let mut exited: HashMap<SpanKey, ThreadKey> = HashMap::new();
+        let mut chunky: BTreeMap<ThreadKey, Vec<Element>> = BTreeMap::new();
+
+        for rpt in self.rpts.iter() {
+            let ids = ids.entry(rpt.source).or_default();
+            ids.note(&rpt.payload);
+            let key = |id| (rpt.source, ids.resolve(id));
+            let thread = match &rpt.payload {
+                ReportPayload::OnEnter { span } => {
+                    entered.entry(key(span.id)).or_default().push(rpt.thread_key());
+                    rpt.thread_key()
+                },
+                ReportPayload::OnExit { span } => {
+                    let thread = entered
+                        .get_mut(&key(span.id))
+                        .and_then(|threads| pop_entered(threads, rpt.thread_key()))
+                        .unwrap_or(rpt.thread_key());
+                    exited.insert(key(span.id), thread);
+                    thread
+                },
+                ReportPayload::CpuTime { span, .. } => exited.get(&key(span.id)).copied().unwrap_or(rpt.thread_key()),
+                _ => rpt.thread_key(),
+            };
+            let rpt = match thread == rpt.thread_key() {
+                true => rpt.clone(),
+                false => Element {
+                    rpt: Rc::new(Report {
+                        thread_id: thread.thread_id,
+                        ..Report::to_owned(&rpt.rpt)
+                    }),
+                    source: rpt.source,
+                },
+            };
+            chunky.entry(thread).or_default().push(rpt);
+        }
+
+        chunky
+            .into_iter()
+            .map(|(id, rpts)| {
+                (
+                    id,
+                    Elements {
+                        rpts,
+                        sources: self.sources.clone(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// The fields of every event, by `file:line`, ordered by file and then
+    /// line.
+    pub fn events_by_location(&self) -> Vec<(String, Vec<tss::SerializeRecordFields<'static>>)> {
+        let mut chunky = BTreeMap::new();
+
+        self.rpts.iter().for_each(|rpt| {
+            if let ReportPayload::OnEvent { ref event } = &rpt.rpt.payload {
+                // Keyed without the module, which isn't part of the name.
+                let key = Location { module: None, ..Location::of(&event.metadata) };
+
+                chunky.entry(key).or_insert_with(Vec::new).push(event.fields.to_owned());
+            }
+        });
+
+        chunky
+            .into_iter()
+            .map(|(key, fields)| (key.to_string(), fields))
+            .collect()
+    }
+}
+
+impl From<Report<'static>> for Element {
+    fn from(other: Report<'static>) -> Self {
+        Self {
+            rpt: Rc::new(other),
+            source: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::ReportLayer;
+
+    fn id(id: u64) -> tss::SerializeId {
+        tss::SerializeId { id: NonZeroU64::new(id).unwrap() }
+    }
+
+    fn elements(rpts: Vec<Report<'static>>) -> Elements {
+        Elements {
+            rpts: rpts.into_iter().map(|rpt| Element { rpt: Rc::new(rpt), source: 0 }).collect(),
+            sources: vec![None],
+        }
+    }
+
+    /// One span with an event inside, whose id changes to `new` right after
+    /// it's entered, as a subscriber calling `on_id_change` would make it.
+    fn id_change_mid_span(new: u64) -> Vec<Report<'static>> {
+        let (layer, handle) = ReportLayer::capturing();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            tracing::info_span!("outer").in_scope(|| tracing::info!("inside"));
+        });
+        let mut rpts = handle.take();
+
+        let enter = rpts.iter().position(|rpt| matches!(rpt.payload, ReportPayload::OnEnter { .. })).unwrap();
+        let ReportPayload::OnEnter { span } = &rpts[enter].payload else { unreachable!() };
+        let old = span.id.get();
+        for rpt in &mut rpts[enter + 1..] {
+            match &mut rpt.payload {
+                ReportPayload::OnExit { span } | ReportPayload::OnClose { span, .. } => *span = id(new),
+                ReportPayload::OnEvent { event } => event.parent = Some(id(new)),
+                _ => {},
+            }
+        }
+        let change = Report {
+            payload: ReportPayload::OnIdChange { old: id(old), new: id(new) },
+            ..rpts[enter].to_owned()
+        };
+        rpts.insert(enter + 1, change);
+        rpts
+    }
+
+    #[test]
+    fn id_remap_follows_chained_changes() {
+        let mut ids = IdRemap::default();
+        ids.note(&ReportPayload::OnIdChange { old: id(1), new: id(2) });
+        ids.note(&ReportPayload::OnIdChange { old: id(2), new: id(3) });
+        assert_eq!(ids.resolve(id(3).id), id(1).id);
+        assert_eq!(ids.resolve(id(2).id), id(1).id);
+        assert_eq!(ids.resolve(id(4).id), id(4).id);
+    }
+
+    #[test]
+    fn id_remap_forgets_reused_ids() {
+        let rpts = id_change_mid_span(7);
+        let new_span = rpts.iter().find(|rpt| matches!(rpt.payload, ReportPayload::OnNewSpan { .. })).unwrap();
+        let ReportPayload::OnNewSpan { id: created, .. } = &new_span.payload else { unreachable!() };
+
+        let mut ids = IdRemap::default();
+        ids.note(&ReportPayload::OnIdChange { old: id(99), new: id(created.id.get()) });
+        assert_eq!(ids.resolve(created.id), id(99).id);
+        ids.note(&new_span.payload);
+        assert_eq!(ids.resolve(created.id), created.id);
+    }
+
+    #[test]
+    fn spanner_follows_id_change_mid_span() {
+        let tl_spans = elements(id_change_mid_span(7)).try_spanner().unwrap();
+        assert_eq!(tl_spans.spans.len(), 1);
+        let span = &tl_spans.spans[0];
+        assert_eq!(span.attrs.metadata.name.as_str(), "outer");
+        assert_eq!(span.events.len(), 1);
+        assert!(tl_spans.events.is_empty());
+        assert_eq!(tl_spans.diagnostics.orphan_exits, 0);
+    }
+
+    #[test]
+    fn split_by_entering_thread_follows_id_change_mid_span() {
+        let mut rpts = id_change_mid_span(7);
+        let thread_id = rpts[0].thread_id;
+        // Exit on another thread, under the new id.
+        let exit = rpts.iter_mut().find(|rpt| matches!(rpt.payload, ReportPayload::OnExit { .. })).unwrap();
+        exit.thread_id = thread_id + 1;
+
+        let split = elements(rpts).split_by_entering_thread();
+        assert_eq!(split.len(), 1);
+        let (thread, elements) = &split[0];
+        assert_eq!(thread.thread_id, thread_id);
+        assert!(elements.rpts.iter().all(|rpt| rpt.thread_id == thread_id));
+        assert_eq!(elements.spanner().spans.len(), 1);
+    }
+
+    #[test]
+    fn span_latencies_follow_id_change_mid_span() {
+        let latencies = elements(id_change_mid_span(7)).span_latencies();
+        assert_eq!(latencies.len(), 1);
+        assert_eq!(latencies[0].name, "outer");
+        assert_eq!(latencies[0].completion.count, 1);
+        assert_eq!(latencies[0].first_event.as_ref().map(|p| p.count), Some(1));
+    }
+
+    #[test]
+    fn callsite_counts_follow_id_change_mid_span() {
+        let counts = elements(id_change_mid_span(7)).callsite_counts();
+        assert!(counts.iter().all(|(location, _)| location != "???:0"), "{:?}", counts);
+        // The span and its event are on one line: everything but the thread
+        // name counts against it.
+        assert_eq!(counts, vec![(counts[0].0.clone(), 6)]);
+    }
+}
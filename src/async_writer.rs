@@ -0,0 +1,99 @@
+use std::{
+    io,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    time::timeout,
+};
+
+use crate::{
+    format::Compression,
+    registry::Registry,
+    writer::ErrorCallback,
+    Shared,
+};
+
+pub(crate) type AsyncSink = Box<dyn AsyncWrite + Send + Unpin>;
+
+const QUEUE_CAPACITY: usize = 128;
+
+/// Like `spawn_writer`, but on the current tokio runtime, for a single sink.
+/// After the first error the task exits, and everything sent after that
+/// counts as dropped.
+pub(crate) fn spawn_async_writer(
+    w: AsyncSink,
+    on_error: Option<ErrorCallback>,
+    shared: Arc<Shared>,
+) -> mpsc::Sender<Vec<u8>> {
+    let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+    tokio::spawn(async move {
+        let result = run(w, rx, &shared).await;
+        if let Err(e) = result.as_ref() {
+            if let Some(on_error) = on_error.as_ref() {
+                on_error(e);
+            }
+        }
+        shared.writer_done(result.is_ok());
+    });
+    tx
+}
+
+async fn run(mut w: AsyncSink, mut rx: mpsc::Receiver<Vec<u8>>, shared: &Shared) -> io::Result<()> {
+    let mut out = AsyncOutput {
+        callsites: 0,
+        dirty: false,
+    };
+    w.write_all(&shared.header(Compression::None).encode()).await?;
+
+    loop {
+        // Write out everything queued so far, and flush, before acking.
+        let acks = shared.take_flush_requests();
+        if !acks.is_empty() {
+            while let Ok(msg) = rx.try_recv() {
+                out.write_report(&mut w, &msg, &shared.callsites).await?;
+            }
+            w.flush().await?;
+            out.dirty = false;
+            for ack in acks {
+                let _ = ack.send(true);
+            }
+        }
+
+        match timeout(Duration::from_millis(25), rx.recv()).await {
+            Ok(Some(msg)) => out.write_report(&mut w, &msg, &shared.callsites).await?,
+            Ok(None) => return w.shutdown().await,
+            Err(_) if out.dirty => {
+                w.flush().await?;
+                out.dirty = false;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+struct AsyncOutput {
+    /// Callsite frames written so far.
+    callsites: usize,
+    /// Whether anything was written since the last flush.
+    dirty: bool,
+}
+
+impl AsyncOutput {
+    async fn write_report(&mut self, w: &mut AsyncSink, buf: &[u8], registry: &Registry) -> io::Result<()> {
+        if registry.len() > self.callsites {
+            let mut frames = Vec::new();
+            registry.frames_from(self.callsites, |frame| {
+                self.callsites += 1;
+                frames.extend_from_slice(frame);
+                Ok(())
+            })?;
+            w.write_all(&frames).await?;
+        }
+        self.dirty = true;
+        w.write_all(buf).await
+    }
+}
@@ -0,0 +1,72 @@
+use core::fmt;
+
+use serde::{Deserialize, Serialize, Serializer};
+use tracing::field::Field;
+use tracing_serde_structured as tss;
+
+use crate::{Report, ReportPayload};
+
+/// A frame in a stream of version 6 or later. Metadata for each callsite is written once,
+/// as a `Callsite` frame, before the first report that refers to it.
+#[derive(Deserialize, Serialize)]
+pub(crate) enum Frame<'a> {
+    Callsite {
+        id: u64,
+        #[serde(borrow)]
+        metadata: tss::SerializeMetadata<'a>,
+    },
+    Report(#[serde(borrow)] WireReport<'a>),
+    /// A report with its metadata inline, which decodes on its own. Written
+    /// to `shared_append` files, and by `Report::to_cobs_frame`.
+    Full(#[serde(borrow)] Report<'a>),
+}
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct WireReport<'a> {
+    pub tick: u64,
+    pub seq: u64,
+    pub thread_id: u64,
+    #[serde(borrow)]
+    pub payload: WirePayload<'a>,
+}
+
+/// `ReportPayload`, with the metadata in `OnNewSpan` and `OnEvent` replaced
+/// by a callsite id.
+#[derive(Deserialize, Serialize)]
+#[cfg_attr(not(feature = "std"), allow(clippy::large_enum_variant))]
+pub(crate) enum WirePayload<'a> {
+    OnNewSpan {
+        callsite: u64,
+        parent: Option<tss::SerializeId>,
+        is_root: bool,
+        id: tss::SerializeId,
+    },
+    OnEvent {
+        callsite: u64,
+        #[serde(borrow, serialize_with = "serialize_event_fields")]
+        fields: tss::SerializeRecordFields<'a>,
+        parent: Option<tss::SerializeId>,
+    },
+    /// Everything that doesn't carry metadata.
+    Other(#[serde(borrow)] ReportPayload<'a>),
+}
+
+/// tss declares a map of all of an event's fields, but only writes the
+/// fields that have a value, so an event with `Empty` fields wouldn't
+/// decode. Declare only those instead.
+fn serialize_event_fields<S: Serializer>(fields: &tss::SerializeRecordFields<'_>, serializer: S) -> Result<S::Ok, S::Error> {
+    let tss::SerializeRecordFields::Ser(event) = fields else {
+        return fields.serialize(serializer);
+    };
+    let mut present = 0;
+    event.record(&mut |_: &Field, _: &dyn fmt::Debug| present += 1);
+    let mut map = tss::SerdeMapVisitor::new(serializer.serialize_map(Some(present))?);
+    event.record(&mut map);
+    map.finish()
+}
+
+impl<'a> From<ReportPayload<'a>> for WirePayload<'a> {
+    fn from(payload: ReportPayload<'a>) -> Self {
+        WirePayload::Other(payload)
+    }
+}
@@ -0,0 +1,57 @@
+use std::{
+    mem,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::{
+    callsite::Frame,
+    registry::{Callsites, Registry},
+    Report,
+};
+
+/// Reports captured by a layer built with `ReportLayer::capturing`.
+#[derive(Clone)]
+pub struct CaptureHandle {
+    capture: Arc<Mutex<Capture>>,
+}
+
+impl CaptureHandle {
+    pub(crate) fn new(capture: Arc<Mutex<Capture>>) -> Self {
+        Self { capture }
+    }
+
+    /// Removes and returns everything captured so far, in order.
+    pub fn take(&self) -> Vec<Report<'static>> {
+        let mut capture = self.capture.lock().unwrap_or_else(PoisonError::into_inner);
+        mem::take(&mut capture.reports)
+    }
+}
+
+/// Decodes frames as they're produced, exactly as `ReportReader` would.
+#[derive(Default)]
+pub(crate) struct Capture {
+    callsites: Callsites,
+    /// Callsite frames from the registry decoded so far.
+    seen: usize,
+    reports: Vec<Report<'static>>,
+}
+
+impl Capture {
+    pub(crate) fn push(&mut self, mut frame: Vec<u8>, registry: &Registry) {
+        if registry.len() > self.seen {
+            let _ = registry.frames_from(self.seen, |frame| {
+                self.seen += 1;
+                self.decode(&mut frame.to_vec());
+                Ok(())
+            });
+        }
+        self.decode(&mut frame);
+    }
+
+    fn decode(&mut self, frame: &mut [u8]) {
+        let frame: Frame = postcard::from_bytes_cobs(frame).expect("the layer wrote a valid frame");
+        if let Ok(Some(rpt)) = self.callsites.resolve(frame) {
+            self.reports.push(rpt);
+        }
+    }
+}
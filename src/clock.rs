@@ -0,0 +1,47 @@
+#[cfg(feature = "std")]
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// The source of report ticks, and of span busy/idle times.
+pub trait Clock: Send + Sync + 'static {
+    /// Nanoseconds since some fixed point, such as when the layer was built.
+    /// Must never go backwards.
+    fn now_nanos(&self) -> u64;
+}
+
+/// The default clock: a monotonic `Instant`, counted from when the clock was
+/// created.
+#[cfg(feature = "std")]
+pub struct InstantClock {
+    start: Instant,
+}
+
+#[cfg(feature = "std")]
+impl InstantClock {
+    pub fn new() -> Self {
+        Self { start: Instant::now() }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for InstantClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for InstantClock {
+    fn now_nanos(&self) -> u64 {
+        // A u64 of nanoseconds lasts for ~584 years; saturate rather than
+        // wrap if that's ever exceeded.
+        u64::try_from(self.start.elapsed().as_nanos()).unwrap_or(u64::MAX)
+    }
+}
+
+/// The wall-clock time when `clock` read 0, in nanoseconds since the Unix
+/// epoch.
+#[cfg(feature = "std")]
+pub(crate) fn start_wall_ns(clock: &dyn Clock) -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(now.as_nanos()).ok()?.checked_sub(clock.now_nanos())
+}
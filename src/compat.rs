@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use tracing_serde_structured as tss;
+
+use crate::{format::Source, Report, ReportPayload};
+
+/// The header's source, from format version 5 to 9.
+#[derive(Deserialize)]
+pub(crate) struct SourceV5 {
+    pid: u32,
+    hostname: String,
+}
+
+impl SourceV5 {
+    pub(crate) fn upgrade(self) -> Source {
+        Source {
+            pid: self.pid,
+            hostname: self.hostname,
+            labels: Default::default(),
+            start_wall_ns: None,
+        }
+    }
+}
+
+/// The header's source, from format version 10 to 16.
+#[derive(Deserialize)]
+pub(crate) struct SourceV10 {
+    pid: u32,
+    hostname: String,
+    labels: BTreeMap<String, String>,
+}
+
+impl SourceV10 {
+    pub(crate) fn upgrade(self) -> Source {
+        Source {
+            pid: self.pid,
+            hostname: self.hostname,
+            labels: self.labels,
+            start_wall_ns: None,
+        }
+    }
+}
+
+/// Format version 1, and streams without a header.
+#[derive(Deserialize)]
+pub(crate) struct ReportV1<'a> {
+    tick: u128,
+    thread_id: u64,
+    #[serde(borrow)]
+    payload: ReportPayloadV1<'a>,
+}
+
+impl ReportV1<'_> {
+    /// Version 1 has no sequence numbers, so the caller numbers the frames in
+    /// the order they were written.
+    pub(crate) fn upgrade(self, seq: u64) -> Report<'static> {
+        Report {
+            tick: upgrade_tick(self.tick),
+            seq,
+            thread_id: self.thread_id,
+            payload: self.payload.upgrade(),
+        }
+    }
+}
+
+fn upgrade_tick(tick: u128) -> u64 {
+    u64::try_from(tick).unwrap_or(u64::MAX)
+}
+
+/// Format version 2.
+#[derive(Deserialize)]
+pub(crate) struct ReportV2<'a> {
+    tick: u128,
+    seq: u64,
+    thread_id: u64,
+    #[serde(borrow)]
+    payload: ReportPayloadV1<'a>,
+}
+
+impl ReportV2<'_> {
+    pub(crate) fn upgrade(self) -> Report<'static> {
+        Report {
+            tick: upgrade_tick(self.tick),
+            seq: self.seq,
+            thread_id: self.thread_id,
+            payload: self.payload.upgrade(),
+        }
+    }
+}
+
+/// Format version 3, which already has the current payloads.
+#[derive(Deserialize)]
+pub(crate) struct ReportV3<'a> {
+    tick: u128,
+    seq: u64,
+    thread_id: u64,
+    #[serde(borrow)]
+    payload: ReportPayload<'a>,
+}
+
+impl ReportV3<'_> {
+    pub(crate) fn upgrade(self) -> Report<'static> {
+        Report {
+            tick: upgrade_tick(self.tick),
+            seq: self.seq,
+            thread_id: self.thread_id,
+            payload: self.payload.to_owned(),
+        }
+    }
+}
+
+/// Payloads in format versions 1 and 2.
+#[derive(Deserialize)]
+#[allow(clippy::enum_variant_names)]
+enum ReportPayloadV1<'a> {
+    OnNewSpan {
+        #[serde(borrow)]
+        attrs: tss::SerializeAttributes<'a>,
+        id: tss::SerializeId,
+    },
+    OnRecord {
+        span: tss::SerializeId,
+        values: tss::SerializeRecord<'a>,
+    },
+    OnFollowsFrom {
+        span: tss::SerializeId,
+        follows: tss::SerializeId,
+    },
+    OnEvent {
+        event: tss::SerializeEvent<'a>,
+    },
+    OnEnter {
+        span: tss::SerializeId,
+    },
+    OnExit {
+        span: tss::SerializeId,
+    },
+    OnIdChange {
+        old: tss::SerializeId,
+        new: tss::SerializeId,
+    },
+    OnClose {
+        span: tss::SerializeId,
+    },
+}
+
+impl ReportPayloadV1<'_> {
+    fn upgrade(self) -> ReportPayload<'static> {
+        match self {
+            ReportPayloadV1::OnNewSpan { attrs, id } => ReportPayload::OnNewSpan { attrs: attrs.to_owned(), id },
+            ReportPayloadV1::OnRecord { span, values } => ReportPayload::OnRecord { span, values: values.to_owned() },
+            ReportPayloadV1::OnFollowsFrom { span, follows } => ReportPayload::OnFollowsFrom { span, follows },
+            ReportPayloadV1::OnEvent { event } => ReportPayload::OnEvent { event: event.to_owned() },
+            ReportPayloadV1::OnEnter { span } => ReportPayload::OnEnter { span },
+            ReportPayloadV1::OnExit { span } => ReportPayload::OnExit { span },
+            ReportPayloadV1::OnIdChange { old, new } => ReportPayload::OnIdChange { old, new },
+            ReportPayloadV1::OnClose { span } => ReportPayload::OnClose { span, busy_ns: 0, idle_ns: 0 },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::{
+        callsite::Frame,
+        format::{to_cobs, MAGIC},
+        ReportLayer, ReportReader,
+    };
+
+    fn captured() -> Vec<Report<'static>> {
+        let (layer, handle) = ReportLayer::capturing();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            let other = tracing::info_span!("other");
+            let span = tracing::info_span!("outer", n = 1, later = tracing::field::Empty);
+            span.follows_from(&other);
+            span.in_scope(|| {
+                span.record("later", "x");
+                tracing::info!(k = "v", "inside");
+            });
+        });
+        handle.take()
+    }
+
+    /// The fixed part of a header, with a `compat` of 7 from version 7.
+    fn header(version: u8) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([version, 0]);
+        if version >= 7 {
+            bytes.push(7);
+        }
+        bytes
+    }
+
+    /// One COBS frame of `fields`, serialized one after another as a
+    /// struct's would be.
+    fn frame(fields: &[Vec<u8>]) -> Vec<u8> {
+        to_cobs(&fields.concat())
+    }
+
+    fn ser(value: &impl serde::Serialize) -> Vec<u8> {
+        postcard::to_allocvec(value).unwrap()
+    }
+
+    /// `payload` as versions 1 and 2 wrote it, if they had it at all.
+    fn payload_v1(payload: &ReportPayload<'_>) -> Option<Vec<u8>> {
+        match payload {
+            ReportPayload::OnClose { span, .. } => Some(ser(&(7u8, span))),
+            ReportPayload::Marker { .. }
+            | ReportPayload::ThreadName { .. }
+            | ReportPayload::Heartbeat { .. }
+            | ReportPayload::CpuTime { .. } => None,
+            payload => Some(ser(payload)),
+        }
+    }
+
+    fn read(bytes: &[u8]) -> (Option<Source>, serde_json::Value) {
+        let mut reader = ReportReader::new(bytes).unwrap();
+        let rpts: Vec<_> = reader.by_ref().map(Result::unwrap).collect();
+        (reader.source().cloned(), serde_json::to_value(rpts).unwrap())
+    }
+
+    #[test]
+    fn reads_versions_1_and_2() {
+        let rpts: Vec<_> = captured()
+            .into_iter()
+            .filter_map(|rpt| Some((payload_v1(&rpt.payload)?, rpt)))
+            .collect();
+        let expected: Vec<_> = rpts
+            .iter()
+            .enumerate()
+            .map(|(seq, (_, rpt))| Report {
+                seq: seq as u64,
+                payload: match &rpt.payload {
+                    ReportPayload::OnClose { span, .. } => ReportPayload::OnClose { span: span.clone(), busy_ns: 0, idle_ns: 0 },
+                    payload => payload.to_owned(),
+                },
+                ..rpt.to_owned()
+            })
+            .collect();
+        let expected = serde_json::to_value(expected).unwrap();
+
+        // Version 1 streams have no header, and no sequence numbers.
+        let v1: Vec<u8> = rpts
+            .iter()
+            .flat_map(|(payload, rpt)| frame(&[ser(&u128::from(rpt.tick)), ser(&rpt.thread_id), payload.clone()]))
+            .collect();
+        assert_eq!(read(&v1), (None, expected.clone()));
+
+        let mut v2 = header(2);
+        for (seq, (payload, rpt)) in rpts.iter().enumerate() {
+            v2.extend(frame(&[ser(&u128::from(rpt.tick)), ser(&(seq as u64)), ser(&rpt.thread_id), payload.clone()]));
+        }
+        assert_eq!(read(&v2), (None, expected));
+    }
+
+    #[test]
+    fn reads_version_3() {
+        let rpts = captured();
+        let mut v3 = header(3);
+        for rpt in &rpts {
+            v3.extend(frame(&[ser(&u128::from(rpt.tick)), ser(&rpt.seq), ser(&rpt.thread_id), ser(&rpt.payload)]));
+        }
+        assert_eq!(read(&v3), (None, serde_json::to_value(&rpts).unwrap()));
+    }
+
+    #[test]
+    fn reads_version_5_source() {
+        let rpts = captured();
+        let mut v5 = header(5);
+        v5.extend(frame(&[ser(&(42u32, "host"))]));
+        for rpt in &rpts {
+            v5.extend(frame(&[ser(rpt)]));
+        }
+        let source = Source {
+            pid: 42,
+            hostname: "host".into(),
+            labels: BTreeMap::new(),
+            start_wall_ns: None,
+        };
+        assert_eq!(read(&v5), (Some(source), serde_json::to_value(&rpts).unwrap()));
+    }
+
+    #[test]
+    fn reads_version_10_source() {
+        let rpts = captured();
+        let labels = BTreeMap::from([("build".to_string(), "abc".to_string())]);
+        let mut v10 = header(10);
+        v10.extend(frame(&[ser(&(42u32, "host", &labels))]));
+        for rpt in &rpts {
+            v10.extend(frame(&[ser(&Frame::Full(rpt.to_owned()))]));
+        }
+        let source = Source {
+            pid: 42,
+            hostname: "host".into(),
+            labels,
+            start_wall_ns: None,
+        };
+        assert_eq!(read(&v10), (Some(source), serde_json::to_value(&rpts).unwrap()));
+    }
+}
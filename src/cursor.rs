@@ -0,0 +1,184 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    num::NonZeroU64,
+    slice,
+};
+
+use tracing_serde_structured as tss;
+
+use crate::{analysis::{pop_entered, IdRemap}, Element, DEFAULT_MAX_DEPTH, ReportPayload, SpanKey, ThreadKey};
+
+/// One step of a `SpanCursor`.
+pub enum Boundary<'e> {
+    /// A span was entered. `depth` is the number of spans entered on the
+    /// thread before it, and not exited yet.
+    Enter {
+        thread: ThreadKey,
+        span: SpanKey,
+        attrs: &'e tss::SerializeAttributes<'static>,
+        tick: u64,
+        depth: usize,
+    },
+    /// The innermost span entered on `thread` exited, on that thread or,
+    /// paired by span id, on another.
+    Exit {
+        thread: ThreadKey,
+        span: SpanKey,
+        attrs: &'e tss::SerializeAttributes<'static>,
+        start: u64,
+        end: u64,
+        depth: usize,
+    },
+    /// An event, with `depth` spans entered on its thread.
+    Event {
+        thread: ThreadKey,
+        event: &'e tss::SerializeEvent<'static>,
+        tick: u64,
+        depth: usize,
+    },
+}
+
+struct Entered<'e> {
+    span: SpanKey,
+    attrs: &'e tss::SerializeAttributes<'static>,
+    start: u64,
+}
+
+/// Span bounds, read off the reports one at a time, from
+/// `Elements::span_cursor`. Enters and exits pair up the way
+/// `Elements::spanner` pairs them: spans with no `OnNewSpan`, or nested
+/// more than `DEFAULT_MAX_DEPTH` deep, are skipped, an exit ends the spans
+/// entered after it first, and spans never exited end at the last tick,
+/// after everything else. Only the spans entered and
+/// not exited yet, and a reference to each span's attributes, are kept.
+pub struct SpanCursor<'e> {
+    rpts: slice::Iter<'e, Element>,
+    attrs: HashMap<SpanKey, &'e tss::SerializeAttributes<'static>>,
+    ids: IdRemap,
+    open: HashMap<ThreadKey, Vec<Entered<'e>>>,
+    /// Unknown or too deep spans that were entered, and not exited yet.
+    skipped: HashMap<ThreadKey, Vec<NonZeroU64>>,
+    /// The threads each span is entered on, innermost last.
+    entered: HashMap<SpanKey, Vec<ThreadKey>>,
+    pending: VecDeque<Boundary<'e>>,
+    last_tick: u64,
+    finished: bool,
+}
+
+impl<'e> SpanCursor<'e> {
+    pub(crate) fn new(rpts: &'e [Element]) -> Self {
+        Self {
+            rpts: rpts.iter(),
+            attrs: HashMap::new(),
+            ids: IdRemap::default(),
+            open: HashMap::new(),
+            skipped: HashMap::new(),
+            entered: HashMap::new(),
+            pending: VecDeque::new(),
+            last_tick: rpts.iter().map(|rpt| rpt.tick).max().unwrap_or(0),
+            finished: false,
+        }
+    }
+
+    fn step(&mut self, rpt: &'e Element) {
+        self.ids.note(&rpt.payload);
+        let thread = rpt.thread_key();
+        match &rpt.payload {
+            ReportPayload::OnNewSpan { attrs, id } => {
+                self.attrs.insert((rpt.source, id.id), attrs);
+            },
+            ReportPayload::OnEnter { span } => {
+                let id = self.ids.resolve(span.id);
+                self.entered.entry((rpt.source, id)).or_default().push(thread);
+                let open = self.open.entry(thread).or_default();
+                let attrs = self.attrs.get(&(rpt.source, id)).copied();
+                let Some(attrs) = attrs.filter(|_| open.len() < DEFAULT_MAX_DEPTH) else {
+                    self.skipped.entry(thread).or_default().push(id);
+                    return;
+                };
+                self.pending.push_back(Boundary::Enter {
+                    thread,
+                    span: (rpt.source, id),
+                    attrs,
+                    tick: rpt.tick,
+                    depth: open.len(),
+                });
+                open.push(Entered {
+                    span: (rpt.source, id),
+                    attrs,
+                    start: rpt.tick,
+                });
+            },
+            ReportPayload::OnExit { span } => {
+                let id = self.ids.resolve(span.id);
+                // Ends the span on the thread that entered it.
+                let thread = self
+                    .entered
+                    .get_mut(&(rpt.source, id))
+                    .and_then(|threads| pop_entered(threads, thread))
+                    .unwrap_or(thread);
+                let open = self.open.entry(thread).or_default();
+                let skipped = self.skipped.entry(thread).or_default();
+                if open.last().is_some_and(|entered| entered.span.1 == id) {
+                    let pos = open.len() - 1;
+                    self.close(thread, pos, rpt.tick);
+                } else if let Some(pos) = skipped.iter().rposition(|skipped| *skipped == id) {
+                    skipped.remove(pos);
+                } else if let Some(pos) = open.iter().rposition(|entered| entered.span.1 == id) {
+                    self.close(thread, pos, rpt.tick);
+                }
+            },
+            ReportPayload::OnEvent { event } => {
+                self.pending.push_back(Boundary::Event {
+                    thread,
+                    event,
+                    tick: rpt.tick,
+                    depth: self.open.get(&thread).map_or(0, Vec::len),
+                });
+            },
+            _ => {},
+        }
+    }
+
+    /// Exits the span at `pos` on `thread`, and everything entered after it.
+    fn close(&mut self, thread: ThreadKey, pos: usize, tick: u64) {
+        let open = self.open.entry(thread).or_default();
+        while open.len() > pos {
+            let Some(entered) = open.pop() else {
+                break;
+            };
+            self.pending.push_back(Boundary::Exit {
+                thread,
+                span: entered.span,
+                attrs: entered.attrs,
+                start: entered.start,
+                end: tick.max(entered.start),
+                depth: open.len(),
+            });
+        }
+    }
+}
+
+impl<'e> Iterator for SpanCursor<'e> {
+    type Item = Boundary<'e>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(boundary) = self.pending.pop_front() {
+                return Some(boundary);
+            }
+            match self.rpts.next() {
+                Some(rpt) => self.step(rpt),
+                None if !self.finished => {
+                    self.finished = true;
+                    let mut threads: Vec<ThreadKey> = self.open.keys().copied().collect();
+                    threads.sort_unstable();
+                    for thread in threads {
+                        self.close(thread, 0, self.last_tick);
+                    }
+                },
+                None => return None,
+            }
+        }
+    }
+}
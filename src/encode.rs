@@ -0,0 +1,318 @@
+// Only `core` and `alloc` here, so this can be built for targets without
+// `std`.
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::fmt;
+
+use tracing::{
+    field::{self, Field, FieldSet, Value, Visit},
+    span::{Attributes, Id, Record},
+    Event, Metadata,
+};
+use tracing_serde_structured::{self as tss, AsSerde};
+
+use crate::{
+    callsite::{Frame, WirePayload, WireReport},
+    format::{to_frame, Compression, Encoding, Framing, Header, Source, COMPAT, VERSION},
+    Clock, ReportPayload,
+};
+
+/// Encodes one report as a frame, as `to_frame` does.
+pub(crate) fn report_frame(tick: u64, seq: u64, thread_id: u64, payload: WirePayload<'_>, framing: Framing, encoding: Encoding) -> Vec<u8> {
+    let rpt = WireReport {
+        tick,
+        seq,
+        thread_id,
+        payload,
+    };
+    to_frame(&Frame::Report(rpt), framing, encoding)
+}
+
+/// Whether some of `values`' fields are `Empty`. tss declares a map of
+/// `values.len()` entries for a record, but only writes the fields that have
+/// a value, so such a record wouldn't decode.
+pub(crate) fn has_empty_fields(values: &Record<'_>) -> bool {
+    let mut present = 0;
+    values.record(&mut |_: &Field, _: &dyn fmt::Debug| present += 1);
+    present < values.len()
+}
+
+/// Where a `FrameEncoder` writes the stream: first the header, and then one
+/// whole frame at a time.
+pub trait FrameSink {
+    fn write(&mut self, bytes: &[u8]);
+}
+
+impl<F: FnMut(&[u8])> FrameSink for F {
+    fn write(&mut self, bytes: &[u8]) {
+        self(bytes)
+    }
+}
+
+/// Writes the same stream as `ReportLayer`, so `ReportReader` and the
+/// analyzer read it as they would any other, but with no threads, files or
+/// `Instant`s: the platform gives it a clock and the current thread's id,
+/// and its own `Subscriber` calls it, e.g. on a microcontroller writing
+/// frames out of a UART. Everything is written uncompressed, with COBS.
+/// This is all there is without the default `std` feature.
+pub struct FrameEncoder<S> {
+    sink: S,
+    clock: Box<dyn Clock>,
+    thread_id: fn() -> u64,
+    /// Ids given to callsites so far, by the address of their metadata.
+    callsites: BTreeMap<usize, u64>,
+    /// The metadata of each open span with fields, to record them one at a
+    /// time when some are `Empty`.
+    spans: BTreeMap<u64, &'static Metadata<'static>>,
+    seq: u64,
+}
+
+impl<S: FrameSink> FrameEncoder<S> {
+    /// Writes the header, with `source` as the process that wrote it.
+    pub fn new(mut sink: S, source: Source, clock: impl Clock, thread_id: fn() -> u64) -> Self {
+        let header = Header {
+            version: VERSION,
+            compat: COMPAT,
+            compression: Compression::None,
+            framing: Framing::Cobs,
+            encoding: Encoding::Postcard,
+            source: Some(source),
+        };
+        sink.write(&header.encode());
+        Self {
+            sink,
+            clock: Box::new(clock),
+            thread_id,
+            callsites: BTreeMap::new(),
+            spans: BTreeMap::new(),
+            seq: 0,
+        }
+    }
+
+    /// Returns the sink, e.g. to flush it.
+    pub fn sink_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    pub fn new_span(&mut self, attrs: &Attributes<'_>, id: &Id) {
+        let callsite = self.callsite(attrs.metadata());
+        self.write(WirePayload::OnNewSpan {
+            callsite,
+            parent: attrs.parent().map(AsSerde::as_serde),
+            is_root: attrs.is_root(),
+            id: id.as_serde(),
+        });
+        let values = Record::new(attrs.values());
+        if values.is_empty() {
+            return;
+        }
+        self.spans.insert(id.into_u64(), attrs.metadata());
+        self.record(id, &values);
+    }
+
+    pub fn record(&mut self, span: &Id, values: &Record<'_>) {
+        match self.spans.get(&span.into_u64()).copied() {
+            Some(metadata) if has_empty_fields(values) => values.record(&mut EachField {
+                encoder: self,
+                fields: metadata.fields(),
+                span,
+            }),
+            _ => self.write_record(span, values),
+        }
+    }
+
+    fn write_record(&mut self, span: &Id, values: &Record<'_>) {
+        self.write(ReportPayload::OnRecord {
+            span: span.as_serde(),
+            values: values.as_serde(),
+        });
+    }
+
+    pub fn follows_from(&mut self, span: &Id, follows: &Id) {
+        self.write(ReportPayload::OnFollowsFrom {
+            span: span.as_serde(),
+            follows: follows.as_serde(),
+        });
+    }
+
+    /// `parent` is the span the event was recorded in: its explicit parent,
+    /// or else the current span.
+    pub fn event(&mut self, event: &Event<'_>, parent: Option<&Id>) {
+        let callsite = self.callsite(event.metadata());
+        self.write(WirePayload::OnEvent {
+            callsite,
+            fields: tss::SerializeRecordFields::Ser(event),
+            parent: parent.map(AsSerde::as_serde),
+        });
+    }
+
+    pub fn enter(&mut self, span: &Id) {
+        self.write(ReportPayload::OnEnter { span: span.as_serde() });
+    }
+
+    pub fn exit(&mut self, span: &Id) {
+        self.write(ReportPayload::OnExit { span: span.as_serde() });
+    }
+
+    pub fn close(&mut self, span: &Id, busy_ns: u64, idle_ns: u64) {
+        self.spans.remove(&span.into_u64());
+        self.write(ReportPayload::OnClose {
+            span: span.as_serde(),
+            busy_ns,
+            idle_ns,
+        });
+    }
+
+    /// The id of `metadata`'s callsite, writing its definition the first
+    /// time.
+    fn callsite(&mut self, metadata: &'static Metadata<'static>) -> u64 {
+        let key = metadata as *const Metadata<'static> as usize;
+        if let Some(id) = self.callsites.get(&key) {
+            return *id;
+        }
+        let id = self.callsites.len() as u64;
+        self.callsites.insert(key, id);
+        let frame = Frame::Callsite {
+            id,
+            metadata: metadata.as_serde(),
+        };
+        self.sink.write(&postcard::to_allocvec_cobs(&frame).expect("metadata always serializes"));
+        id
+    }
+
+    fn write<'a>(&mut self, payload: impl Into<WirePayload<'a>>) {
+        let frame = report_frame(self.clock.now_nanos(), self.seq, (self.thread_id)(), payload.into(), Framing::Cobs, Encoding::Postcard);
+        self.seq += 1;
+        self.sink.write(&frame);
+    }
+}
+
+/// Records each field that has a value on its own, for a span created with
+/// `Empty` fields.
+struct EachField<'a, S> {
+    encoder: &'a mut FrameEncoder<S>,
+    fields: &'a FieldSet,
+    span: &'a Id,
+}
+
+impl<S: FrameSink> EachField<'_, S> {
+    fn record(&mut self, field: &Field, value: &dyn Value) {
+        let values = [(field, Some(value))];
+        let values = self.fields.value_set(&values);
+        self.encoder.write_record(self.span, &Record::new(&values));
+    }
+}
+
+impl<S: FrameSink> Visit for EachField<'_, S> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field, &value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, &value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, &value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, &value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, &value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, &field::debug(value));
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tracing::{field::Empty, Subscriber};
+    use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+
+    use super::*;
+    use crate::{ReportReader, ReportError};
+
+    struct Stopped;
+
+    impl Clock for Stopped {
+        fn now_nanos(&self) -> u64 {
+            0
+        }
+    }
+
+    #[derive(Default)]
+    struct Bytes(Vec<u8>);
+
+    impl FrameSink for Bytes {
+        fn write(&mut self, bytes: &[u8]) {
+            self.0.extend_from_slice(bytes);
+        }
+    }
+
+    /// Drives a `FrameEncoder` the way a platform's own subscriber would.
+    struct EncoderLayer(Arc<Mutex<FrameEncoder<Bytes>>>);
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for EncoderLayer {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _: Context<'_, S>) {
+            self.0.lock().unwrap().new_span(attrs, id);
+        }
+
+        fn on_record(&self, span: &Id, values: &Record<'_>, _: Context<'_, S>) {
+            self.0.lock().unwrap().record(span, values);
+        }
+
+        fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+            let parent = ctx.event_span(event).map(|span| span.id());
+            self.0.lock().unwrap().event(event, parent.as_ref());
+        }
+
+        fn on_enter(&self, span: &Id, _: Context<'_, S>) {
+            self.0.lock().unwrap().enter(span);
+        }
+
+        fn on_exit(&self, span: &Id, _: Context<'_, S>) {
+            self.0.lock().unwrap().exit(span);
+        }
+
+        fn on_close(&self, span: Id, _: Context<'_, S>) {
+            self.0.lock().unwrap().close(&span, 0, 0);
+        }
+    }
+
+    #[test]
+    fn empty_fields_round_trip() {
+        let encoder = FrameEncoder::new(Bytes::default(), Source::current(), Stopped, || 1);
+        let encoder = Arc::new(Mutex::new(encoder));
+        let subscriber = tracing_subscriber::registry().with(EncoderLayer(encoder.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("outer", a = 1, b = Empty, c = Empty);
+            span.record("b", "set");
+            span.in_scope(|| tracing::info!(d = Empty, e = 2, "inside"));
+        });
+
+        let bytes = std::mem::take(&mut encoder.lock().unwrap().sink_mut().0);
+        let rpts: Vec<_> = ReportReader::new(&bytes[..]).unwrap().collect::<Result<_, ReportError>>().unwrap();
+        let fields: Vec<_> = rpts
+            .iter()
+            .filter_map(|rpt| match &rpt.payload {
+                ReportPayload::OnRecord { values, .. } => Some(serde_json::to_value(values).unwrap()),
+                ReportPayload::OnEvent { event } => Some(serde_json::to_value(&event.fields).unwrap()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                serde_json::json!({ "a": { "I64": 1 } }),
+                serde_json::json!({ "b": { "Str": "set" } }),
+                serde_json::json!({ "e": { "I64": 2 }, "message": { "Debug": "inside" } }),
+            ]
+        );
+    }
+}
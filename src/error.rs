@@ -0,0 +1,84 @@
+use std::{error::Error, fmt, io, num::NonZeroU64};
+
+/// Why reading or reconstructing a trace failed.
+#[derive(Debug)]
+pub enum ReportError {
+    /// Reading the input failed.
+    Io(io::Error),
+    /// The header or a frame is malformed. Later frames may still decode.
+    Decode(String),
+    /// The stream needs a newer reader.
+    VersionMismatch {
+        /// Format version of the stream.
+        version: u8,
+        /// Oldest reader version that can read it.
+        compat: u8,
+    },
+    /// A span was entered with no `OnNewSpan` for it, e.g. because the
+    /// trace was started mid-span or a ring buffer dropped it.
+    UnknownSpanId(NonZeroU64),
+    /// Enters and exits don't pair up.
+    UnbalancedSpan {
+        orphan_exits: usize,
+        unclosed_spans: usize,
+    },
+    /// Spans are nested deeper than reconstruction allows.
+    TooDeep {
+        max_depth: usize,
+    },
+}
+
+impl fmt::Display for ReportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReportError::Io(e) => write!(f, "{}", e),
+            ReportError::Decode(msg) => write!(f, "malformed report stream: {}", msg),
+            ReportError::VersionMismatch { version, compat } => write!(
+                f,
+                "stream is format version {}, and needs a reader for version {} or later (this one reads up to {})",
+                version,
+                compat,
+                crate::format::VERSION,
+            ),
+            ReportError::UnknownSpanId(id) => write!(f, "span {} was entered, but never created", id),
+            ReportError::UnbalancedSpan { orphan_exits, unclosed_spans } => write!(
+                f,
+                "unbalanced trace ({} orphan exits, {} unclosed spans)",
+                orphan_exits, unclosed_spans,
+            ),
+            ReportError::TooDeep { max_depth } => write!(f, "spans are nested more than {} deep", max_depth),
+        }
+    }
+}
+
+impl Error for ReportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReportError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReportError {
+    fn from(e: io::Error) -> Self {
+        ReportError::Io(e)
+    }
+}
+
+impl From<postcard::Error> for ReportError {
+    fn from(e: postcard::Error) -> Self {
+        ReportError::Decode(e.to_string())
+    }
+}
+
+/// For callers working in `io::Result`s. Everything but `Io` becomes
+/// `ErrorKind::InvalidData`.
+impl From<ReportError> for io::Error {
+    fn from(e: ReportError) -> Self {
+        match e {
+            ReportError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
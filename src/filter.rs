@@ -0,0 +1,165 @@
+use std::{error::Error, fmt, str::FromStr};
+
+use tracing::{level_filters::LevelFilter, Metadata};
+
+/// Per-target level rules, in the same syntax as `tracing_subscriber`'s
+/// `EnvFilter`: comma-separated `target=level` directives, where a bare
+/// `level` sets the default and a bare `target` enables everything from it,
+/// e.g. `"myapp=debug,hyper=off,myapp::db=trace"`.
+///
+/// The directive with the longest matching target wins. Like `EnvFilter`,
+/// anything no directive matches is disabled, unless a bare `level` sets a
+/// default, and an empty filter enables only `ERROR`. Span-name and field
+/// directives aren't supported.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    default: LevelFilter,
+    /// Sorted by descending target length, so the first match is the most
+    /// specific.
+    directives: Vec<(String, LevelFilter)>,
+}
+
+#[derive(Debug)]
+pub struct ParseFilterError {
+    directive: String,
+}
+
+impl fmt::Display for ParseFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid filter directive `{}`", self.directive)
+    }
+}
+
+impl Error for ParseFilterError {}
+
+impl Filter {
+    /// Like `parse`, but skips invalid directives instead of failing, as
+    /// `EnvFilter` does for environment variables.
+    pub fn parse_lenient(s: &str) -> Self {
+        Self::build(s.split(',').filter_map(|d| parse_directive(d).ok()))
+    }
+
+    fn build(parsed: impl Iterator<Item = Option<(Option<String>, LevelFilter)>>) -> Self {
+        let mut filter = Filter {
+            default: LevelFilter::ERROR,
+            directives: Vec::new(),
+        };
+        let mut default = None;
+        for (target, level) in parsed.flatten() {
+            match target {
+                Some(target) => filter.directives.push((target, level)),
+                None => default = Some(level),
+            }
+        }
+        filter.default = match default {
+            Some(level) => level,
+            None if filter.directives.is_empty() => LevelFilter::ERROR,
+            None => LevelFilter::OFF,
+        };
+        filter.directives.sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        filter
+    }
+
+    pub fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= &self.level_for(metadata.target())
+    }
+
+    /// The most verbose level enabled for `target`.
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .find(|(prefix, _)| {
+                target.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = ParseFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = s.split(',').map(parse_directive).collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::build(parsed.into_iter()))
+    }
+}
+
+/// Returns `None` for empty directives, e.g. from a trailing comma.
+fn parse_directive(directive: &str) -> Result<Option<(Option<String>, LevelFilter)>, ParseFilterError> {
+    let directive = directive.trim();
+    if directive.is_empty() {
+        return Ok(None);
+    }
+    let err = || ParseFilterError {
+        directive: directive.to_string(),
+    };
+    let valid_target = |t: &str| !t.is_empty() && !t.contains(['[', ']', '{', '}', '=']);
+
+    match directive.split_once('=') {
+        Some((target, level)) if valid_target(target) => {
+            let level = level.parse().map_err(|_| err())?;
+            Ok(Some((Some(target.to_string()), level)))
+        }
+        Some(_) => Err(err()),
+        None => match directive.parse::<LevelFilter>() {
+            Ok(level) => Ok(Some((None, level))),
+            Err(_) if valid_target(directive) => Ok(Some((Some(directive.to_string()), LevelFilter::TRACE))),
+            Err(_) => Err(err()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels(filter: &str, targets: &[&str]) -> Vec<LevelFilter> {
+        let filter: Filter = filter.parse().unwrap();
+        targets.iter().map(|target| filter.level_for(target)).collect()
+    }
+
+    #[test]
+    fn longest_target_wins() {
+        assert_eq!(
+            levels("myapp=debug,hyper=off,myapp::db=trace", &["myapp", "myapp::db", "myapp::db::pool", "myapp::web", "hyper"]),
+            [LevelFilter::DEBUG, LevelFilter::TRACE, LevelFilter::TRACE, LevelFilter::DEBUG, LevelFilter::OFF],
+        );
+    }
+
+    #[test]
+    fn targets_match_whole_path_segments() {
+        assert_eq!(levels("myapp=debug", &["myapp2", "myapp::x"]), [LevelFilter::OFF, LevelFilter::DEBUG]);
+    }
+
+    #[test]
+    fn unmatched_targets_are_disabled() {
+        assert_eq!(levels("myapp=debug", &["other"]), [LevelFilter::OFF]);
+        assert_eq!(levels("myapp", &["myapp", "other"]), [LevelFilter::TRACE, LevelFilter::OFF]);
+    }
+
+    #[test]
+    fn bare_level_sets_the_default() {
+        assert_eq!(levels("warn,myapp=trace", &["myapp", "other"]), [LevelFilter::TRACE, LevelFilter::WARN]);
+    }
+
+    #[test]
+    fn empty_filter_enables_errors() {
+        assert_eq!(levels("", &["other"]), [LevelFilter::ERROR]);
+        assert_eq!(levels(" , ", &["other"]), [LevelFilter::ERROR]);
+    }
+
+    #[test]
+    fn invalid_directives() {
+        for invalid in ["=debug", "myapp=loud", "myapp[span]=debug", "a{b}"] {
+            assert!(invalid.parse::<Filter>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn lenient_parsing_skips_invalid_directives() {
+        let filter = Filter::parse_lenient("myapp=loud,other=info");
+        assert_eq!(filter.level_for("myapp"), LevelFilter::OFF);
+        assert_eq!(filter.level_for("other"), LevelFilter::INFO);
+    }
+}
@@ -0,0 +1,454 @@
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+#[cfg(feature = "std")]
+use std::{
+    io::{self, Read, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "std")]
+use once_cell::sync::Lazy;
+#[cfg(feature = "std")]
+use postcard::ser_flavors::{Cobs, Flavor, StdVec};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "std")]
+use crate::{
+    compat::{SourceV10, SourceV5},
+    ReportError,
+};
+
+/// Every stream written by `ReportLayer` starts with these bytes, followed by
+/// the version byte and the rest of the header.
+pub const MAGIC: [u8; 4] = *b"TRPT";
+
+pub const VERSION: u8 = 17;
+
+/// The oldest format version whose readers can read what this version
+/// writes.
+///
+/// Appending variants to `Frame`, `WirePayload` or `ReportPayload`, or
+/// appending fields to `Source`, keeps this as it is: an older reader then
+/// reads the stream, and yields a `ReportError::Decode` for each frame it
+/// doesn't understand. COBS framing means one such frame never affects the
+/// next. Any other change must raise this to the new `VERSION`. The header,
+/// up to and including the `compat` byte, never changes.
+pub const COMPAT: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+/// How frames are delimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Framing {
+    /// COBS-encoded, each followed by a `0` byte. A reader can pick the
+    /// stream up again at the next `0` after any corruption.
+    #[default]
+    Cobs,
+    /// A varint length, followed by that many bytes of the plain frame, so a
+    /// reader can skip a frame without looking at it. Since version 11;
+    /// older readers can't read these streams.
+    LengthPrefixed,
+}
+
+/// The first version that can read length-prefixed streams, and whose
+/// headers have a framing byte when `compat` is at least this.
+const FRAMING_VERSION: u8 = 11;
+
+/// How each frame is serialized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Postcard,
+    /// bincode 1.x, with its default options: fixed-width little-endian
+    /// integers, `u64` lengths and `u32` enum tags. Since version 12; only
+    /// readers built with the `bincode` feature can read these streams.
+    #[cfg(feature = "bincode")]
+    Bincode,
+}
+
+/// The first version that can read bincode streams, and whose headers have
+/// an encoding byte when `compat` is at least this.
+const ENCODING_VERSION: u8 = 12;
+
+/// The process that wrote a stream, so streams from several processes or
+/// machines can be told apart after merging.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Source {
+    pub pid: u32,
+    pub hostname: String,
+    /// Set with `ReportLayerBuilder::label`, e.g. a build version or git
+    /// SHA. Since version 10.
+    pub labels: BTreeMap<String, String>,
+    /// The wall-clock time when the layer's clock read 0, in nanoseconds
+    /// since the Unix epoch, so every report's wall time is this plus its
+    /// tick. Since version 17.
+    pub start_wall_ns: Option<u64>,
+}
+
+#[cfg(feature = "std")]
+static CURRENT: Lazy<Source> = Lazy::new(|| Source {
+    pid: std::process::id(),
+    hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+    labels: BTreeMap::new(),
+    start_wall_ns: None,
+});
+
+#[cfg(feature = "std")]
+impl Source {
+    pub fn current() -> Self {
+        CURRENT.clone()
+    }
+
+    /// `start_wall_ns`, for `Report::wall_time`.
+    pub fn start_wall(&self) -> Option<SystemTime> {
+        Some(UNIX_EPOCH + Duration::from_nanos(self.start_wall_ns?))
+    }
+}
+
+/// The uncompressed prefix of a report stream.
+///
+/// Streams written before the header existed have no prefix at all, and are
+/// read as uncompressed COBS frames.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    /// The oldest reader version that can read this stream. Since version
+    /// 7; for older streams, the same as `version`.
+    pub compat: u8,
+    pub compression: Compression,
+    /// Since version 11. Always COBS before that.
+    pub framing: Framing,
+    /// Since version 12. Always postcard before that. The `Source` frame
+    /// in the header is postcard either way.
+    pub encoding: Encoding,
+    /// Since version 5.
+    pub source: Option<Source>,
+}
+
+impl Header {
+    /// Length of the part of the header that's the same in every version.
+    pub const LEN: usize = MAGIC.len() + 2;
+
+    #[cfg(feature = "std")]
+    pub fn new(compression: Compression) -> Self {
+        Self {
+            version: VERSION,
+            compat: COMPAT,
+            compression,
+            framing: Framing::Cobs,
+            encoding: Encoding::Postcard,
+            source: Some(Source::current()),
+        }
+    }
+
+    /// Length-prefixed streams need a reader that knows about them.
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        if framing != Framing::Cobs {
+            self.compat = self.compat.max(FRAMING_VERSION);
+        }
+        self.framing = framing;
+        self
+    }
+
+    /// bincode streams need a reader that knows about them.
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        if encoding != Encoding::Postcard {
+            self.compat = self.compat.max(ENCODING_VERSION);
+        }
+        self.encoding = encoding;
+        self
+    }
+
+    /// Sets the labels of the source.
+    pub fn with_labels(mut self, labels: BTreeMap<String, String>) -> Self {
+        if let Some(source) = self.source.as_mut() {
+            source.labels = labels;
+        }
+        self
+    }
+
+    /// Sets the `start_wall_ns` of the source.
+    pub fn with_start_wall(mut self, start_wall_ns: Option<u64>) -> Self {
+        if let Some(source) = self.source.as_mut() {
+            source.start_wall_ns = start_wall_ns;
+        }
+        self
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let compression = match self.compression {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+        };
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([self.version, compression]);
+        if self.version >= 7 {
+            bytes.push(self.compat);
+        }
+        if self.compat >= FRAMING_VERSION {
+            bytes.push(match self.framing {
+                Framing::Cobs => 0,
+                Framing::LengthPrefixed => 1,
+            });
+        }
+        if self.compat >= ENCODING_VERSION {
+            bytes.push(match self.encoding {
+                Encoding::Postcard => 0,
+                #[cfg(feature = "bincode")]
+                Encoding::Bincode => 1,
+            });
+        }
+        if let Some(source) = self.source.as_ref() {
+            bytes.extend(postcard::to_allocvec_cobs(source).expect("a `Source` always serializes"));
+        }
+        bytes
+    }
+
+    #[cfg(feature = "std")]
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.encode())
+    }
+
+    #[cfg(feature = "std")]
+    /// Reads the rest of the header, after the `LEN` bytes given to `parse`.
+    /// Fails if the stream is too new for this reader.
+    pub fn read_rest<R: Read>(&mut self, r: &mut R) -> Result<(), ReportError> {
+        if self.version >= 7 {
+            let mut compat = [0];
+            r.read_exact(&mut compat)?;
+            self.compat = compat[0];
+        }
+        if self.compat > VERSION {
+            return Err(ReportError::VersionMismatch {
+                version: self.version,
+                compat: self.compat,
+            });
+        }
+        if self.compat >= FRAMING_VERSION {
+            let mut framing = [0];
+            r.read_exact(&mut framing)?;
+            self.framing = match framing[0] {
+                0 => Framing::Cobs,
+                1 => Framing::LengthPrefixed,
+                other => return Err(ReportError::Decode(format!("unknown framing {}", other))),
+            };
+        }
+        if self.compat >= ENCODING_VERSION {
+            let mut encoding = [0];
+            r.read_exact(&mut encoding)?;
+            self.encoding = match encoding[0] {
+                0 => Encoding::Postcard,
+                #[cfg(feature = "bincode")]
+                1 => Encoding::Bincode,
+                #[cfg(not(feature = "bincode"))]
+                1 => {
+                    return Err(ReportError::Decode(
+                        "stream is bincode-encoded, and this reader was built without the `bincode` feature".into(),
+                    ))
+                }
+                other => return Err(ReportError::Decode(format!("unknown encoding {}", other))),
+            };
+        }
+
+        if self.version < 5 {
+            return Ok(());
+        }
+
+        // Read a byte at a time, so nothing after the frame is consumed.
+        let mut frame = vec![];
+        let mut byte = [0];
+        loop {
+            r.read_exact(&mut byte)?;
+            match byte[0] {
+                0 => break,
+                b => frame.push(b),
+            }
+        }
+        let source = match self.version {
+            5..=9 => postcard::from_bytes_cobs::<SourceV5>(&mut frame)?.upgrade(),
+            10..=16 => postcard::from_bytes_cobs::<SourceV10>(&mut frame)?.upgrade(),
+            _ => postcard::from_bytes_cobs(&mut frame)?,
+        };
+        self.source = Some(source);
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    /// Parses the fixed part of the header. Returns `None` if `bytes` doesn't
+    /// start with a header at all.
+    pub fn parse(bytes: &[u8]) -> Option<Result<Self, ReportError>> {
+        if bytes.len() < Self::LEN || bytes[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+
+        // Whether a newer version is readable depends on `compat`, which is
+        // checked by `read_rest`.
+        let version = bytes[MAGIC.len()];
+        let compression = match bytes[MAGIC.len() + 1] {
+            0 => Compression::None,
+            1 => Compression::Zstd,
+            other => return Some(Err(ReportError::Decode(format!("unknown compression {}", other)))),
+        };
+
+        Some(Ok(Self {
+            version,
+            compat: version,
+            compression,
+            framing: Framing::Cobs,
+            encoding: Encoding::Postcard,
+            source: None,
+        }))
+    }
+}
+
+/// A machine-readable description of the on-disk format, for writing
+/// decoders in other languages. Serializable, e.g. to JSON.
+#[derive(Debug, Serialize)]
+pub struct FormatDescription {
+    pub version: u8,
+    pub compat: u8,
+    pub compatibility: &'static str,
+    pub header: &'static [Field],
+    pub framing: &'static str,
+    pub encoding: &'static str,
+    /// Since version 6, every frame is one of these. Before that, every
+    /// frame is a report.
+    pub frame: &'static [Variant],
+    pub report: &'static [Field],
+    /// Since version 6, `ReportPayload` is wrapped in this.
+    pub wire_payload: &'static [Variant],
+    pub payload: &'static [Variant],
+}
+
+#[derive(Debug, Serialize)]
+pub struct Field {
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub ty: &'static str,
+}
+
+/// One `ReportPayload` variant. `index` is the postcard enum discriminant.
+#[derive(Debug, Serialize)]
+pub struct Variant {
+    pub index: u32,
+    pub name: &'static str,
+    pub fields: &'static [Field],
+}
+
+const fn field(name: &'static str, ty: &'static str) -> Field {
+    Field { name, ty }
+}
+
+static DESCRIPTION: FormatDescription = FormatDescription {
+    version: VERSION,
+    compat: COMPAT,
+    compatibility: "a reader for version N reads any stream whose compat is at most N. \
+        Newer streams it can read may contain frames it doesn't understand, \
+        which it skips one frame at a time.",
+    header: &[
+        field("magic", "[u8; 4] = \"TRPT\""),
+        field("version", "u8"),
+        field("compression", "u8 (0 = none, 1 = zstd: everything after the header is a sequence of zstd frames)"),
+        field("compat", "u8, the oldest format version that can read this stream (since version 7)"),
+        field("framing", "u8 (0 = COBS, 1 = length-prefixed), only present if compat is 11 or more"),
+        field("encoding", "u8 (0 = postcard, 1 = bincode), only present if compat is 12 or more"),
+        field("source", "postcard COBS frame of { pid: u32, hostname: String, labels: Map<String, String> (since version 10), start_wall_ns: Option<u64>, the Unix time in nanoseconds when tick 0 was (since version 17) }, always uncompressed (since version 5)"),
+    ],
+    framing: "each frame is postcard-encoded, then COBS-encoded and followed by a single 0 byte, \
+        including the last one. Readers skip empty frames, i.e. consecutive 0 bytes. \
+        With length-prefixed framing instead, each frame is a LEB128 varint length followed by \
+        that many bytes of the plain frame, and zero-length frames are skipped. \
+        Since version 6, a Callsite frame comes before the first report that refers to its id, \
+        and may be repeated later in the stream.",
+    encoding: "postcard 1.x: unsigned integers are LEB128 varints, signed integers are zigzag varints, \
+        enums are a varint discriminant followed by their fields, Option is a 0/1 tag, \
+        strings and sequences are a varint length followed by their contents. \
+        Streams whose encoding byte is 1 use bincode 1.x with its default options instead, \
+        for every frame but the source: fixed-width little-endian integers, u32 enum discriminants, \
+        u64 lengths. \
+        `tss::*` types are from tracing-serde-structured 0.1.",
+    frame: &[
+        Variant { index: 0, name: "Callsite", fields: &[field("id", "u64"), field("metadata", "tss::SerializeMetadata")] },
+        Variant { index: 1, name: "Report", fields: &[field("report", "Report")] },
+        Variant { index: 2, name: "Full", fields: &[field("report", "Report, with ReportPayload in place of WirePayload (since version 8)")] },
+    ],
+    report: &[
+        field("tick", "u64 (u128 before version 4), nanoseconds from the layer's clock"),
+        field("seq", "u64, global production order (since version 2)"),
+        field("thread_id", "u64"),
+        field("payload", "WirePayload (ReportPayload before version 6)"),
+    ],
+    wire_payload: &[
+        Variant { index: 0, name: "OnNewSpan", fields: &[field("callsite", "u64"), field("parent", "Option<tss::SerializeId>"), field("is_root", "bool"), field("id", "tss::SerializeId")] },
+        Variant { index: 1, name: "OnEvent", fields: &[field("callsite", "u64"), field("fields", "tss::SerializeRecordFields"), field("parent", "Option<tss::SerializeId>, the explicit parent, or else the current span (since version 10)")] },
+        Variant { index: 2, name: "Other", fields: &[field("payload", "ReportPayload")] },
+    ],
+    payload: &[
+        Variant { index: 0, name: "OnNewSpan", fields: &[field("attrs", "tss::SerializeAttributes"), field("id", "tss::SerializeId")] },
+        Variant { index: 1, name: "OnRecord", fields: &[field("span", "tss::SerializeId"), field("values", "tss::SerializeRecord (since version 9, also written right after OnNewSpan with the span's initial values)")] },
+        Variant { index: 2, name: "OnFollowsFrom", fields: &[field("span", "tss::SerializeId"), field("follows", "tss::SerializeId")] },
+        Variant { index: 3, name: "OnEvent", fields: &[field("event", "tss::SerializeEvent")] },
+        Variant { index: 4, name: "OnEnter", fields: &[field("span", "tss::SerializeId")] },
+        Variant { index: 5, name: "OnExit", fields: &[field("span", "tss::SerializeId")] },
+        Variant { index: 6, name: "OnIdChange", fields: &[field("old", "tss::SerializeId"), field("new", "tss::SerializeId")] },
+        Variant { index: 7, name: "OnClose", fields: &[field("span", "tss::SerializeId"), field("busy_ns", "u64 (since version 3)"), field("idle_ns", "u64 (since version 3)")] },
+        Variant { index: 8, name: "Marker", fields: &[field("name", "String (since version 13)")] },
+        Variant { index: 9, name: "ThreadName", fields: &[field("name", "String (since version 14)")] },
+        Variant { index: 10, name: "Heartbeat", fields: &[field("interval_ns", "u64 (since version 15)")] },
+        Variant { index: 11, name: "CpuTime", fields: &[field("span", "tss::SerializeId (since version 16)"), field("cpu_ns", "u64")] },
+    ],
+};
+
+/// Describes the format written by this version of the crate. Its `version`
+/// matches the version byte in the header, so consumers can check which
+/// description applies to a given stream.
+pub fn describe() -> &'static FormatDescription {
+    &DESCRIPTION
+}
+
+/// Appends one frame, already encoded for `framing` (see `report_frame`),
+/// adding its length prefix if it needs one.
+#[cfg(feature = "std")]
+pub(crate) fn push_frame(buf: &mut Vec<u8>, frame: &[u8], framing: Framing) {
+    if framing == Framing::LengthPrefixed {
+        let mut len = frame.len() as u64;
+        while len >= 0x80 {
+            buf.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        buf.push(len as u8);
+    }
+    buf.extend_from_slice(frame);
+}
+
+/// Serializes one frame in `encoding`: with COBS, including the trailing 0
+/// byte, or for length prefixes, plain, to be prefixed on write.
+pub(crate) fn to_frame<T: Serialize>(frame: &T, framing: Framing, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Postcard => match framing {
+            Framing::Cobs => postcard::to_allocvec_cobs(frame),
+            Framing::LengthPrefixed => postcard::to_allocvec(frame),
+        }
+        .expect("a frame always serializes"),
+        #[cfg(feature = "bincode")]
+        Encoding::Bincode => {
+            let plain = bincode::serialize(frame).expect("a frame always serializes");
+            match framing {
+                Framing::Cobs => to_cobs(&plain),
+                Framing::LengthPrefixed => plain,
+            }
+        }
+    }
+}
+
+/// COBS-encodes a plain frame, for outputs that always use COBS
+/// when the writer's frames are length-prefixed.
+#[cfg(feature = "std")]
+pub(crate) fn to_cobs(frame: &[u8]) -> Vec<u8> {
+    let mut cobs = Cobs::try_new(StdVec::new()).expect("a Vec never runs out of room");
+    cobs.try_extend(frame).expect("a Vec never runs out of room");
+    cobs.finalize().expect("a Vec never runs out of room")
+}
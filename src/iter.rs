@@ -0,0 +1,29 @@
+use crate::{Report, ReportPayload};
+
+/// Filters for a stream of reports.
+///
+/// `ReportReader` yields `Result`s, so decode errors need handling first,
+/// e.g. `reader.filter_map(Result::ok).for_thread(1).events()`.
+pub trait ReportIterExt<'a>: Iterator<Item = Report<'a>> + Sized {
+    /// Only `OnEvent` reports.
+    fn events(self) -> impl Iterator<Item = Report<'a>> {
+        self.filter(|rpt| matches!(rpt.payload, ReportPayload::OnEvent { .. }))
+    }
+
+    /// Only `OnEnter` reports.
+    fn span_enters(self) -> impl Iterator<Item = Report<'a>> {
+        self.filter(|rpt| matches!(rpt.payload, ReportPayload::OnEnter { .. }))
+    }
+
+    /// Only `OnExit` reports.
+    fn span_exits(self) -> impl Iterator<Item = Report<'a>> {
+        self.filter(|rpt| matches!(rpt.payload, ReportPayload::OnExit { .. }))
+    }
+
+    /// Only reports from the given thread.
+    fn for_thread(self, thread_id: u64) -> impl Iterator<Item = Report<'a>> {
+        self.filter(move |rpt| rpt.thread_id == thread_id)
+    }
+}
+
+impl<'a, I: Iterator<Item = Report<'a>>> ReportIterExt<'a> for I {}
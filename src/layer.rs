@@ -0,0 +1,1275 @@
+use once_cell::sync::Lazy;
+use std::ops::Deref;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{self, Write},
+    mem,
+    net::SocketAddr,
+    panic,
+    path::PathBuf,
+    ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc, Mutex, PoisonError, Weak,
+    },
+    thread_local,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    span::{Attributes, Id, Record},
+    Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, Layer},
+    registry::LookupSpan,
+};
+use tracing_serde_structured::{self as tss, AsSerde};
+
+#[cfg(feature = "tokio")]
+use crate::async_writer::{spawn_async_writer, AsyncSink};
+use crate::{
+    callsite::WirePayload,
+    capture::Capture,
+    clock::{self, Clock, InstantClock},
+    encode::{has_empty_fields, report_frame},
+    filter::Filter,
+    format::{Compression, Encoding, Framing, Header},
+    limit::{RateLimit, Suppressed},
+    queue::{DropPolicy, Queue},
+    registry::Registry,
+    ring::RingBuffer,
+    truncate::{to_record_map, truncated, truncated_owned, OwnedFields},
+    writer::{spawn_writer, Buffering, ErrorCallback, Target, Writer, WriterConfig},
+    CaptureHandle, ReportPayload,
+};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use crate::mmap::MmapOverflow;
+
+static THREAD_ID: AtomicU64 = AtomicU64::new(1);
+pub(crate) static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+pub struct ReportLayer {
+    sink: Sink,
+    shared: Arc<Shared>,
+    clock: Arc<dyn Clock>,
+    rate_limit: Option<RateLimit>,
+    recorded: Recorded,
+    filter: Option<Filter>,
+    thread_ids: ThreadIds,
+    only_thread: Option<ThreadPredicate>,
+    max_value_len: Option<usize>,
+    max_reports: Option<u64>,
+    /// Reports handled so far, when there's a `max_reports`.
+    reports: AtomicU64,
+    /// How each report is encoded: in the writer's framing and encoding, so
+    /// its outputs can write it as is, or else postcard with COBS.
+    framing: Framing,
+    encoding: Encoding,
+}
+
+type ThreadPredicate = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// Where each report's `thread_id` comes from.
+#[derive(Clone, Copy, Default)]
+enum ThreadIds {
+    /// Sequential, in the order threads first report.
+    #[default]
+    Sequential,
+    Os,
+    NameHash,
+}
+
+/// Which `Layer` callbacks write reports, besides those for span creation,
+/// enters, exits and closes, which always do.
+#[derive(Clone, Copy)]
+struct Recorded {
+    events: bool,
+    values: bool,
+    follows_from: bool,
+    id_changes: bool,
+    /// Whether span values are held back until the span closes.
+    values_on_close: bool,
+    cpu_time: bool,
+}
+
+impl Default for Recorded {
+    fn default() -> Self {
+        Self {
+            events: true,
+            values: true,
+            follows_from: true,
+            id_changes: true,
+            values_on_close: false,
+            cpu_time: false,
+        }
+    }
+}
+
+enum Sink {
+    Writer(Arc<Queue<Vec<u8>>>, DropPolicy),
+    /// Frames go to a task on the tokio runtime instead of a thread.
+    #[cfg(feature = "tokio")]
+    Async(tokio::sync::mpsc::Sender<Vec<u8>>),
+    /// Frames go straight into `Shared::ring`, with no writer thread.
+    Ring,
+    /// Frames are written by the thread that produced them, through
+    /// `Shared::inline`.
+    Inline,
+    /// Frames are decoded straight back into reports, for tests.
+    Capture(Arc<Mutex<Capture>>),
+}
+
+/// State shared between a layer, its writer thread, and any handles.
+#[derive(Default)]
+pub(crate) struct Shared {
+    pub(crate) dropped: AtomicU64,
+    pub(crate) ring: Option<Mutex<RingBuffer>>,
+    pub(crate) callsites: Registry,
+    /// Only set when there's a writer thread.
+    pub(crate) flushes: Option<Mutex<Flushes>>,
+    /// Only set for `synchronous` layers.
+    pub(crate) inline: Option<Mutex<Writer>>,
+    /// Written in the header of every stream.
+    pub(crate) labels: BTreeMap<String, String>,
+    pub(crate) start_wall_ns: Option<u64>,
+}
+
+#[derive(Default)]
+pub(crate) struct Flushes {
+    /// Acks for flushes the writer hasn't done yet, each sent whether
+    /// everything was written and synced.
+    pending: Vec<SyncSender<bool>>,
+    /// Set when the writer exits, after its last sync.
+    done: bool,
+}
+
+impl Shared {
+    pub(crate) fn header(&self, compression: Compression) -> Header {
+        Header::new(compression)
+            .with_labels(self.labels.clone())
+            .with_start_wall(self.start_wall_ns)
+    }
+
+    pub(crate) fn take_flush_requests(&self) -> Vec<SyncSender<bool>> {
+        match self.flushes.as_ref() {
+            Some(flushes) => mem::take(&mut flushes.lock().unwrap_or_else(PoisonError::into_inner).pending),
+            None => Vec::new(),
+        }
+    }
+
+    /// Called by the writer on its way out, so nobody waits on it for a
+    /// flush. `synced` is whether its last sync succeeded.
+    pub(crate) fn writer_done(&self, synced: bool) {
+        if let Some(flushes) = self.flushes.as_ref() {
+            let mut flushes = flushes.lock().unwrap_or_else(PoisonError::into_inner);
+            flushes.done = true;
+            for ack in flushes.pending.drain(..) {
+                let _ = ack.send(synced);
+            }
+        }
+    }
+}
+
+/// Observes a `ReportLayer` after it has been moved into a subscriber.
+#[derive(Clone)]
+pub struct ReportHandle {
+    shared: Arc<Shared>,
+}
+
+impl ReportHandle {
+    /// Number of reports that were lost instead of being written.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Writes the frames currently held by a `ring_buffer` layer to `w`, as a
+    /// complete uncompressed report stream. Safe to call from a panic hook.
+    pub fn dump_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let Some(ring) = self.shared.ring.as_ref() else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "dump_to requires a layer built with ring_buffer()",
+            ));
+        };
+        // A panic while holding the lock can't leave the ring inconsistent,
+        // and a panic hook is exactly where we want this to keep working.
+        let ring = ring.lock().unwrap_or_else(PoisonError::into_inner);
+        ring.dump_to(&mut w, &self.shared)
+    }
+
+    /// Blocks until the writer thread has written and synced everything
+    /// queued so far, e.g. before a checkpoint. The layer keeps running.
+    /// Without a writer thread, or once it has exited, returns right away.
+    pub fn flush(&self) {
+        if let Some(writer) = self.shared.inline.as_ref() {
+            writer.lock().unwrap_or_else(PoisonError::into_inner).sync(&self.shared);
+        }
+        if let Some(rx) = self.request_flush() {
+            let _ = rx.recv();
+        }
+    }
+
+    /// Like `flush`, but fails unless every report so far is known to be
+    /// written and synced, e.g. before a test reads the file back: if
+    /// there's no writer to flush, as with `ring_buffer`, if it has exited,
+    /// if an output failed to write or sync, or if any report was dropped.
+    /// An `mmap` file is only scheduled to be written back, but what's in it
+    /// can be read right away.
+    pub fn barrier(&self) -> io::Result<()> {
+        let synced = match self.shared.inline.as_ref() {
+            Some(writer) => writer.lock().unwrap_or_else(PoisonError::into_inner).sync(&self.shared),
+            None => match self.request_flush() {
+                Some(rx) => rx.recv().unwrap_or(false),
+                None => return Err(io::Error::other("there's no running writer to flush")),
+            },
+        };
+        if !synced {
+            return Err(io::Error::other("not everything could be written and synced"));
+        }
+        match self.dropped() {
+            0 => Ok(()),
+            n => Err(io::Error::other(format!("{} reports were dropped", n))),
+        }
+    }
+
+    /// Like `flush`, but gives up after `timeout`. Returns whether the
+    /// flush finished.
+    pub(crate) fn flush_timeout(&self, timeout: Duration) -> bool {
+        if let Some(writer) = self.shared.inline.as_ref() {
+            // The panic may have happened mid-write, with the lock held.
+            return match writer.try_lock() {
+                Ok(mut writer) => {
+                    writer.sync(&self.shared);
+                    true
+                }
+                Err(_) => false,
+            };
+        }
+        match self.request_flush() {
+            Some(rx) => rx.recv_timeout(timeout).is_ok(),
+            None => true,
+        }
+    }
+
+    fn request_flush(&self) -> Option<Receiver<bool>> {
+        let mut flushes = self.shared.flushes.as_ref()?.lock().unwrap_or_else(PoisonError::into_inner);
+        if flushes.done {
+            return None;
+        }
+        let (tx, rx) = sync_channel(1);
+        flushes.pending.push(tx);
+        Some(rx)
+    }
+
+    fn install_panic_hook(&self) {
+        let handle = self.clone();
+        let prev = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if handle.shared.ring.is_some() {
+                let millis = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis())
+                    .unwrap_or_default();
+                let path = format!("report-panic-{}.bin", millis);
+                if let Err(e) = File::create(&path).and_then(|f| handle.dump_to(f)) {
+                    eprintln!("tracing-report: failed to write {}: {}", path, e);
+                }
+            } else {
+                handle.flush_timeout(PANIC_FLUSH_TIMEOUT);
+            }
+            prev(info);
+        }));
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn os_thread_id() -> Option<u64> {
+    // SAFETY: gettid has no preconditions.
+    u64::try_from(unsafe { libc::gettid() }).ok()
+}
+
+#[cfg(target_os = "macos")]
+fn os_thread_id() -> Option<u64> {
+    let mut tid = 0;
+    // SAFETY: a null thread means the calling thread, and `tid` is valid
+    // for writes.
+    match unsafe { libc::pthread_threadid_np(0, &mut tid) } {
+        0 => Some(tid),
+        _ => None,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn os_thread_id() -> Option<u64> {
+    None
+}
+
+/// CPU time used by the calling thread so far.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn thread_cpu_nanos() -> Option<u64> {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: `ts` is valid for writes.
+    match unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) } {
+        0 => Some(u64::try_from(ts.tv_sec).ok()? * 1_000_000_000 + u64::try_from(ts.tv_nsec).ok()?),
+        _ => None,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn thread_cpu_nanos() -> Option<u64> {
+    None
+}
+
+/// How many threads with each name have asked for a hashed id so far.
+static THREAD_NAMES: Lazy<Mutex<HashMap<String, u64>>> = Lazy::new(Default::default);
+
+/// FNV-1a of the thread name, followed by how many threads had the same
+/// name before this one, with the top bit set so it can't collide with a
+/// sequential id.
+fn hashed_thread_id() -> Option<u64> {
+    let name = std::thread::current().name()?.to_string();
+    let nth = {
+        let mut names = THREAD_NAMES.lock().unwrap_or_else(PoisonError::into_inner);
+        let n = names.entry(name.clone()).or_default();
+        *n += 1;
+        *n - 1
+    };
+    let hash = name
+        .bytes()
+        .chain(nth.to_le_bytes())
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3));
+    Some(hash | 1 << 63)
+}
+
+const DEFAULT_BATCH_SIZE: usize = 64;
+
+/// How long a panic hook waits for the writer thread, which may be stuck
+/// retrying a failed file or socket.
+const PANIC_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+pub struct ReportLayerBuilder {
+    target: Option<Target>,
+    sinks: Vec<Target>,
+    zstd: Option<i32>,
+    on_error: Option<ErrorCallback>,
+    ring_buffer: Option<usize>,
+    drop_policy: DropPolicy,
+    clock: Option<Arc<dyn Clock>>,
+    rate_limit: Option<(u32, Duration)>,
+    recorded: Recorded,
+    filter: Option<Filter>,
+    capture_everything: bool,
+    thread_ids: ThreadIds,
+    only_thread: Option<ThreadPredicate>,
+    max_value_len: Option<usize>,
+    max_reports: Option<u64>,
+    batch_size: Option<usize>,
+    batch_timeout: Duration,
+    buffering: Option<Buffering>,
+    queue_capacity_bytes: Option<usize>,
+    heartbeat: Option<Duration>,
+    synchronous: bool,
+    labels: BTreeMap<String, String>,
+    framing: Framing,
+    encoding: Encoding,
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    mmap_overflow: MmapOverflow,
+    #[cfg(feature = "tokio")]
+    async_sink: Option<AsyncSink>,
+}
+
+impl ReportLayerBuilder {
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.target = Some(Target::File(path.into()));
+        self
+    }
+
+    /// Append to a file that other processes, e.g. short-lived children, may
+    /// be appending to at the same time, instead of owning it. It's opened
+    /// with `O_APPEND`, and each report is written with any callsite it
+    /// refers to inline, in a single `write` call of its own, so that frames from different
+    /// processes interleave whole and decode on their own. Whichever process
+    /// creates the file writes the header; the others' `label`s and sources
+    /// aren't recorded.
+    ///
+    /// That a `write` is appended whole is up to the filesystem: local ones
+    /// on Linux and macOS do so for regular files, but NFS doesn't, and
+    /// writes larger than `PIPE_BUF` (4 KiB on Linux) aren't guaranteed to
+    /// be atomic everywhere, so keep reports small, e.g. with
+    /// `max_value_len`. Use `os_thread_ids` so threads of different
+    /// processes get different ids, and a `clock` with a common starting
+    /// point, since ticks otherwise count from when each layer was built.
+    /// `zstd` and `framing` don't apply.
+    pub fn shared_append(mut self, path: impl Into<PathBuf>) -> Self {
+        self.target = Some(Target::Append(path.into()));
+        self
+    }
+
+    /// Stream frames to a collector listening on a Unix domain socket,
+    /// instead of writing a file. If the connection drops, the writer
+    /// reconnects with a backoff and starts a new stream, with a new header.
+    #[cfg(unix)]
+    pub fn connect_unix(mut self, path: impl Into<PathBuf>) -> Self {
+        self.target = Some(Target::Unix(path.into()));
+        self
+    }
+
+    /// Send each frame to `addr` as its own UDP datagram, instead of writing
+    /// a file, e.g. to a local aggregator. The header goes first, in a
+    /// datagram of its own. There's no backpressure and no retransmission,
+    /// so a collector may miss reports, or the callsites they refer to.
+    /// Frames too large for one datagram are dropped, and reported to
+    /// `on_error`. `zstd` doesn't apply.
+    pub fn connect_udp(mut self, addr: impl Into<SocketAddr>) -> Self {
+        self.target = Some(Target::Udp(addr.into()));
+        self
+    }
+
+    /// Write the stream into a memory-mapped file of `size` bytes (at least
+    /// 64 KiB), instead of with `write` calls, and leave it to the kernel to
+    /// write the pages back. With `synchronous`, reports are then written
+    /// with no syscalls at all. What happens when it fills up is set with
+    /// `mmap_overflow`. `zstd` doesn't apply.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub fn mmap(mut self, path: impl Into<PathBuf>, size: usize) -> Self {
+        self.target = Some(Target::Mmap(path.into(), size, MmapOverflow::default()));
+        self
+    }
+
+    /// Whether a full `mmap` file grows, the default, or wraps around.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub fn mmap_overflow(mut self, overflow: MmapOverflow) -> Self {
+        self.mmap_overflow = overflow;
+        self
+    }
+
+    /// How frames are delimited in the file, socket or `sink`s. COBS, the
+    /// default, can be read by any version; length prefixes let a reader
+    /// skip a frame without scanning it, but need a reader of format version
+    /// 11 or later. UDP, `mmap` and ring buffers always use COBS.
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// How frames are serialized in the file, socket or `sink`s. postcard,
+    /// the default, is what the format description documents and other
+    /// tools expect; with the `bincode` feature, frames can be bincode
+    /// instead, which needs a reader of format version 12 or later, built
+    /// with that feature too. Shared files and ring buffers always use
+    /// postcard.
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Also write the stream to `w`, in addition to the file or socket. Can
+    /// be called more than once. If only `sink`s are given, no file is
+    /// written. Each sink gets its own copy of the stream, and failures are
+    /// handled separately, but a sink can't be reopened: after its first
+    /// error it's left out.
+    pub fn sink(mut self, w: impl Write + Send + 'static) -> Self {
+        self.sinks.push(Target::Writer(Some(Box::new(w))));
+        self
+    }
+
+    /// Write the stream to `w` from a task spawned on the current tokio
+    /// runtime, instead of from a writer thread, e.g. to a
+    /// `tokio::fs::File` or `tokio::net::TcpStream`. The file, socket and
+    /// other sinks, `zstd`, `framing`, `encoding` and `drop_policy` are ignored: a report is
+    /// dropped whenever the task falls behind, so the layer never blocks.
+    /// After the first error, the task gives up and every later report is
+    /// dropped.
+    ///
+    /// `build` must then be called from within a runtime with the time
+    /// driver enabled.
+    #[cfg(feature = "tokio")]
+    pub fn async_sink(mut self, w: impl tokio::io::AsyncWrite + Send + Unpin + 'static) -> Self {
+        self.async_sink = Some(Box::new(w));
+        self
+    }
+
+    /// Compress the whole stream with zstd at the given level.
+    #[cfg(feature = "zstd")]
+    pub fn zstd(mut self, level: i32) -> Self {
+        self.zstd = Some(level);
+        self
+    }
+
+    /// Called from the writer thread for every I/O error. The writer keeps
+    /// running either way: it reopens the file (or reconnects) with a
+    /// backoff, and counts the reports lost in the meantime as dropped.
+    pub fn on_error(mut self, f: impl Fn(&io::Error) + Send + 'static) -> Self {
+        self.on_error = Some(Box::new(f));
+        self
+    }
+
+    /// Most frames the writer thread writes at once. It takes whatever is
+    /// queued, up to this many, and writes them with a single call. Defaults
+    /// to 64.
+    pub fn batch_size(mut self, frames: usize) -> Self {
+        self.batch_size = Some(frames.max(1));
+        self
+    }
+
+    /// How long the writer thread waits for more frames before writing a
+    /// batch that isn't full. Defaults to zero: only frames already queued
+    /// are batched.
+    pub fn batch_timeout(mut self, timeout: Duration) -> Self {
+        self.batch_timeout = timeout;
+        self
+    }
+
+    /// How the file, socket or `sink`s are buffered. By default, the writer
+    /// writes each batch as it takes it, and syncs every 250ms; see
+    /// `Buffering` for the alternatives. `Buffering::None` and
+    /// `Buffering::Line` ignore `batch_timeout`.
+    pub fn buffering(mut self, buffering: Buffering) -> Self {
+        self.buffering = Some(buffering);
+        self
+    }
+
+    /// Have the writer thread write a `Heartbeat` whenever no reports have
+    /// come in for `interval`, so that a reader can tell a quiet process
+    /// from one whose writer had stopped. Heartbeats have a `thread_id` of
+    /// 0. Without a writer thread, e.g. with `synchronous`, there are none.
+    pub fn heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = Some(interval);
+        self
+    }
+
+    /// Tag the capture with `key = value`, e.g. a build version, git SHA or
+    /// environment, written once in the stream header, so an archived trace
+    /// says what produced it. Read back with `Elements::labels`.
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Write each report from the thread that produced it, under a mutex,
+    /// instead of spawning a writer thread, for targets where threads are
+    /// unavailable or unwanted. Every report then costs the instrumented
+    /// thread a write to the file or socket, and threads contend for the
+    /// lock, so this is much slower than the default wherever there's more
+    /// than a trickle of reports. Outputs are still synced every 250ms, on
+    /// `ReportHandle::flush`, and when the layer is dropped. `drop_policy`
+    /// and batching don't apply.
+    pub fn synchronous(mut self) -> Self {
+        self.synchronous = true;
+        self
+    }
+
+    /// Bound the queue to the writer thread by the total size of the frames
+    /// in it, instead of by count (128 frames), so a burst of large reports
+    /// can't use up memory. A frame larger than `bytes` on its own is still
+    /// queued once the queue is empty. What happens when it's full is up to
+    /// `drop_policy`.
+    pub fn queue_capacity_bytes(mut self, bytes: usize) -> Self {
+        self.queue_capacity_bytes = Some(bytes);
+        self
+    }
+
+    /// What to do when the writer thread falls behind. Defaults to
+    /// `DropPolicy::Block`. Dropped reports are counted in
+    /// `ReportHandle::dropped`.
+    pub fn drop_policy(mut self, policy: DropPolicy) -> Self {
+        self.drop_policy = policy;
+        self
+    }
+
+    /// Where report ticks come from. Defaults to an `InstantClock` started
+    /// when the layer is built.
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Let each span or event callsite produce at most `per_callsite`
+    /// reports per `window`, and drop the rest, counting them in
+    /// `ReportHandle::dropped`. When a span is dropped, so is everything
+    /// recorded about it.
+    pub fn rate_limit(mut self, per_callsite: u32, window: Duration) -> Self {
+        self.rate_limit = Some((per_callsite, window));
+        self
+    }
+
+    /// Only record spans and events the filter enables. Without this, the
+    /// filter is read from the `TRACING_REPORT_FILTER` environment variable,
+    /// or else from `RUST_LOG`, and everything the rest of the subscriber
+    /// lets through is recorded only if neither is set.
+    ///
+    /// This is checked in `Layer::enabled`, so like any layer's filter it
+    /// applies to the whole subscriber: what it leaves out, other layers
+    /// don't see either.
+    ///
+    /// Filters of other layers in the same subscriber apply to this one as
+    /// well, unless they're per-layer filters, added with `with_filter`,
+    /// which a layer has no way of consulting. Falling back to `RUST_LOG`
+    /// keeps the report to about what an `EnvFilter::from_default_env()`
+    /// on such a filter admits, though only target and level directives
+    /// are understood.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Don't fall back to `RUST_LOG` when no filter is given, and record
+    /// whatever the rest of the subscriber lets through, even if another
+    /// layer's per-layer filter leaves it out. `TRACING_REPORT_FILTER`
+    /// still applies.
+    pub fn capture_everything(mut self) -> Self {
+        self.capture_everything = true;
+        self
+    }
+
+    fn take_filter(&mut self) -> Option<Filter> {
+        self.filter.take().or_else(|| {
+            let env = std::env::var("TRACING_REPORT_FILTER")
+                .or_else(|e| match self.capture_everything {
+                    true => Err(e),
+                    false => std::env::var("RUST_LOG"),
+                })
+                .ok()?;
+            Some(Filter::parse_lenient(&env))
+        })
+    }
+
+    /// Record only span lifecycles, for profiling: events are ignored
+    /// entirely.
+    pub fn spans_only(self) -> Self {
+        self.record_events(false)
+    }
+
+    /// Whether to record events. Same as `spans_only` when `false`.
+    pub fn record_events(mut self, enabled: bool) -> Self {
+        self.recorded.events = enabled;
+        self
+    }
+
+    /// Whether to record values set with `Span::record`. Values given when
+    /// a span is created are always recorded.
+    pub fn record_values(mut self, enabled: bool) -> Self {
+        self.recorded.values = enabled;
+        self
+    }
+
+    /// Collect each span's values, initial and recorded, and write them all
+    /// in one `OnRecord` right before its `OnClose`, rather than one report
+    /// per `record` call, for spans that record a lot of values. Readers
+    /// need no changes, but a span's values are then unknown until it
+    /// closes, e.g. to `--follow`, and lost if it never does.
+    pub fn record_values_on_close(mut self) -> Self {
+        self.recorded.values_on_close = true;
+        self
+    }
+
+    /// After each exit from a span, also record the CPU time the thread
+    /// spent inside it since the enter, from the thread's CPU clock, to
+    /// tell spans that were slow because they were computing from those
+    /// that were blocked. Only on Linux and macOS.
+    pub fn record_cpu_time(mut self) -> Self {
+        self.recorded.cpu_time = true;
+        self
+    }
+
+    /// Whether to record `follows_from` links between spans.
+    pub fn record_follows_from(mut self, enabled: bool) -> Self {
+        self.recorded.follows_from = enabled;
+        self
+    }
+
+    /// Whether to record span id changes. Without them, the reader loses
+    /// track of spans whose id changed, which few subscribers ever do.
+    pub fn record_id_changes(mut self, enabled: bool) -> Self {
+        self.recorded.id_changes = enabled;
+        self
+    }
+
+    /// Only record reports from threads whose name matches `predicate`.
+    /// Unnamed threads are matched as `""`. Reports from other threads are
+    /// never encoded, unlike filtering the file afterwards.
+    pub fn only_thread(mut self, predicate: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        self.only_thread = Some(Box::new(predicate));
+        self
+    }
+
+    /// Cut string and `Debug` field values down to `bytes`, followed by
+    /// `…`, so one huge value can't bloat the trace. `Debug` values are
+    /// formatted only up to the limit.
+    pub fn max_value_len(mut self, bytes: usize) -> Self {
+        self.max_value_len = Some(bytes);
+        self
+    }
+
+    /// Stop after the first `n` reports: later ones are ignored, and the
+    /// writer thread writes out what it has and exits, so a long session
+    /// can't grow the file past that.
+    pub fn max_reports(mut self, n: u64) -> Self {
+        self.max_reports = Some(n);
+        self
+    }
+
+    /// Use the OS thread id (`gettid` on Linux) as each report's
+    /// `thread_id`, so it lines up with perf, gdb and the like, instead of
+    /// the default sequential ids starting at 1. On platforms where there's
+    /// no such id, the sequential ids are used anyway.
+    pub fn os_thread_ids(mut self) -> Self {
+        self.thread_ids = ThreadIds::Os;
+        self
+    }
+
+    /// Derive each named thread's `thread_id` from a hash of its name, so
+    /// e.g. `tokio-runtime-worker` threads get the same ids in every run,
+    /// and captures can be compared. Threads sharing a name are numbered
+    /// in the order they first report. Unnamed threads get the default
+    /// sequential ids.
+    pub fn hashed_thread_ids(mut self) -> Self {
+        self.thread_ids = ThreadIds::NameHash;
+        self
+    }
+
+    /// Flight recorder mode: instead of writing a file, keep only the most
+    /// recent `capacity_bytes` of frames in memory, and write them out on
+    /// demand with `ReportHandle::dump_to`. The output target and `zstd` are
+    /// ignored.
+    pub fn ring_buffer(mut self, capacity_bytes: usize) -> Self {
+        self.ring_buffer = Some(capacity_bytes);
+        self
+    }
+
+    /// Builds a layer that keeps every report in memory instead of writing
+    /// it anywhere, for asserting on in tests. The output target, `zstd`,
+    /// `ring_buffer` and `drop_policy` are ignored.
+    pub fn build_capturing(mut self) -> (ReportLayer, CaptureHandle) {
+        let filter = self.take_filter();
+        let capture = Arc::new(Mutex::new(Capture::default()));
+        let layer = ReportLayer {
+            sink: Sink::Capture(capture.clone()),
+            shared: Arc::new(Shared::default()),
+            clock: self.clock.unwrap_or_else(|| Arc::new(InstantClock::new())),
+            rate_limit: self.rate_limit.map(|(n, window)| RateLimit::new(n, window)),
+            recorded: self.recorded,
+            filter,
+            thread_ids: self.thread_ids,
+            only_thread: self.only_thread,
+            max_value_len: self.max_value_len,
+            max_reports: self.max_reports,
+            reports: AtomicU64::new(0),
+            framing: Framing::Cobs,
+            encoding: Encoding::Postcard,
+        };
+        (layer, CaptureHandle::new(capture))
+    }
+
+    pub fn build(mut self) -> ReportLayer {
+        let filter = self.take_filter();
+        let clock = self.clock.unwrap_or_else(|| Arc::new(InstantClock::new()));
+        let rate_limit = self.rate_limit.map(|(n, window)| RateLimit::new(n, window));
+
+        if let Some(capacity) = self.ring_buffer {
+            let shared = Arc::new(Shared {
+                ring: Some(Mutex::new(RingBuffer::new(capacity))),
+                labels: self.labels,
+                start_wall_ns: clock::start_wall_ns(&*clock),
+                ..Shared::default()
+            });
+            return ReportLayer {
+                sink: Sink::Ring,
+                shared,
+                clock,
+                rate_limit,
+                recorded: self.recorded,
+                filter,
+                thread_ids: self.thread_ids,
+                only_thread: self.only_thread,
+                max_value_len: self.max_value_len,
+                max_reports: self.max_reports,
+                reports: AtomicU64::new(0),
+                framing: Framing::Cobs,
+                encoding: Encoding::Postcard,
+            };
+        }
+
+        #[cfg(feature = "tokio")]
+        if let Some(w) = self.async_sink.take() {
+            let shared = Arc::new(Shared {
+                flushes: Some(Mutex::default()),
+                labels: self.labels,
+                start_wall_ns: clock::start_wall_ns(&*clock),
+                ..Shared::default()
+            });
+            return ReportLayer {
+                sink: Sink::Async(spawn_async_writer(w, self.on_error, shared.clone())),
+                shared,
+                clock,
+                rate_limit,
+                recorded: self.recorded,
+                filter,
+                thread_ids: self.thread_ids,
+                only_thread: self.only_thread,
+                max_value_len: self.max_value_len,
+                max_reports: self.max_reports,
+                reports: AtomicU64::new(0),
+                framing: Framing::Cobs,
+                encoding: Encoding::Postcard,
+            };
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        if let Some(Target::Mmap(_, _, overflow)) = self.target.as_mut() {
+            *overflow = self.mmap_overflow;
+        }
+        let default = match self.sinks.is_empty() {
+            true => Some(Target::File(PathBuf::from("report.bin"))),
+            false => None,
+        };
+        let cfg = WriterConfig {
+            targets: self.target.or(default).into_iter().chain(self.sinks).collect(),
+            zstd: self.zstd,
+            framing: self.framing,
+            encoding: self.encoding,
+            on_error: self.on_error,
+            batch_size: self.batch_size.unwrap_or(DEFAULT_BATCH_SIZE),
+            batch_timeout: self.batch_timeout,
+            buffering: self.buffering,
+            queue_capacity_bytes: self.queue_capacity_bytes,
+            heartbeat: self.heartbeat.map(|interval| (interval, clock.clone())),
+        };
+        let (sink, shared) = match self.synchronous {
+            true => {
+                let shared = Arc::new(Shared {
+                    inline: Some(Mutex::new(Writer::new(cfg))),
+                    labels: self.labels,
+                    start_wall_ns: clock::start_wall_ns(&*clock),
+                    ..Shared::default()
+                });
+                (Sink::Inline, shared)
+            }
+            false => {
+                let shared = Arc::new(Shared {
+                    flushes: Some(Mutex::default()),
+                    labels: self.labels,
+                    start_wall_ns: clock::start_wall_ns(&*clock),
+                    ..Shared::default()
+                });
+                let queue = spawn_writer(cfg, shared.clone());
+                (Sink::Writer(queue, self.drop_policy), shared)
+            }
+        };
+        ReportLayer {
+            sink,
+            shared,
+            clock,
+            rate_limit,
+            recorded: self.recorded,
+            filter,
+            thread_ids: self.thread_ids,
+            only_thread: self.only_thread,
+            max_value_len: self.max_value_len,
+            max_reports: self.max_reports,
+            reports: AtomicU64::new(0),
+            framing: self.framing,
+            encoding: self.encoding,
+        }
+    }
+}
+
+impl Default for ReportLayer {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl Drop for ReportLayer {
+    fn drop(&mut self) {
+        match &self.sink {
+            // Lets the writer thread finish up once it has drained the queue.
+            Sink::Writer(queue, _) => queue.close(),
+            Sink::Inline => self.handle().flush(),
+            _ => {}
+        }
+    }
+}
+
+impl ReportLayer {
+    /// `values` for an `OnRecord`, cut down to `max_value_len`. Records with
+    /// `Empty` fields are collected into a map too, as tss can't write them
+    /// as they are.
+    fn serialize_values<'a>(&self, values: &'a Record<'a>) -> tss::SerializeRecord<'a> {
+        match self.max_value_len {
+            Some(max) => tss::SerializeRecord::De(truncated(values, max)),
+            None if has_empty_fields(values) => {
+                tss::SerializeRecord::De(truncated(values, usize::MAX))
+            }
+            None => values.as_serde(),
+        }
+    }
+
+    thread_local! {
+        static LOCAL_METADATA: Lazy<u64> = Lazy::new(|| {
+            THREAD_ID.fetch_add(1, Ordering::Relaxed)
+        });
+        static OS_THREAD_ID: Lazy<Option<u64>> = Lazy::new(os_thread_id);
+        static HASHED_THREAD_ID: Lazy<Option<u64>> = Lazy::new(hashed_thread_id);
+        /// The layers this thread has written its name to. Holding a `Weak`
+        /// keeps a dropped layer's address from being reused by a new one.
+        static NAMED: RefCell<Vec<Weak<Shared>>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub fn builder() -> ReportLayerBuilder {
+        ReportLayerBuilder::default()
+    }
+
+    /// Shorthand for `ReportLayer::builder().build_capturing()`.
+    pub fn capturing() -> (ReportLayer, CaptureHandle) {
+        Self::builder().build_capturing()
+    }
+
+    pub fn handle(&self) -> ReportHandle {
+        ReportHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Chains a panic hook in front of the current one, which saves what
+    /// the layer has so far before the process goes down: a ring buffer is
+    /// dumped to `report-panic-<unix millis>.bin` in the working directory,
+    /// and otherwise the writer thread is given up to a second to write and
+    /// sync everything queued. Call it before the layer is moved into a
+    /// subscriber.
+    pub fn install_panic_hook(&self) {
+        self.handle().install_panic_hook();
+    }
+
+    /// Checks a new span or event against the rate limit, counting it as
+    /// dropped if it's over.
+    fn allow(&self, metadata: &'static tracing::Metadata<'static>) -> bool {
+        let Some(limit) = self.rate_limit.as_ref() else {
+            return true;
+        };
+        let allowed = limit.allow(metadata, self.clock.now_nanos());
+        if !allowed {
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// Whether `id` was dropped by the rate limit, in which case so is
+    /// this report about it.
+    fn suppressed<S>(&self, id: &Id, ctx: &Context<'_, S>) -> bool
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        if self.rate_limit.is_none() {
+            return false;
+        }
+        let suppressed = ctx
+            .span(id)
+            .is_some_and(|span| span.extensions().get::<Suppressed>().is_some());
+        if suppressed {
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        suppressed
+    }
+
+    fn handle_message<'a>(&self, payload: impl Into<WirePayload<'a>>) {
+        if let Some(only_thread) = self.only_thread.as_ref() {
+            if !only_thread(std::thread::current().name().unwrap_or("")) {
+                return;
+            }
+        }
+        if let Some(max) = self.max_reports {
+            let n = self.reports.fetch_add(1, Ordering::Relaxed);
+            if n >= max {
+                // The first report over the limit lets the writer finish up.
+                if n == max {
+                    match &self.sink {
+                        Sink::Writer(queue, _) => queue.close(),
+                        Sink::Inline => self.handle().flush(),
+                        _ => {}
+                    }
+                }
+                return;
+            }
+        }
+        if let Some(name) = self.first_report_of_named_thread() {
+            self.handle_message(ReportPayload::ThreadName { name });
+        }
+        let thread_id = match self.thread_ids {
+            ThreadIds::Sequential => None,
+            ThreadIds::Os => Self::OS_THREAD_ID.with(|id| *id.deref()),
+            ThreadIds::NameHash => Self::HASHED_THREAD_ID.with(|id| *id.deref()),
+        };
+        let thread_id = thread_id.unwrap_or_else(|| Self::LOCAL_METADATA.with(|id| *id.deref()));
+        let ser_msg = report_frame(
+            self.clock.now_nanos(),
+            SEQUENCE.fetch_add(1, Ordering::Relaxed),
+            thread_id,
+            payload.into(),
+            self.framing,
+            self.encoding,
+        );
+        match &self.sink {
+            Sink::Writer(queue, policy) => {
+                let dropped = queue.push(ser_msg, *policy);
+                if dropped > 0 {
+                    self.shared.dropped.fetch_add(dropped as u64, Ordering::Relaxed);
+                }
+            }
+            #[cfg(feature = "tokio")]
+            Sink::Async(tx) => {
+                if tx.try_send(ser_msg).is_err() {
+                    self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Sink::Ring => {
+                if let Some(ring) = self.shared.ring.as_ref() {
+                    ring.lock().unwrap_or_else(PoisonError::into_inner).push(ser_msg);
+                }
+            }
+            Sink::Inline => {
+                if let Some(writer) = self.shared.inline.as_ref() {
+                    writer
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .write_inline(ser_msg, &self.shared);
+                }
+            }
+            Sink::Capture(capture) => {
+                capture
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(ser_msg, &self.shared.callsites);
+            }
+        }
+    }
+
+    /// The current thread's name, if it has one and this is the first time
+    /// it reports to this layer.
+    fn first_report_of_named_thread(&self) -> Option<String> {
+        Self::NAMED.with(|named| {
+            let mut named = named.borrow_mut();
+            if named.iter().any(|layer| ptr::eq(layer.as_ptr(), Arc::as_ptr(&self.shared))) {
+                return None;
+            }
+            named.retain(|layer| layer.strong_count() > 0);
+            named.push(Arc::downgrade(&self.shared));
+            Some(std::thread::current().name()?.to_string())
+        })
+    }
+}
+
+/// Writes a marker named `name`, e.g. "starting load test", into the stream
+/// of the `ReportLayer` in the current default subscriber, to line up what
+/// was being done with what was captured. Readers show it across all
+/// threads. Does nothing if there's no `ReportLayer`.
+pub fn marker(name: &str) {
+    tracing::dispatcher::get_default(|dispatch| {
+        if let Some(layer) = dispatch.downcast_ref::<ReportLayer>() {
+            layer.handle_message(ReportPayload::Marker { name: name.to_string() });
+        }
+    });
+}
+
+/// A span's values so far, kept in its extensions with
+/// `record_values_on_close`.
+struct Values(OwnedFields);
+
+/// Busy/idle bookkeeping, kept in each span's extensions. In clock
+/// nanoseconds.
+struct Timings {
+    busy: u64,
+    idle: u64,
+    last: u64,
+    /// The thread's CPU time at the last enter, with `record_cpu_time`.
+    cpu_entered: Option<u64>,
+}
+
+impl<S> Layer<S> for ReportLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, metadata: &tracing::Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        self.filter.as_ref().is_none_or(|filter| filter.enabled(metadata))
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !self.allow(attrs.metadata()) {
+            if let Some(span) = ctx.span(id) {
+                span.extensions_mut().insert(Suppressed);
+            }
+            return;
+        }
+
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            extensions.insert(Timings {
+                busy: 0,
+                idle: 0,
+                last: self.clock.now_nanos(),
+                cpu_entered: None,
+            });
+            if self.recorded.values_on_close {
+                let max = self.max_value_len.unwrap_or(usize::MAX);
+                extensions.insert(Values(truncated_owned(&Record::new(attrs.values()), max)));
+            }
+        }
+
+        self.handle_message(WirePayload::OnNewSpan {
+            callsite: self.shared.callsites.id(attrs.metadata()),
+            parent: attrs.parent().map(AsSerde::as_serde),
+            is_root: attrs.is_root(),
+            id: id.as_serde(),
+        });
+
+        // The span's initial field values, which `SerializeAttributes`
+        // doesn't carry.
+        if !attrs.values().is_empty() && !self.recorded.values_on_close {
+            let values = Record::new(attrs.values());
+            self.handle_message(ReportPayload::OnRecord {
+                span: id.as_serde(),
+                values: self.serialize_values(&values),
+            });
+        }
+    }
+
+    fn on_record(&self, span: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if !self.recorded.values || self.suppressed(span, &ctx) {
+            return;
+        }
+        if self.recorded.values_on_close {
+            if let Some(span) = ctx.span(span) {
+                if let Some(Values(map)) = span.extensions_mut().get_mut::<Values>() {
+                    map.extend(truncated_owned(values, self.max_value_len.unwrap_or(usize::MAX)));
+                }
+            }
+            return;
+        }
+        self.handle_message(ReportPayload::OnRecord {
+            span: span.as_serde(),
+            values: self.serialize_values(values),
+        })
+    }
+
+    fn on_follows_from(&self, span: &Id, follows: &Id, ctx: Context<'_, S>) {
+        if !self.recorded.follows_from || self.suppressed(span, &ctx) || self.suppressed(follows, &ctx) {
+            return;
+        }
+        self.handle_message(ReportPayload::OnFollowsFrom {
+            span: span.as_serde(),
+            follows: follows.as_serde(),
+        })
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+        if !self.recorded.events || !self.allow(event.metadata()) {
+            return;
+        }
+        // Suppressed spans aren't in the stream, so skip to the nearest
+        // ancestor that is.
+        let parent = ctx.event_span(event).and_then(|span| {
+            span.scope()
+                .find(|span| self.rate_limit.is_none() || span.extensions().get::<Suppressed>().is_none())
+                .map(|span| span.id().as_serde())
+        });
+        self.handle_message(WirePayload::OnEvent {
+            callsite: self.shared.callsites.id(event.metadata()),
+            fields: match self.max_value_len {
+                Some(max) => tss::SerializeRecordFields::De(truncated(event, max)),
+                None => tss::SerializeRecordFields::Ser(event),
+            },
+            parent,
+        })
+    }
+
+    fn on_enter(&self, span: &Id, ctx: Context<'_, S>) {
+        if self.suppressed(span, &ctx) {
+            return;
+        }
+        if let Some(span) = ctx.span(span) {
+            if let Some(timings) = span.extensions_mut().get_mut::<Timings>() {
+                let now = self.clock.now_nanos();
+                timings.idle += now.saturating_sub(timings.last);
+                timings.last = now;
+                if self.recorded.cpu_time {
+                    timings.cpu_entered = thread_cpu_nanos();
+                }
+            }
+        }
+
+        self.handle_message(ReportPayload::OnEnter {
+            span: span.as_serde(),
+        })
+    }
+
+    fn on_exit(&self, span: &Id, ctx: Context<'_, S>) {
+        if self.suppressed(span, &ctx) {
+            return;
+        }
+        let mut cpu_ns = None;
+        if let Some(span) = ctx.span(span) {
+            if let Some(timings) = span.extensions_mut().get_mut::<Timings>() {
+                let now = self.clock.now_nanos();
+                timings.busy += now.saturating_sub(timings.last);
+                timings.last = now;
+                cpu_ns = timings
+                    .cpu_entered
+                    .take()
+                    .and_then(|entered| thread_cpu_nanos().map(|now| now.saturating_sub(entered)));
+            }
+        }
+
+        self.handle_message(ReportPayload::OnExit {
+            span: span.as_serde(),
+        });
+        if let Some(cpu_ns) = cpu_ns {
+            self.handle_message(ReportPayload::CpuTime {
+                span: span.as_serde(),
+                cpu_ns,
+            });
+        }
+    }
+
+    fn on_id_change(&self, old: &Id, new: &Id, _ctx: Context<'_, S>) {
+        if !self.recorded.id_changes {
+            return;
+        }
+        self.handle_message(ReportPayload::OnIdChange {
+            old: old.as_serde(),
+            new: new.as_serde(),
+        })
+    }
+
+    fn on_close(&self, span: Id, ctx: Context<'_, S>) {
+        if self.suppressed(&span, &ctx) {
+            return;
+        }
+        let now = self.clock.now_nanos();
+        let (busy, idle) = ctx
+            .span(&span)
+            .and_then(|s| {
+                s.extensions()
+                    .get::<Timings>()
+                    .map(|t| (t.busy, t.idle + now.saturating_sub(t.last)))
+            })
+            .unwrap_or_default();
+
+        let values = ctx.span(&span).and_then(|s| s.extensions_mut().remove::<Values>());
+        if let Some(Values(map)) = values.filter(|Values(map)| !map.is_empty()) {
+            self.handle_message(ReportPayload::OnRecord {
+                span: span.as_serde(),
+                values: tss::SerializeRecord::De(to_record_map(map)),
+            });
+        }
+
+        self.handle_message(ReportPayload::OnClose {
+            span: span.as_serde(),
+            busy_ns: busy,
+            idle_ns: idle,
+        })
+    }
+}
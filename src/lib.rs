@@ -1,90 +1,119 @@
-use once_cell::sync::Lazy;
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::string::String;
+use core::fmt;
 use serde::{Deserialize, Serialize};
-use std::ops::Deref;
-use std::{
-    fs::File,
-    io::Write,
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        mpsc::{sync_channel, RecvTimeoutError, SyncSender},
-    },
-    thread::spawn,
-    thread_local,
-    time::{Duration, Instant},
-};
-use tracing::{
-    span::{Attributes, Id, Record},
-    Subscriber,
-};
-use tracing_subscriber::layer::{Context, Layer};
-use tss::AsSerde;
-
-static START: Lazy<Instant> = Lazy::new(Instant::now);
-static THREAD_ID: AtomicU64 = AtomicU64::new(1);
-static PRODUCER: Lazy<SyncSender<Vec<u8>>> = Lazy::new(|| {
-    let (tx, rx) = sync_channel::<Vec<u8>>(128);
-    spawn(move || {
-        let mut f = File::create("report.bin").unwrap();
-        f.sync_all().unwrap();
-        let mut last_flush = Instant::now();
-
-        loop {
-            if last_flush.elapsed() > Duration::from_millis(250) {
-                f.sync_all().unwrap();
-                last_flush = Instant::now();
-            }
-
-            match rx.recv_timeout(Duration::from_millis(25)) {
-                Ok(msg) => f.write_all(&msg).unwrap(),
-                Err(RecvTimeoutError::Timeout) => {}
-                Err(RecvTimeoutError::Disconnected) => {
-                    f.sync_all().unwrap();
-                    return;
-                }
-            }
-        }
-    });
-    tx
-});
+#[cfg(feature = "std")]
+use std::time::{Duration, SystemTime};
 
-pub struct ReportLayer;
+#[cfg(feature = "std")]
+use callsite::Frame;
 
-impl ReportLayer {
-    thread_local! {
-        static LOCAL_METADATA: Lazy<u64> = Lazy::new(|| {
-            THREAD_ID.fetch_add(1, Ordering::Relaxed)
-        });
-    }
+#[cfg(feature = "std")]
+mod analysis;
+#[cfg(feature = "tokio")]
+mod async_writer;
+mod callsite;
+#[cfg(feature = "std")]
+mod capture;
+mod clock;
+#[cfg(feature = "std")]
+mod compat;
+#[cfg(feature = "std")]
+mod cursor;
+mod encode;
+#[cfg(feature = "std")]
+mod error;
+#[cfg(feature = "std")]
+mod filter;
+pub mod format;
+#[cfg(feature = "std")]
+mod iter;
+#[cfg(feature = "std")]
+mod layer;
+#[cfg(feature = "std")]
+mod limit;
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "macos")))]
+mod mmap;
+#[cfg(feature = "std")]
+mod owned;
+#[cfg(feature = "std")]
+mod queue;
+#[cfg(feature = "std")]
+mod reader;
+#[cfg(feature = "std")]
+mod registry;
+#[cfg(feature = "std")]
+mod ring;
+#[cfg(feature = "std")]
+mod truncate;
+#[cfg(feature = "std")]
+mod value;
+#[cfg(feature = "std")]
+mod writer;
 
-    fn handle_message(&self, payload: ReportPayload<'_>) {
-        let thread_id = Self::LOCAL_METADATA.with(|id| *id.deref());
-        let msg = Report {
-            tick: START.elapsed().as_nanos(),
-            thread_id,
-            payload,
-        };
-        let ser_msg = postcard::to_stdvec_cobs(&msg).unwrap();
-        let _ = PRODUCER.send(ser_msg);
-    }
-}
+#[cfg(feature = "std")]
+pub use analysis::{
+    CategoryBy, Diagnostics, DiffReport, EdgeKind, Element, Elements, ExportConfig, Gap, LevelCounts, Overview, Percentiles, Span, SpanDiff, SpanEdge, SpanKey, SpanLatency, ThreadKey, ThreadSummary, ThreadValidation, TimingMode, TlSpans, DEFAULT_MAX_DEPTH, MAX_PLAUSIBLE_DURATION,
+};
+#[cfg(feature = "std")]
+pub use capture::CaptureHandle;
+pub use clock::Clock;
+#[cfg(feature = "std")]
+pub use clock::InstantClock;
+#[cfg(feature = "std")]
+pub use cursor::{Boundary, SpanCursor};
+pub use encode::{FrameEncoder, FrameSink};
+#[cfg(feature = "std")]
+pub use error::ReportError;
+#[cfg(feature = "std")]
+pub use filter::{Filter, ParseFilterError};
+#[cfg(feature = "std")]
+pub use iter::ReportIterExt;
+#[cfg(feature = "std")]
+pub use layer::{marker, ReportHandle, ReportLayer, ReportLayerBuilder};
+#[cfg(feature = "std")]
+pub(crate) use layer::{Shared, SEQUENCE};
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "macos")))]
+pub use mmap::MmapOverflow;
+#[cfg(feature = "std")]
+pub use queue::DropPolicy;
+#[cfg(feature = "std")]
+pub use reader::{Position, ReportReader};
+#[cfg(feature = "std")]
+pub use value::{DisplayValue, EventExt, ValueExt};
+#[cfg(feature = "std")]
+pub use writer::Buffering;
 
 use tracing_serde_structured as tss;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Report<'a> {
-    pub tick: u128,
+    /// Nanoseconds since the layer's clock started.
+    pub tick: u64,
+    /// Stamped in the order reports were produced, across all threads, to
+    /// break ties between reports with the same `tick`.
+    pub seq: u64,
     pub thread_id: u64,
     #[serde(borrow)]
     pub payload: ReportPayload<'a>,
 }
 
+// The order of these variants is part of the on-disk format, see
+// `format::describe`. Without `std`, tss keeps its fields and values in
+// fixed-capacity vectors, inline, so some variants are much larger.
 #[derive(Debug, Deserialize, Serialize)]
+#[cfg_attr(not(feature = "std"), allow(clippy::large_enum_variant))]
 pub enum ReportPayload<'a> {
     OnNewSpan {
         #[serde(borrow)]
         attrs: tss::SerializeAttributes<'a>,
         id: tss::SerializeId,
     },
+    /// Also written right after `OnNewSpan`, with the span's initial
+    /// values, since format version 9.
     OnRecord {
         span: tss::SerializeId,
         values: tss::SerializeRecord<'a>,
@@ -93,6 +122,9 @@ pub enum ReportPayload<'a> {
         span: tss::SerializeId,
         follows: tss::SerializeId,
     },
+    /// `event.parent` is the span the event was recorded in: its explicit
+    /// parent, or else the current span. Before format version 10, only
+    /// explicit parents were recorded.
     OnEvent {
         event: tss::SerializeEvent<'a>,
     },
@@ -106,15 +138,71 @@ pub enum ReportPayload<'a> {
         old: tss::SerializeId,
         new: tss::SerializeId,
     },
+    /// `busy_ns` is the time spent inside the span, between enters and
+    /// exits, and `idle_ns` the rest of its lifetime. Both are zero in
+    /// streams older than format version 3.
     OnClose {
         span: tss::SerializeId,
+        busy_ns: u64,
+        idle_ns: u64,
+    },
+    /// Written by `marker`, since format version 13.
+    Marker {
+        name: String,
+    },
+    /// The name of the thread that wrote it, before the thread's first
+    /// other report. Unnamed threads have none. Since format version 14.
+    ThreadName {
+        name: String,
+    },
+    /// Written by the writer thread with `heartbeat`, when nothing else had
+    /// been for `interval_ns`. Since format version 15.
+    Heartbeat {
+        interval_ns: u64,
+    },
+    /// The CPU time the thread spent inside `span` between the `OnExit`
+    /// right before this and the enter it pairs with, written with
+    /// `record_cpu_time`. Since format version 16.
+    CpuTime {
+        span: tss::SerializeId,
+        cpu_ns: u64,
     },
 }
 
 impl<'a> Report<'a> {
+    #[cfg(feature = "std")]
+    /// When the report was made, given its stream's `Source::start_wall`:
+    /// ticks count nanoseconds from then.
+    pub fn wall_time(&self, stream_start: SystemTime) -> SystemTime {
+        stream_start + Duration::from_nanos(self.tick)
+    }
+
+    #[cfg(feature = "std")]
+    /// Encodes the report as one self-contained stream frame, including the
+    /// trailing 0 byte. Frames written by the layer itself refer to callsite
+    /// metadata written earlier in the stream instead.
+    pub fn to_cobs_frame(&self) -> Vec<u8> {
+        postcard::to_stdvec_cobs(&Frame::Full(self.to_owned())).unwrap()
+    }
+
+    #[cfg(feature = "std")]
+    /// Decodes one frame, in place, with or without its trailing 0 byte.
+    /// Only frames from `to_cobs_frame` decode on their own; use
+    /// `ReportReader` for whole streams written by the layer.
+    pub fn from_cobs_frame(frame: &mut [u8]) -> Result<Report<'static>, ReportError> {
+        let invalid = |e: &str| ReportError::Decode(e.to_string());
+        match postcard::from_bytes_cobs::<Frame>(frame)? {
+            Frame::Full(rpt) => Ok(rpt.to_owned()),
+            Frame::Report(_) => Err(invalid("frame refers to callsite metadata elsewhere in its stream")),
+            Frame::Callsite { .. } => Err(invalid("frame is callsite metadata, not a report")),
+        }
+    }
+
+    #[cfg(feature = "std")]
     pub fn to_owned(&self) -> Report<'static> {
         Report {
             tick: self.tick,
+            seq: self.seq,
             thread_id: self.thread_id,
             payload: self.payload.to_owned(),
         }
@@ -122,6 +210,7 @@ impl<'a> Report<'a> {
 }
 
 impl<'a> ReportPayload<'a> {
+    #[cfg(feature = "std")]
     pub fn to_owned(&self) -> ReportPayload<'static> {
         match self {
             ReportPayload::OnNewSpan { attrs, id } => ReportPayload::OnNewSpan { attrs: attrs.to_owned(), id: id.to_owned() },
@@ -131,69 +220,46 @@ impl<'a> ReportPayload<'a> {
             ReportPayload::OnEnter { span } => ReportPayload::OnEnter { span: span.to_owned() },
             ReportPayload::OnExit { span } => ReportPayload::OnExit { span: span.to_owned() },
             ReportPayload::OnIdChange { old, new } => ReportPayload::OnIdChange { old: old.to_owned(), new: new.to_owned() },
-            ReportPayload::OnClose { span } => ReportPayload::OnClose { span: span.to_owned() },
+            ReportPayload::OnClose { span, busy_ns, idle_ns } => ReportPayload::OnClose { span: span.to_owned(), busy_ns: *busy_ns, idle_ns: *idle_ns },
+            ReportPayload::Marker { name } => ReportPayload::Marker { name: name.clone() },
+            ReportPayload::ThreadName { name } => ReportPayload::ThreadName { name: name.clone() },
+            ReportPayload::Heartbeat { interval_ns } => ReportPayload::Heartbeat { interval_ns: *interval_ns },
+            ReportPayload::CpuTime { span, cpu_ns } => ReportPayload::CpuTime { span: span.to_owned(), cpu_ns: *cpu_ns },
         }
     }
-}
-
-impl<S> Layer<S> for ReportLayer
-where
-    S: Subscriber,
-{
-    fn enabled(&self, _metadata: &tracing::Metadata<'_>, _ctx: Context<'_, S>) -> bool {
-        // always enabled for all levels
-        true
-    }
-
-    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _ctx: Context<'_, S>) {
-        self.handle_message(ReportPayload::OnNewSpan {
-            attrs: attrs.as_serde(),
-            id: id.as_serde(),
-        });
-    }
-
-    fn on_record(&self, span: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
-        self.handle_message(ReportPayload::OnRecord {
-            span: span.as_serde(),
-            values: values.as_serde(),
-        })
-    }
-
-    fn on_follows_from(&self, span: &Id, follows: &Id, _ctx: Context<'_, S>) {
-        self.handle_message(ReportPayload::OnFollowsFrom {
-            span: span.as_serde(),
-            follows: follows.as_serde(),
-        })
-    }
-
-    fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
-        self.handle_message(ReportPayload::OnEvent {
-            event: event.as_serde(),
-        })
-    }
 
-    fn on_enter(&self, span: &Id, _ctx: Context<'_, S>) {
-        self.handle_message(ReportPayload::OnEnter {
-            span: span.as_serde(),
-        })
+    /// Where the span or event was created. `None` for reports about an
+    /// existing span, which only carry its id.
+    pub fn location(&self) -> Option<Location<'_>> {
+        match self {
+            ReportPayload::OnNewSpan { attrs, .. } => Some(Location::of(&attrs.metadata)),
+            ReportPayload::OnEvent { event } => Some(Location::of(&event.metadata)),
+            _ => None,
+        }
     }
+}
 
-    fn on_exit(&self, span: &Id, _ctx: Context<'_, S>) {
-        self.handle_message(ReportPayload::OnExit {
-            span: span.as_serde(),
-        })
-    }
+/// A callsite's place in the source. Displays as `file:line`, with `???`
+/// and `0` for whatever is missing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Location<'a> {
+    pub file: Option<&'a str>,
+    pub line: Option<u32>,
+    pub module: Option<&'a str>,
+}
 
-    fn on_id_change(&self, old: &Id, new: &Id, _ctx: Context<'_, S>) {
-        self.handle_message(ReportPayload::OnIdChange {
-            old: old.as_serde(),
-            new: new.as_serde(),
-        })
+impl<'a> Location<'a> {
+    pub fn of(metadata: &'a tss::SerializeMetadata<'_>) -> Self {
+        Self {
+            file: metadata.file.as_deref(),
+            line: metadata.line,
+            module: metadata.module_path.as_deref(),
+        }
     }
+}
 
-    fn on_close(&self, span: Id, _ctx: Context<'_, S>) {
-        self.handle_message(ReportPayload::OnClose {
-            span: span.as_serde(),
-        })
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file.unwrap_or("???"), self.line.unwrap_or(0))
     }
 }
@@ -0,0 +1,50 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use tracing::{callsite::Identifier, Metadata};
+
+/// Caps how many spans or events each callsite may produce per window.
+pub(crate) struct RateLimit {
+    per_callsite: u32,
+    window_ns: u64,
+    windows: Mutex<HashMap<Identifier, Window>>,
+}
+
+struct Window {
+    start: u64,
+    count: u32,
+}
+
+impl RateLimit {
+    pub(crate) fn new(per_callsite: u32, window: Duration) -> Self {
+        Self {
+            per_callsite,
+            window_ns: u64::try_from(window.as_nanos()).unwrap_or(u64::MAX),
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts one report from `metadata`'s callsite at `now` (in clock
+    /// nanoseconds), and returns whether it's still within the limit.
+    pub(crate) fn allow(&self, metadata: &'static Metadata<'static>, now: u64) -> bool {
+        let mut windows = self.windows.lock().unwrap_or_else(PoisonError::into_inner);
+        let window = windows.entry(metadata.callsite()).or_insert(Window { start: now, count: 0 });
+        if now.saturating_sub(window.start) >= self.window_ns {
+            window.start = now;
+            window.count = 0;
+        }
+        if window.count < self.per_callsite {
+            window.count += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Marks a span whose callsite was over its limit, so none of its reports
+/// are written.
+pub(crate) struct Suppressed;
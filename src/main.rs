@@ -1,334 +1,1489 @@
-use std::{fs::File, io::Read, collections::{HashSet, HashMap}, ops::Deref, rc::Rc, num::NonZeroU64};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Write},
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    rc::Rc,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
-use tracing_report::{Report, ReportPayload};
+use clap::{Parser, ValueEnum};
+use serde_json::json;
+use tracing_report::{Boundary, CategoryBy, DiffReport, Element, Elements, EventExt, ExportConfig, Location, Percentiles, ReportError, ReportPayload, ReportReader, Span, SpanKey, ThreadKey, ThreadValidation, TimingMode, TlSpans, ValueExt, DEFAULT_MAX_DEPTH};
 use tracing_serde_structured as tss;
-use tss::CowString;
 
-#[derive(Clone)]
-struct Element {
-    rpt: Rc<Report<'static>>,
+#[derive(Parser)]
+struct Args {
+    /// Report file to read, or `-` for stdin. Repeat to merge several captures
+    #[arg(short, long)]
+    input: Vec<PathBuf>,
+
+    /// Only show reports from this thread, by id or by name, e.g.
+    /// `tokio-runtime-worker`, which matches every thread of that name
+    #[arg(short, long)]
+    thread: Option<String>,
+
+    /// Only show spans with this name, and everything nested under them
+    #[arg(long)]
+    span_name: Option<String>,
+
+    /// How many levels of nested spans to print in the tree view
+    #[arg(short, long, default_value_t = 4)]
+    depth: usize,
+
+    #[arg(short, long, value_enum, default_value_t = Format::Tree)]
+    format: Format,
+
+    /// Show a span nested directly in another of the same callsite, e.g. a
+    /// recursive call, as part of it: once in the tree view, annotated with
+    /// how deep it went, and as a single frame in the flame view
+    #[arg(long)]
+    collapse_recursion: bool,
+
+    /// What the chrome and flame views time spans by: `busy` from each enter
+    /// to its exit, or `wall` from creation to close, idle time included
+    #[arg(long, value_enum, default_value_t = Timing::Busy)]
+    timing: Timing,
+
+    /// Weigh the flame view by the CPU time spent in each span, for captures
+    /// with `record_cpu_time`, instead of by how long it took
+    #[arg(long)]
+    cpu: bool,
+
+    /// What the chrome view groups, and Perfetto colors, spans and events
+    /// by: `target`, `location` (`file:line`), or a field, as
+    /// `field:NAME`, falling back to the target where it's missing
+    #[arg(long, default_value = "target", value_parser = parse_category)]
+    category_by: CategoryBy,
+
+    /// Leave spans shorter than this, e.g. `10us`, out of the tree,
+    /// tree-json and flame views. Their events go to the span they were
+    /// nested in, and their time counts as that span's own
+    #[arg(long, value_parser = parse_duration)]
+    min_duration: Option<Duration>,
+
+    /// Bucket size for the histogram view, e.g. `500us` or `10ms`
+    #[arg(long, default_value = "1ms", value_parser = parse_duration)]
+    bucket: Duration,
+
+    /// Count span enters as well as events in the histogram view
+    #[arg(long)]
+    include_spans: bool,
+
+    /// Shortest pause to report in the gaps view, e.g. `500us` or `10ms`
+    #[arg(long, default_value = "1ms", value_parser = parse_duration)]
+    threshold: Duration,
+
+    /// Tick to look at in the active view
+    #[arg(long, required_if_eq("format", "active"))]
+    at: Option<u64>,
+
+    /// Instead of printing anything else, list the spans (named by
+    /// `--span-name`, if given) that took longer than this, and exit with
+    /// status 1 if there are any, e.g. to fail a CI job
+    #[arg(long, value_parser = parse_duration)]
+    max_duration: Option<Duration>,
+
+    /// Instead of printing anything else, compare the input against the
+    /// capture at this path: the change in count, total time and p95 of
+    /// each span callsite, largest regression first
+    #[arg(long)]
+    baseline: Option<PathBuf>,
+
+    /// Label events with their `message` field, followed by the other
+    /// fields: listed under each span in the tree view, as a top-level
+    /// `message` key in NDJSON, and as the first field column in CSV
+    #[arg(long)]
+    messages: bool,
+
+    /// Keep reading the input as it grows, printing each report as it
+    /// arrives, like the interleaved view (or as NDJSON with `--format
+    /// ndjson`), until Ctrl-C
+    #[arg(long)]
+    follow: bool,
+
+    /// Instead of printing anything, serve the chrome view over HTTP at this
+    /// address, e.g. `127.0.0.1:8080`: as JSON at `/trace.json`, and at `/`
+    /// a page that opens it in Perfetto
+    #[arg(long)]
+    serve: Option<SocketAddr>,
+
+    /// Print a JSON description of the binary format and exit
+    #[arg(long)]
+    describe_format: bool,
 }
 
-impl Deref for Element {
-    type Target = Report<'static>;
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// Per-thread span tree
+    Tree,
+    /// Chrome trace event JSON, for chrome://tracing or Perfetto
+    Chrome,
+    /// Folded stacks, for flamegraph.pl or inferno
+    Flame,
+    /// One JSON object per report
+    Ndjson,
+    /// Events grouped by source location
+    Events,
+    /// Per-thread enter/exit/event timeline
+    Timeline,
+    /// Events per time bucket, for all threads and then each thread
+    Histogram,
+    /// Distinct sets of field names emitted from each event callsite
+    Shapes,
+    /// One row per event, with a column for every field name seen
+    Csv,
+    /// Mermaid sequence diagram of span enters, exits and follows-from edges
+    Mermaid,
+    /// Every event inside a span named by `--span-name` (or any span), with
+    /// the span it was in
+    SpanEvents,
+    /// Pauses longer than `--threshold` between reports on the same thread
+    Gaps,
+    /// Enters, exits and events from all threads in one timeline, to see how
+    /// they interleave
+    Interleaved,
+    /// Number of reports from each source location, events and span
+    /// operations alike, most first
+    Callsites,
+    /// The spans each thread was inside of at the tick given by `--at`
+    Active,
+    /// Integrity checks: decode errors, unbalanced enters and exits, and
+    /// ticks going backwards. Exits with status 1 if anything is wrong
+    Validate,
+    /// The reconstructed span tree as JSON, one line per thread
+    TreeJson,
+    /// The most instances of each span callsite entered at once, across all
+    /// threads
+    Concurrency,
+    /// p50/p95/p99 of how long each span callsite took from enter to exit,
+    /// and from enter to its first event
+    Latency,
+    /// Events at each level, per thread and source location
+    Levels,
+    /// Parent-child and follows-from links between spans, as CSV
+    Edges,
+    /// Parent-child and follows-from links between spans, one JSON object
+    /// per line
+    EdgesJson,
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.rpt
+#[derive(Clone, Copy, ValueEnum)]
+enum Timing {
+    Busy,
+    Wall,
+}
+
+impl From<Timing> for TimingMode {
+    fn from(timing: Timing) -> Self {
+        match timing {
+            Timing::Busy => TimingMode::Busy,
+            Timing::Wall => TimingMode::Wall,
+        }
     }
 }
 
-struct Elements {
-    rpts: Vec<Element>,
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: u64 = num.parse().map_err(|_| format!("invalid duration `{}`", s))?;
+    match unit {
+        "ns" => Ok(Duration::from_nanos(num)),
+        "us" => Ok(Duration::from_micros(num)),
+        "ms" => Ok(Duration::from_millis(num)),
+        "s" => Ok(Duration::from_secs(num)),
+        _ => Err(format!("invalid duration `{}`, expected a unit of ns, us, ms or s", s)),
+    }
 }
 
-struct TlSpans {
-    spans: Vec<Span>,
-    events: Vec<tss::SerializeEvent<'static>>,
+fn parse_category(s: &str) -> Result<CategoryBy, String> {
+    match s.split_once(':') {
+        Some(("field", name)) if !name.is_empty() => Ok(CategoryBy::Field(name.to_string())),
+        None if s == "target" => Ok(CategoryBy::Target),
+        None if s == "location" => Ok(CategoryBy::Location),
+        _ => Err(format!("invalid category `{}`, expected target, location or field:NAME", s)),
+    }
 }
 
-struct Span {
-    start: u128,
-    end: u128,
-    spans: Vec<Span>,
-    events: Vec<tss::SerializeEvent<'static>>,
-    attrs: tss::SerializeAttributes<'static>,
+fn open_input(path: Option<&PathBuf>) -> io::Result<Box<dyn Read>> {
+    match path {
+        Some(path) if path.as_os_str() == "-" => Ok(Box::new(io::stdin().lock())),
+        Some(path) => Ok(Box::new(File::open(path)?)),
+        None if !io::stdin().is_terminal() => Ok(Box::new(io::stdin().lock())),
+        None => Ok(Box::new(File::open("report.bin")?)),
+    }
 }
 
-impl Span {
-    fn count_events_rec(&self) -> (usize, usize) {
-        let own_events = self.events.len();
-        let mut child_events = 0;
-        self.spans.iter().for_each(|s| {
-            let (own, child) = s.count_events_rec();
-            child_events += own;
-            child_events += child;
-        });
-        (own_events, child_events)
+/// Adds any frames that failed to decode to `errors`. Exits if the input
+/// can't be read at all.
+fn load(path: Option<&PathBuf>, errors: &mut Vec<ReportError>) -> Elements {
+    let loaded = open_input(path)
+        .map_err(ReportError::from)
+        .and_then(Elements::from_reader_with_errors);
+    match loaded {
+        Ok((elements, mut decode_errors)) => {
+            errors.append(&mut decode_errors);
+            elements
+        },
+        Err(e) => {
+            match path {
+                Some(path) => eprintln!("error: {}: {}", path.display(), e),
+                None => eprintln!("error: {}", e),
+            }
+            std::process::exit(1);
+        },
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+
+    if args.describe_format {
+        println!("{}", serde_json::to_string_pretty(tracing_report::format::describe()).unwrap());
+        return;
+    }
+
+    if args.follow {
+        let path = match args.input.as_slice() {
+            [] => PathBuf::from("report.bin"),
+            [path] => path.clone(),
+            _ => {
+                eprintln!("error: --follow reads a single input");
+                std::process::exit(2);
+            },
+        };
+        if let Err(e) = follow(&path, matches!(args.format, Format::Ndjson), args.messages) {
+            eprintln!("error: {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut errors = vec![];
+    let mut elements = match args.input.as_slice() {
+        [] => load(None, &mut errors),
+        [path] => load(Some(path), &mut errors),
+        paths => Elements::merge(paths.iter().map(|path| load(Some(path), &mut errors)).collect()),
+    };
+    if let Some(path) = args.baseline.as_ref() {
+        let baseline = load(Some(path), &mut vec![]);
+        print_diff(&baseline.diff(&elements));
+        return;
     }
 
-    fn print_spans_rec(&self, depth: usize, indent: usize) {
-        if depth == 0 {
+    if let Some(thread) = args.thread.as_deref() {
+        let ids: HashSet<u64> = match thread.parse() {
+            Ok(id) => HashSet::from([id]),
+            Err(_) => elements
+                .thread_names()
+                .into_iter()
+                .filter_map(|(id, name)| (name == thread).then_some(id))
+                .collect(),
+        };
+        if ids.is_empty() {
+            eprintln!("error: no thread is named {:?}", thread);
+            std::process::exit(2);
+        }
+        elements.rpts.retain(|rpt| ids.contains(&rpt.thread_id));
+    }
+    if let Some(name) = args.span_name.as_deref() {
+        if let Format::SpanEvents = args.format {
+            print_span_events(&elements, |attrs| attrs.metadata.name.as_str() == name);
             return;
         }
+        elements = elements.filter_span_subtrees(|attrs| attrs.metadata.name.as_str() == name);
+    }
+
+    if let Some(max) = args.max_duration {
+        let named = |span: &Span| args.span_name.as_deref().is_none_or(|name| span.attrs.metadata.name.as_str() == name);
+        if !check_max_duration(&elements, max, named) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let export = ExportConfig {
+        category_by: args.category_by.clone(),
+    };
+    if let Some(addr) = args.serve {
+        if let Err(e) = serve(&elements, args.timing.into(), &export, addr) {
+            eprintln!("error: {}: {}", addr, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let min_ns = args.min_duration.map_or(0, |min| u64::try_from(min.as_nanos()).unwrap_or(u64::MAX));
+    match args.format {
+        Format::Tree => {
+            print_tree(&elements, args.depth, min_ns, args.messages, args.collapse_recursion);
+            print_overview(&elements, errors.len());
+        },
+        Format::Chrome => write_chrome(&elements, args.timing.into(), &export, io::stdout().lock()).unwrap(),
+        Format::Flame => print_flame(&elements, args.timing.into(), min_ns, args.cpu, args.collapse_recursion),
+        Format::Ndjson => print_ndjson(&elements, args.messages),
+        Format::Events => print_events(&elements),
+        Format::Timeline => print_timeline(&elements),
+        Format::Histogram => print_histogram(&elements, args.bucket, args.include_spans),
+        Format::Shapes => print_shapes(&elements),
+        Format::Csv => print_csv(&elements, args.messages),
+        Format::Mermaid => write_mermaid(&elements, &mut io::stdout().lock()).unwrap(),
+        Format::SpanEvents => print_span_events(&elements, |_| true),
+        Format::Gaps => print_gaps(&elements, args.threshold),
+        Format::Interleaved => print_interleaved(&elements),
+        Format::TreeJson => {
+            for (thread_id, tl_spans) in elements.spanner_by_thread().iter_mut() {
+                tl_spans.prune_shorter_than(min_ns);
+                println!("{}", json!({ "thread": thread_id, "tree": tl_spans }));
+            }
+        },
+        Format::Validate => {
+            if !print_validation(&elements, &errors) {
+                std::process::exit(1);
+            }
+        },
+        Format::Active => {
+            for (thread_id, spans) in elements.active_at(args.at.unwrap_or_default()) {
+                match spans.is_empty() {
+                    true => println!("THREAD {} | (idle)", thread_id),
+                    false => println!("THREAD {} | {}", thread_id, spans.join(" > ")),
+                }
+            }
+        },
+        Format::Latency => print_latencies(&elements),
+        Format::Levels => {
+            println!("{:>8} | {:>6} {:>6} {:>6} {:>6} {:>6} | location", "thread", "ERROR", "WARN", "INFO", "DEBUG", "TRACE");
+            for (thread_id, locations) in elements.level_counts() {
+                for (location, n) in locations {
+                    println!(
+                        "{:>8} | {:>6} {:>6} {:>6} {:>6} {:>6} | {}",
+                        thread_id.to_string(), n.error, n.warn, n.info, n.debug, n.trace, location,
+                    );
+                }
+            }
+        },
+        Format::Edges => {
+            println!("from_span_id,to_span_id,kind");
+            for edge in elements.span_edges() {
+                println!("{},{},{}", span_key(edge.from), span_key(edge.to), edge.kind);
+            }
+        },
+        Format::EdgesJson => {
+            for edge in elements.span_edges() {
+                println!("{}", json!({ "source": edge.from.0, "from": edge.from.1, "to": edge.to.1, "kind": edge.kind }));
+            }
+        },
+        Format::Concurrency => {
+            for (location, peak) in elements.peak_concurrency() {
+                println!("{:>8} | {}", peak, location);
+            }
+        },
+        Format::Callsites => {
+            for (location, count) in elements.callsite_counts() {
+                println!("{:>8} | {}", count, location);
+            }
+        },
+    }
+}
 
-        for span in self.spans.iter() {
-            for _ in 0..indent {
-                print!("-");
+/// Prints every span matching `predicate` that took longer than `max`.
+/// Returns whether there were none.
+fn check_max_duration(elements: &Elements, max: Duration, predicate: impl Fn(&Span) -> bool) -> bool {
+    let max = u64::try_from(max.as_nanos()).unwrap_or(u64::MAX);
+    let mut over = 0;
+    let mut longest = 0;
+    for (thread_id, tl_spans) in elements.spanner_by_thread().iter() {
+        for root in tl_spans.spans.iter() {
+            for span in root.find_all(|span| predicate(span) && span.end - span.start > max) {
+                println!("THREAD {} | {}ns | {}", thread_id, span.end - span.start, span.label());
+                over += 1;
             }
-            print!("> ");
+            longest = root.fold(longest, &mut |longest, span| match predicate(span) {
+                true => longest.max(span.end - span.start),
+                false => longest,
+            });
+        }
+    }
+    match over {
+        0 => println!("OK: no span over {}ns (longest: {}ns)", max, longest),
+        n => println!("FAIL: {} spans over {}ns (longest: {}ns)", n, max, longest),
+    }
+    over == 0
+}
+
+/// Returns whether everything checked out.
+fn print_validation(elements: &Elements, errors: &[ReportError]) -> bool {
+    println!("{} reports, {} frames that failed to decode", elements.rpts.len(), errors.len());
+    for e in errors.iter().take(10) {
+        println!("    |> {}", e);
+    }
+    if errors.len() > 10 {
+        println!("    |> ...");
+    }
+
+    let threads = elements.validate();
+    for v in threads.iter() {
+        println!(
+            "THREAD {} | {} spans | {} events | {} unmatched enters | {} orphan exits | {} backwards ticks",
+            v.thread,
+            v.spans,
+            v.events,
+            v.unmatched_enters,
+            v.orphan_exits,
+            v.backwards_ticks,
+        );
+    }
+
+    let ok = errors.is_empty() && threads.iter().all(ThreadValidation::is_ok);
+    println!("{}", if ok { "OK" } else { "PROBLEMS FOUND" });
+    ok
+}
+
+fn print_overview(elements: &Elements, decode_errors: usize) {
+    let overview = elements.overview();
+    println!();
+    println!("OVERVIEW");
+    println!(
+        "{} reports | {} decode errors | {} threads | {} spans | {} events | {}ns",
+        overview.reports,
+        decode_errors,
+        overview.threads,
+        overview.spans,
+        overview.events,
+        overview.duration_ns,
+    );
+    if let Some((thread, busy_ns)) = overview.busiest_thread {
+        println!("busiest thread: {} ({}ns busy)", thread, busy_ns);
+    }
+    if let Some((location, reports)) = overview.busiest_location {
+        println!("busiest location: {} ({} reports)", location, reports);
+    }
+}
+
+/// A span id, prefixed by its source when several captures are merged, like
+/// `ThreadKey`.
+fn span_key((source, id): SpanKey) -> String {
+    match source {
+        0 => id.to_string(),
+        source => format!("{}:{}", source, id),
+    }
+}
+
+/// Prints which thread ids are which named threads, if any are.
+fn print_thread_names(elements: &Elements) {
+    let mut names: Vec<(u64, String)> = elements.thread_names().into_iter().collect();
+    if names.is_empty() {
+        return;
+    }
+    names.sort();
+    println!("THREADS");
+    for (thread_id, name) in names.iter() {
+        println!("{:>8} | {}", thread_id, name);
+    }
+    println!();
+}
+
+fn print_tree(elements: &Elements, depth: usize, min_ns: u64, messages: bool, collapse: bool) {
+    let labels = elements.labels();
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        println!("LABELS {}", labels.join(" "));
+        println!();
+    }
+    print_thread_names(elements);
+    let mut trees = elements.spanner_by_thread();
+    for ((thread_id, elements), (_, tl_span)) in elements.split_by_entering_thread().iter().zip(trees.iter_mut()) {
+        match elements.source(thread_id.source) {
+            Some(src) => println!("THREAD {} ({}, pid {})", thread_id, src.hostname, src.pid),
+            None => println!("THREAD {}", thread_id),
+        }
+        let summary = elements.thread_summary();
+        println!(
+            "{} spans | {} events | {}ns busy | {}ns active",
+            summary.spans,
+            summary.events,
+            summary.busy_ns,
+            summary.last_tick.saturating_sub(summary.first_tick),
+        );
+        match tl_span.prune_shorter_than(min_ns) {
+            0 => {},
+            pruned => println!("{} spans shorter than {}ns left out", pruned, min_ns),
+        }
+        println!();
 
+        for span in tl_span.spans.iter() {
+            let nested = Nested::of(span, collapse);
             let (oevt, cevt) = span.count_events_rec();
+            let (ospn, aspn) = span.count_spans_rec();
             println!(
-                "[SPAN | {}ns | {}:{}] ({} events, {} child events)",
+                "[SPAN | {}ns{} | {}{}] ({} events, {} child events, {} spans, {} nested spans{})",
                 span.end - span.start,
-                span.attrs.metadata.file.as_deref().unwrap_or("???"),
-                span.attrs.metadata.line.unwrap_or(0),
+                cpu_time(span),
+                span.label(),
+                nested.annotation(),
                 oevt,
                 cevt,
+                ospn,
+                aspn,
+                if span.is_root { "" } else { ", not a root" },
+            );
+            if messages {
+                print_messages(nested.events.iter().copied(), 1);
+            }
+            print_spans_rec(&nested.spans, 1, depth, messages, collapse);
+        }
+        if !tl_span.events.is_empty() {
+            println!("[NO SPAN] ({} events)", tl_span.events.len());
+            if messages {
+                print_messages(tl_span.events.iter(), 1);
+            }
+        }
+
+        let diag = &tl_span.diagnostics;
+        if !diag.is_clean() {
+            println!();
+            println!(
+                "WARNING: unbalanced trace ({} orphan exits, {} unclosed spans)",
+                diag.orphan_exits,
+                diag.unclosed_spans,
+            );
+        }
+        if diag.unknown_spans > 0 {
+            println!();
+            println!(
+                "WARNING: {} spans entered with no record of their creation, left out of the tree",
+                diag.unknown_spans,
+            );
+        }
+        if diag.too_deep_spans > 0 {
+            println!();
+            println!(
+                "WARNING: {} spans nested more than {} deep, left out of the tree",
+                diag.too_deep_spans,
+                DEFAULT_MAX_DEPTH,
+            );
+        }
+        if diag.has_clock_anomalies() {
+            println!();
+            println!(
+                "WARNING: clock anomalies ({} spans ending before they start, {} spans longer than 30 days)",
+                diag.backwards_spans,
+                diag.absurd_spans,
             );
-            span.print_spans_rec(depth - 1, indent + 2);
         }
     }
 }
 
-fn capture_span(
-    map: &HashMap<NonZeroU64, tss::SerializeAttributes<'static>>,
-    stack: &mut Vec<Element>,
-    id_span: NonZeroU64,
-    start: u128,
-) -> Span {
-    let mut spans = vec![];
-    let mut events = vec![];
-    loop {
-        let pop = stack.pop();
-        let Report { tick, payload, .. } = pop.as_deref().unwrap();
-        match payload {
-            ReportPayload::OnEvent { event } => {
-                events.push(event.to_owned());
+/// What's printed under a span in the tree view.
+struct Nested<'s> {
+    spans: Vec<&'s Span>,
+    events: Vec<&'s tss::SerializeEvent<'static>>,
+    /// How many spans of the same callsite, nested in each other, were
+    /// collapsed into this one.
+    levels: usize,
+}
+
+impl<'s> Nested<'s> {
+    /// With `collapse`, spans nested in `span` that are of the same callsite
+    /// are left out, and what's inside them is shown in their place.
+    fn of(span: &'s Span, collapse: bool) -> Self {
+        let mut nested = Nested {
+            spans: vec![],
+            events: span.events.iter().collect(),
+            levels: 1,
+        };
+        if !collapse {
+            nested.spans = span.spans.iter().collect();
+            return nested;
+        }
+        let location = span.location();
+        let mut stack = vec![span.spans.iter()];
+        while let Some(spans) = stack.last_mut() {
+            match spans.next() {
+                Some(child) if child.location() == location => {
+                    nested.events.extend(child.events.iter());
+                    stack.push(child.spans.iter());
+                    nested.levels = nested.levels.max(stack.len());
+                },
+                Some(child) => nested.spans.push(child),
+                None => {
+                    stack.pop();
+                },
+            }
+        }
+        nested
+    }
+
+    fn annotation(&self) -> String {
+        match self.levels {
+            1 => String::new(),
+            levels => format!(" (recursion x{})", levels),
+        }
+    }
+}
+
+fn print_spans_rec(spans: &[&Span], level: usize, remaining: usize, messages: bool, collapse: bool) {
+    if remaining == 0 {
+        return;
+    }
+
+    for span in spans.iter() {
+        for _ in 0..level * 2 {
+            print!("-");
+        }
+        print!("> ");
+
+        let nested = Nested::of(span, collapse);
+        let (oevt, cevt) = span.count_events_rec();
+        let (ospn, aspn) = span.count_spans_rec();
+        println!(
+            "[SPAN | {}ns{} | {}{}] ({} events, {} child events, {} spans, {} nested spans)",
+            span.end - span.start,
+            cpu_time(span),
+            span.label(),
+            nested.annotation(),
+            oevt,
+            cevt,
+            ospn,
+            aspn,
+        );
+        if messages {
+            print_messages(nested.events.iter().copied(), level + 1);
+        }
+        print_spans_rec(&nested.spans, level + 1, remaining - 1, messages, collapse);
+    }
+}
+
+/// The span's CPU time, if recorded, to follow its duration.
+fn cpu_time(span: &Span) -> String {
+    match span.cpu_ns {
+        Some(ns) => format!(", {}ns cpu", ns),
+        None => String::new(),
+    }
+}
+
+fn print_messages<'e>(events: impl Iterator<Item = &'e tss::SerializeEvent<'static>>, depth: usize) {
+    for event in events {
+        println!("{:width$}* [{}] {}", "", level_str(event.metadata.level), event.label(), width = depth * 2);
+    }
+}
+
+/// Writes a JSON array one value at a time, so it never has to be held in
+/// memory whole.
+struct JsonArray<W: Write> {
+    w: W,
+    empty: bool,
+}
+
+impl<W: Write> JsonArray<W> {
+    fn new(mut w: W) -> io::Result<Self> {
+        w.write_all(b"[")?;
+        Ok(Self { w, empty: true })
+    }
+
+    fn push(&mut self, value: serde_json::Value) -> io::Result<()> {
+        if !self.empty {
+            self.w.write_all(b",")?;
+        }
+        self.empty = false;
+        serde_json::to_writer(&mut self.w, &value)?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.w.write_all(b"]\n")?;
+        self.w.flush()
+    }
+}
+
+fn write_chrome<W: Write>(elements: &Elements, timing: TimingMode, export: &ExportConfig, w: W) -> io::Result<()> {
+    fn push_rec<W: Write>(out: &mut JsonArray<W>, thread: ThreadKey, span: &Span, export: &ExportConfig) -> io::Result<()> {
+        out.push(span.to_chrome_complete_event_with(thread, export))?;
+        for child in span.spans.iter() {
+            push_rec(out, thread, child, export)?;
+        }
+        Ok(())
+    }
+
+    let mut out = JsonArray::new(w)?;
+    for (index, src) in elements.sources.iter().enumerate() {
+        if let Some(src) = src {
+            out.push(json!({
+                "name": "process_name",
+                "ph": "M",
+                "pid": index,
+                "args": { "name": format!("{} (pid {})", src.hostname, src.pid) },
+            }))?;
+        }
+    }
+    for (thread_id, spans) in elements.spans_by_thread(timing).iter() {
+        for span in spans.iter() {
+            push_rec(&mut out, *thread_id, span, export)?;
+        }
+    }
+    // Events outside any span, as instant events, and markers as global
+    // ones, which are drawn across every thread.
+    let mut result = Ok(());
+    elements.walk_with_stack(|rpt, stack| {
+        if result.is_err() {
+            return;
+        }
+        match (&rpt.payload, stack) {
+            (ReportPayload::OnEvent { event }, []) => {
+                let location = Location::of(&event.metadata).to_string();
+                let category = match &export.category_by {
+                    CategoryBy::Field(name) => event
+                        .attributes()
+                        .into_iter()
+                        .find(|(key, _)| key == name)
+                        .map(|(_, val)| val.display().to_string()),
+                    CategoryBy::Target => None,
+                    CategoryBy::Location => Some(location.clone()),
+                };
+                result = out.push(json!({
+                    "name": location,
+                    "cat": category.as_deref().unwrap_or(event.metadata.target.as_str()),
+                    "ph": "i",
+                    "s": "t",
+                    "ts": rpt.tick as f64 / 1000.0,
+                    "pid": rpt.source,
+                    "tid": rpt.thread_id,
+                }));
             },
-            ReportPayload::OnEnter { span } => {
-                spans.push(capture_span(map, stack, span.id, *tick));
+            (ReportPayload::Marker { name }, _) => {
+                result = out.push(json!({
+                    "name": name,
+                    "cat": "marker",
+                    "ph": "i",
+                    "s": "g",
+                    "ts": rpt.tick as f64 / 1000.0,
+                    "pid": rpt.source,
+                    "tid": rpt.thread_id,
+                }));
             },
-            ReportPayload::OnExit { span } => {
-                assert_eq!(span.id, id_span);
-                return Span {
-                    start,
-                    end: *tick,
+            _ => {},
+        }
+    });
+    result?;
+
+    // Draw follows-from links as flow arrows, from where the earlier span
+    // was first entered to where the later one was.
+    let mut first_enters: HashMap<SpanKey, (ThreadKey, u64)> = HashMap::new();
+    for rpt in elements.rpts.iter() {
+        if let ReportPayload::OnEnter { span } = &rpt.payload {
+            first_enters.entry((rpt.source, span.id)).or_insert((rpt.thread_key(), rpt.tick));
+        }
+    }
+    let links = elements.rpts.iter().filter_map(|rpt| match &rpt.payload {
+        ReportPayload::OnFollowsFrom { span, follows } => Some((
+            first_enters.get(&(rpt.source, follows.id))?,
+            first_enters.get(&(rpt.source, span.id))?,
+        )),
+        _ => None,
+    });
+    for (id, ((from_thread, from_tick), (to_thread, to_tick))) in links.enumerate() {
+        out.push(json!({
+            "name": "follows_from",
+            "cat": "follows_from",
+            "ph": "s",
+            "id": id,
+            "ts": *from_tick as f64 / 1000.0,
+            "pid": from_thread.source,
+            "tid": from_thread.thread_id,
+        }))?;
+        out.push(json!({
+            "name": "follows_from",
+            "cat": "follows_from",
+            "ph": "f",
+            "bp": "e",
+            "id": id,
+            "ts": *to_tick as f64 / 1000.0,
+            "pid": to_thread.source,
+            "tid": to_thread.thread_id,
+        }))?;
+    }
+    out.finish()
+}
+
+/// Served at `/` by `--serve`. Perfetto can't fetch from another origin, so
+/// the page fetches the trace, and hands it over with `postMessage`.
+const VIEWER: &str = r#"<!doctype html>
+<meta charset="utf-8">
+<title>tracing-report</title>
+<p><button id="open">Open in Perfetto</button> or download <a href="/trace.json">trace.json</a></p>
+<script>
+const ORIGIN = 'https://ui.perfetto.dev';
+document.getElementById('open').onclick = async () => {
+  const ui = window.open(ORIGIN + '/#!/');
+  const buffer = await (await fetch('/trace.json')).arrayBuffer();
+  const ping = setInterval(() => ui.postMessage('PING', ORIGIN), 50);
+  window.addEventListener('message', function onPong(e) {
+    if (e.origin !== ORIGIN || e.data !== 'PONG') return;
+    clearInterval(ping);
+    window.removeEventListener('message', onPong);
+    ui.postMessage({ perfetto: { buffer, title: 'trace.json' } }, ORIGIN);
+  });
+};
+</script>
+"#;
+
+/// Serves one request at a time, until killed. The trace JSON is generated
+/// for each request, and streamed as it's generated.
+fn serve(elements: &Elements, timing: TimingMode, export: &ExportConfig, addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("serving on http://{}/", listener.local_addr()?);
+    for stream in listener.incoming() {
+        if let Err(e) = stream.and_then(|stream| respond(elements, timing, export, stream)) {
+            eprintln!("warning: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn respond(elements: &Elements, timing: TimingMode, export: &ExportConfig, stream: TcpStream) -> io::Result<()> {
+    let mut lines = BufReader::new(&stream).lines();
+    let request = lines.next().transpose()?.unwrap_or_default();
+    // Read the headers, though they're all ignored, so closing the
+    // connection doesn't reset it.
+    for line in lines {
+        if line?.is_empty() {
+            break;
+        }
+    }
+
+    // No `Content-Length`: the body ends when the connection closes.
+    let mut w = BufWriter::new(&stream);
+    match request.split_whitespace().nth(1) {
+        Some("/") => {
+            write!(w, "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nConnection: close\r\n\r\n")?;
+            w.write_all(VIEWER.as_bytes())?;
+        },
+        Some("/trace.json") => {
+            write!(w, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nConnection: close\r\n\r\n")?;
+            write_chrome(elements, timing, export, &mut w)?;
+        },
+        _ => write!(w, "HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n")?,
+    }
+    w.flush()
+}
+
+fn print_latencies(elements: &Elements) {
+    for latency in elements.span_latencies() {
+        let p = &latency.completion;
+        println!(
+            "[SPAN | {} | {}] {} instances | completion p50 {}ns, p95 {}ns, p99 {}ns, max {}ns",
+            latency.location, latency.name, p.count, p.p50, p.p95, p.p99, p.max,
+        );
+        if let Some(p) = &latency.first_event {
+            println!(
+                "    |> first event ({} instances) p50 {}ns, p95 {}ns, p99 {}ns, max {}ns",
+                p.count, p.p50, p.p95, p.p99, p.max,
+            );
+        }
+    }
+}
+
+fn print_diff(diff: &DiffReport) {
+    println!(
+        "{:>10} {:>10} {:>8} | {:>14} {:>14} {:>14} | {:>12} {:>12} {:>12} | span",
+        "count", "was", "change", "total ns", "was", "change", "p95 ns", "was", "change",
+    );
+    for span in diff.spans.iter() {
+        let get = |p: &Option<Percentiles>, f: fn(&Percentiles) -> u64| p.as_ref().map_or(0, f);
+        println!(
+            "{:>10} {:>10} {:>+8} | {:>14} {:>14} {:>+14} | {:>12} {:>12} {:>+12} | {} {}",
+            get(&span.candidate, |p| p.count as u64),
+            get(&span.baseline, |p| p.count as u64),
+            span.count_change(),
+            get(&span.candidate, |p| p.total),
+            get(&span.baseline, |p| p.total),
+            span.total_change(),
+            get(&span.candidate, |p| p.p95),
+            get(&span.baseline, |p| p.p95),
+            span.p95_change(),
+            span.location,
+            span.name,
+        );
+    }
+}
+
+fn print_flame(elements: &Elements, timing: TimingMode, min_ns: u64, cpu: bool, collapse: bool) {
+    let mut folded: HashMap<String, u64> = HashMap::new();
+    let mut add = |thread: ThreadKey, frames: &mut dyn Iterator<Item = String>, ns: u64| {
+        let mut stack = vec![format!("thread-{}", thread)];
+        stack.extend(frames);
+        if collapse {
+            stack.dedup();
+        }
+        *folded.entry(stack.join(";")).or_default() += ns;
+    };
+    match timing {
+        // Streamed, so span trees are never built.
+        TimingMode::Busy if !cpu => {
+            // Each entered span's location, and the time spent in spans
+            // entered inside it.
+            let mut open: HashMap<ThreadKey, Vec<(String, u64)>> = HashMap::new();
+            for boundary in elements.span_cursor() {
+                match boundary {
+                    Boundary::Enter { thread, attrs, .. } => {
+                        open.entry(thread).or_default().push((Location::of(&attrs.metadata).to_string(), 0));
+                    },
+                    Boundary::Exit { thread, start, end, .. } => {
+                        let stack = open.entry(thread).or_default();
+                        // Too short a span, and everything in it, is left as
+                        // time spent in its parent.
+                        if end - start < min_ns {
+                            stack.pop();
+                            continue;
+                        }
+                        let children = stack.last().map_or(0, |(_, children)| *children);
+                        add(thread, &mut stack.iter().map(|(location, _)| location.clone()), (end - start).saturating_sub(children));
+                        stack.pop();
+                        if let Some((_, children)) = stack.last_mut() {
+                            *children += end - start;
+                        }
+                    },
+                    Boundary::Event { .. } => {},
+                }
+            }
+        },
+        _ => {
+            let weight = |span: &Span| match cpu {
+                true => span.cpu_ns.unwrap_or(0),
+                false => span.end - span.start,
+            };
+            for (thread_id, spans) in elements.spans_by_thread(timing) {
+                let mut tl_spans = TlSpans {
                     spans,
-                    events,
-                    attrs: map.get(&id_span).unwrap().to_owned(),
+                    events: vec![],
+                    diagnostics: Default::default(),
                 };
-            },
-            _ => continue,
+                tl_spans.prune_shorter_than(min_ns);
+                for root in tl_spans.spans.iter() {
+                    root.walk(&mut |span| {
+                        let mut frames = span.ancestors.iter().flatten().cloned().chain([span.location()]);
+                        let children: u64 = span.spans.iter().map(weight).sum();
+                        add(thread_id, &mut frames, weight(span).saturating_sub(children));
+                    });
+                }
+            }
+        },
+    }
+
+    let mut folded: Vec<_> = folded.into_iter().collect();
+    folded.sort_unstable();
+    for (stack, ns) in folded.iter() {
+        println!("{} {}", stack, ns);
+    }
+}
+
+fn print_ndjson(elements: &Elements, messages: bool) {
+    for rpt in elements.rpts.iter() {
+        print_ndjson_line(rpt, messages);
+    }
+}
+
+fn print_ndjson_line(rpt: &Element, messages: bool) {
+    match &rpt.payload {
+        ReportPayload::OnEvent { event } if messages => {
+            let mut line = serde_json::to_value(&**rpt).unwrap();
+            line["message"] = event.message().map(|m| m.to_string()).into();
+            println!("{}", line);
+        },
+        _ => println!("{}", serde_json::to_string(&**rpt).unwrap()),
+    }
+}
+
+fn print_events(elements: &Elements) {
+    for (thread_id, elements) in elements.split_by_thread_id().iter() {
+        println!("THREAD {}", thread_id);
+        for (key, events) in elements.events_by_location().iter() {
+            println!("{} | {} | {} INSTANCES", thread_id, key, events.len());
+            for rec in events.iter() {
+                // Decoded reports only ever hold `De` fields.
+                let tss::SerializeRecordFields::De(fields) = rec else {
+                    continue;
+                };
+                print!("    |> ");
+                let mut data: Vec<(&tss::CowString, &tss::SerializeValue)> = fields.iter().collect();
+                data.sort_unstable_by_key(|(key, _val)| key.as_str());
+                for (key, val) in data.iter() {
+                    print!("{} = {}, ", key.as_str(), val.display());
+                }
+                println!("|");
+            }
+        }
+        for _ in 0..3 {
+            println!();
         }
     }
 }
 
-impl Elements {
-    fn spanner(&self) -> TlSpans {
-        let mut map = HashMap::new();
-        self.rpts.iter().for_each(|rpt| {
+fn print_timeline(elements: &Elements) {
+    // Markers go in every thread's timeline, where their tick falls.
+    let markers: Vec<(u64, &str)> = elements
+        .rpts
+        .iter()
+        .filter_map(|rpt| match &rpt.payload {
+            ReportPayload::Marker { name } => Some((rpt.tick, name.as_str())),
+            _ => None,
+        })
+        .collect();
+
+    print_thread_names(elements);
+    for (thread_id, reports) in elements.split_by_thread_id().iter() {
+        let mut spans = HashMap::new();
+        reports.rpts.iter().for_each(|rpt| {
             if let ReportPayload::OnNewSpan { attrs, id } = &rpt.payload {
-                map.insert(
+                spans.insert(
                     id.id,
-                    attrs.to_owned(),
+                    format!(
+                        "[SPAN | {}]",
+                        Location::of(&attrs.metadata),
+                    )
                 );
             }
         });
 
-        // Reverse, so we can pop off the end.
-        let mut stack = self.rpts.iter().rev().cloned().collect::<Vec<Element>>();
-        let mut spans = vec![];
-        let mut events = vec![];
+        let mut indent = 0usize;
 
-        loop {
-            let pop = stack.pop();
-            let Report { tick, payload, .. } = if let Some(rpt) = pop.as_deref() {
-                rpt
-            } else {
-                break;
-            };
+        println!("============================================================");
+        println!("THREAD {}", thread_id);
+        println!("============================================================");
 
-            match payload {
+        let mut markers = markers.iter().peekable();
+        let print_marker = |(tick, name): &(u64, &str)| println!(" {:016} |==== {} ====", tick, name);
+        for report in reports.rpts.iter() {
+            while let Some(marker) = markers.next_if(|(tick, _)| *tick <= report.tick) {
+                print_marker(marker);
+            }
+            match &report.payload {
                 ReportPayload::OnEvent { event } => {
-                    events.push(event.to_owned());
+                    print!(" {:016} |", report.tick);
+                    for _ in 0..indent {
+                        print!(" ");
+                    }
+                    println!(
+                        "[EVENT | {}]",
+                        Location::of(&event.metadata),
+                    );
                 },
                 ReportPayload::OnEnter { span } => {
-                    spans.push(capture_span(&map, &mut stack, span.id, *tick));
+                    print!(" {:016} |", report.tick);
+                    for _ in 0..indent {
+                        print!(" ");
+                    }
+                    if spans.contains_key(&span.id) {
+                        println!("{}", spans[&span.id]);
+                    } else {
+                        println!("[SPAN | ???]");
+                    }
+                    indent += 2;
                 },
                 ReportPayload::OnExit { .. } => {
-                    panic!()
+                    print!(" {:016} |", report.tick);
+                    // Exits of spans entered before the capture started.
+                    indent = indent.saturating_sub(2);
+                    for _ in 0..indent {
+                        print!(" ");
+                    }
+                    println!("<-");
                 },
-                _ => continue,
+                _ => {},
             }
         }
+        markers.for_each(print_marker);
 
-        TlSpans { spans, events }
+        for _ in 0..5 {
+            println!();
+        }
     }
+}
 
+static STOP: AtomicBool = AtomicBool::new(false);
 
+/// A file that's still being written: reads wait for more bytes instead of
+/// ending, until Ctrl-C. Frames are only decoded once their closing `0` has
+/// arrived, so a partly written frame is just waited on.
+struct Follow(File);
 
-    fn split_by_thread_id(&self) -> Vec<(u64, Elements)> {
-        let mut chunky = HashMap::new();
-
-        self.rpts.iter().for_each(|rpt| {
-            if !chunky.contains_key(&rpt.thread_id) {
-                chunky.insert(rpt.thread_id, vec![]);
+impl Read for Follow {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.0.read(buf)?;
+            if n > 0 || STOP.load(Ordering::Relaxed) {
+                return Ok(n);
             }
-            chunky.get_mut(&rpt.thread_id).unwrap().push(rpt.clone());
-        });
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn stop_on_ctrl_c() {
+    extern "C" fn on_sigint(_: libc::c_int) {
+        STOP.store(true, Ordering::Relaxed);
+    }
+    unsafe {
+        libc::signal(libc::SIGINT, on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+// Ctrl-C just ends the process.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn stop_on_ctrl_c() {}
+
+fn follow(path: &PathBuf, ndjson: bool, messages: bool) -> Result<(), ReportError> {
+    stop_on_ctrl_c();
+    let mut interleaved = Interleaved::default();
+    for rpt in ReportReader::new(Follow(File::open(path)?))? {
+        if STOP.load(Ordering::Relaxed) {
+            // Whatever was read last may be a partial frame.
+            break;
+        }
+        let rpt = match rpt {
+            Ok(rpt) => Element { rpt: Rc::new(rpt), source: 0 },
+            Err(ReportError::Io(e)) => return Err(e.into()),
+            Err(e) => {
+                eprintln!("warning: {}", e);
+                continue;
+            },
+        };
+        match ndjson {
+            true => print_ndjson_line(&rpt, messages),
+            false => {
+                if let Some(line) = interleaved.line(&rpt) {
+                    println!("{}", line);
+                }
+            },
+        }
+    }
+    Ok(())
+}
 
-        chunky
-            .drain()
-            .map(|(id, vr)| (id, Elements { rpts: vr }))
-            .collect()
+fn print_interleaved(elements: &Elements) {
+    print_thread_names(elements);
+    let mut interleaved = Interleaved::default();
+    for report in elements.timeline().iter() {
+        if let Some(line) = interleaved.line(report) {
+            println!("{}", line);
+        }
     }
+}
 
-    fn events_by_location(&self) -> Vec<(String, Vec<tss::SerializeRecordFields<'static>>)> {
-        let mut chunky = HashMap::new();
+/// Formats reports for the interleaved view, one at a time, in tick order.
+#[derive(Default)]
+struct Interleaved {
+    spans: HashMap<SpanKey, String>,
+    indents: HashMap<ThreadKey, usize>,
+}
 
-        self.rpts.iter().for_each(|rpt| {
-            if let ReportPayload::OnEvent { ref event } = &rpt.rpt.payload {
-                let key = format!(
-                    "{}:{}",
-                    event.metadata.file.as_deref().unwrap_or("???"),
-                    event.metadata.line.unwrap_or(0),
+impl Interleaved {
+    fn line(&mut self, report: &Element) -> Option<String> {
+        let indent = self.indents.entry(report.thread_key()).or_default();
+        let line = match &report.payload {
+            ReportPayload::OnNewSpan { attrs, id } => {
+                self.spans.insert(
+                    (report.source, id.id),
+                    Location::of(&attrs.metadata).to_string(),
+                );
+                return None;
+            },
+            ReportPayload::OnEvent { event } => format!(
+                "{:width$}[EVENT | {}]",
+                "",
+                Location::of(&event.metadata),
+                width = *indent,
+            ),
+            ReportPayload::OnEnter { span } => {
+                let line = format!(
+                    "{:width$}[SPAN | {}]",
+                    "",
+                    self.spans.get(&(report.source, span.id)).map_or("???", String::as_str),
+                    width = *indent,
                 );
+                *indent += 2;
+                line
+            },
+            ReportPayload::OnExit { .. } => {
+                *indent = indent.saturating_sub(2);
+                format!("{:width$}<-", "", width = *indent)
+            },
+            ReportPayload::Marker { name } => format!("==== {} ====", name),
+            _ => return None,
+        };
+        Some(format!(" {:016} | {:>8} |{}", report.tick, report.thread_key().to_string(), line))
+    }
+}
 
-                if !chunky.contains_key(&key) {
-                    chunky.insert(key.clone(), vec![]);
-                }
-                chunky.get_mut(&key).unwrap().push(event.fields.to_owned());
-            }
+fn print_histogram(elements: &Elements, bucket: Duration, include_spans: bool) {
+    fn print_buckets(buckets: &BTreeMap<u64, usize>) {
+        for (start, count) in buckets.iter() {
+            println!(" {:016} | {}", start, count);
+        }
+        println!();
+    }
 
+    println!("ALL THREADS");
+    print_buckets(&elements.event_histogram(bucket, include_spans));
 
-        });
+    for (thread_id, elements) in elements.split_by_thread_id().iter() {
+        println!("THREAD {}", thread_id);
+        print_buckets(&elements.event_histogram(bucket, include_spans));
+    }
+}
 
-        chunky
-            .drain()
-            .collect()
+fn print_shapes(elements: &Elements) {
+    for (key, shape, count) in elements.events_by_shape().iter() {
+        println!("{} | {{{}}} | {} INSTANCES", key, shape.join(", "), count);
     }
 }
 
-impl<'a> From<Report<'a>> for Element {
-    fn from(other: Report<'a>) -> Self {
-        Self {
-            rpt: Rc::new(other.to_owned()),
+fn print_span_events(elements: &Elements, predicate: impl Fn(&tss::SerializeAttributes) -> bool) {
+    let names: HashMap<SpanKey, &str> = elements
+        .rpts
+        .iter()
+        .filter_map(|rpt| match &rpt.payload {
+            ReportPayload::OnNewSpan { attrs, id } => Some(((rpt.source, id.id), attrs.metadata.name.as_str())),
+            _ => None,
+        })
+        .collect();
+
+    for (span, rpt) in elements.events_in_span(predicate).iter() {
+        let ReportPayload::OnEvent { event } = &rpt.payload else {
+            continue;
+        };
+        print!(
+            "{} | thread {} | {}#{} | {} |",
+            rpt.tick,
+            rpt.thread_key(),
+            names.get(span).copied().unwrap_or("???"),
+            span.1,
+            Location::of(&event.metadata),
+        );
+        if let tss::SerializeRecordFields::De(fields) = &event.fields {
+            let mut fields: Vec<_> = fields.iter().collect();
+            fields.sort_unstable_by_key(|(key, _)| key.as_str());
+            for (key, val) in fields.iter() {
+                print!(" {} = {},", key.as_str(), val.display());
+            }
         }
+        println!();
     }
 }
 
-fn main() {
-    let mut file = File::open("report.bin").unwrap();
-    let mut contents = vec![];
-    file.read_to_end(&mut contents).unwrap();
-    let data: Vec<Element> = contents
-        .split_mut(|&b| b == 0)
-        .map(postcard::from_bytes_cobs::<tracing_report::Report>)
-        .filter_map(Result::ok)
-        .map(Into::into)
+fn print_gaps(elements: &Elements, threshold: Duration) {
+    let names: HashMap<SpanKey, &str> = elements
+        .rpts
+        .iter()
+        .filter_map(|rpt| match &rpt.payload {
+            ReportPayload::OnNewSpan { attrs, id } => Some(((rpt.source, id.id), attrs.metadata.name.as_str())),
+            _ => None,
+        })
         .collect();
+    let describe = |rpt: &Element| -> String {
+        let name = |id: &tss::SerializeId| names.get(&(rpt.source, id.id)).copied().unwrap_or("???");
+        match &rpt.payload {
+            ReportPayload::OnNewSpan { attrs, .. } => format!("new span {}", attrs.metadata.name.as_str()),
+            ReportPayload::OnRecord { span, .. } => format!("record {}", name(span)),
+            ReportPayload::OnFollowsFrom { span, follows } => format!("{} follows from {}", name(span), name(follows)),
+            ReportPayload::OnEvent { event } => format!(
+                "event {}",
+                Location::of(&event.metadata),
+            ),
+            ReportPayload::OnEnter { span } => format!("enter {}", name(span)),
+            ReportPayload::OnExit { span } => format!("exit {}", name(span)),
+            ReportPayload::OnIdChange { old, .. } => format!("id change {}", name(old)),
+            ReportPayload::OnClose { span, .. } => format!("close {}", name(span)),
+            ReportPayload::Marker { name } => format!("marker {}", name),
+            ReportPayload::ThreadName { name } => format!("thread name {}", name),
+            ReportPayload::Heartbeat { .. } => "heartbeat".to_string(),
+            ReportPayload::CpuTime { span, .. } => format!("cpu time {}", name(span)),
+        }
+    };
 
-    let elements = Elements { rpts: data };
+    for gap in elements.find_gaps(threshold).iter() {
+        let heartbeats = match gap.heartbeats {
+            Some(0) => " | no heartbeats".to_string(),
+            Some(n) => format!(" | {} heartbeats", n),
+            None => String::new(),
+        };
+        println!(
+            "{}ns | thread {} | {} @ {} -> {} @ {}{}",
+            gap.duration(),
+            gap.thread,
+            describe(&gap.before),
+            gap.before.tick,
+            describe(&gap.after),
+            gap.after.tick,
+            heartbeats,
+        );
+    }
+}
 
-    let by_thread = elements.split_by_thread_id();
+fn print_csv(elements: &Elements, messages: bool) {
+    fn escape(field: &str) -> String {
+        if field.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.to_string()
+        }
+    }
 
-    for (thread_id, elements) in by_thread.iter() {
-        println!("THREAD {}", thread_id);
-        println!();
+    let events: Vec<(&Element, &tss::SerializeEvent<'static>)> = elements
+        .rpts
+        .iter()
+        .filter_map(|rpt| match &rpt.payload {
+            ReportPayload::OnEvent { event } => Some((rpt, event)),
+            _ => None,
+        })
+        .collect();
 
-        let tl_span = elements.spanner();
-        for span in tl_span.spans.iter() {
-            let (oevt, cevt) = span.count_events_rec();
-            println!(
-                "[SPAN | {}ns | {}:{}] ({} events, {} child events)",
-                span.end - span.start,
-                span.attrs.metadata.file.as_deref().unwrap_or("???"),
-                span.attrs.metadata.line.unwrap_or(0),
-                oevt,
-                cevt,
-            );
-            span.print_spans_rec(4, 2);
-        }
-    }
-
-    // for (thread_id, elements) in by_thread.iter() {
-    //     println!("THREAD {}", thread_id);
-    //     let mut ebl = elements.events_by_location();
-    //     ebl.sort_unstable_by_key(|(key, _evts)| key.clone());
-    //     for (key, events) in ebl.iter() {
-    //         println!("{} | {} | {} INSTANCES", thread_id, key, events.len());
-    //         for rec in events.iter() {
-    //             print!("    |> ");
-    //             match rec {
-    //                 tss::SerializeRecordFields::Ser(_) => panic!(),
-    //                 tss::SerializeRecordFields::De(fields) => {
-    //                     let mut data: Vec<(&CowString, &tss::SerializeValue)> = fields.iter().collect();
-    //                     data.sort_unstable_by_key(|(key, _val)| key.as_str());
-    //                     for (key, val) in data.iter() {
-    //                         print!("{} = ", key.as_str());
-    //                         match val {
-    //                             tss::SerializeValue::Debug(tss::DebugRecord::De(x)) => print!("{}, ", x.as_str()),
-    //                             tss::SerializeValue::Str(x) => print!("{}, ", x.as_str()),
-    //                             tss::SerializeValue::F64(x) => print!("{}, ", x),
-    //                             tss::SerializeValue::I64(x) => print!("{}, ", x),
-    //                             tss::SerializeValue::U64(x) => print!("{}, ", x),
-    //                             tss::SerializeValue::Bool(x) => print!("{}, ", x),
-    //                             _ => print!("???, "),
-    //                         }
-    //                     }
-    //                 },
-    //             }
-    //             println!("|");
-    //         }
-    //     }
-    //     for _ in 0..3 {
-    //         println!();
-    //     }
-    // }
-
-
-
-    // for (thread_id, reports) in by_thread.iter() {
-    //     let mut spans = HashMap::new();
-    //     reports.rpts.iter().for_each(|rpt| {
-    //         if let ReportPayload::OnNewSpan { attrs, id } = &rpt.payload {
-    //             spans.insert(
-    //                 id.id,
-    //                 format!(
-    //                     "[SPAN | {}:{}]",
-    //                     attrs.metadata.file.as_deref().unwrap_or("???"),
-    //                     attrs.metadata.line.unwrap_or(0),
-    //                 )
-    //             );
-    //         }
-    //     });
-
-    //     let mut indent = 0;
-
-    //     println!("============================================================");
-    //     println!("THREAD {}", thread_id);
-    //     println!("============================================================");
-
-    //     for report in reports.rpts.iter() {
-    //         match &report.payload {
-    //             ReportPayload::OnEvent { event } => {
-    //                 print!(" {:016} |", report.tick);
-    //                 for _ in 0..indent {
-    //                     print!(" ");
-    //                 }
-    //                 println!(
-    //                     "[EVENT | {}:{}]",
-    //                     event.metadata.file.as_deref().unwrap_or("???"),
-    //                     event.metadata.line.unwrap_or(0),
-    //                 );
-    //             },
-    //             ReportPayload::OnEnter { span } => {
-    //                 print!(" {:016} |", report.tick);
-    //                 for _ in 0..indent {
-    //                     print!(" ");
-    //                 }
-    //                 if spans.contains_key(&span.id) {
-    //                     println!("{}", spans[&span.id]);
-    //                 } else {
-    //                     println!("[SPAN | ???]");
-    //                 }
-    //                 indent += 2;
-    //             },
-    //             ReportPayload::OnExit { .. } => {
-    //                 print!(" {:016} |", report.tick);
-    //                 indent -= 2;
-    //                 for _ in 0..indent {
-    //                     print!(" ");
-    //                 }
-    //                 println!("<-");
-    //             },
-    //             _ => {},
-    //         }
-    //     }
-
-    //     for _ in 0..5 {
-    //         println!();
-    //     }
-    // }
+    // Different events carry different fields, so the header is the union.
+    let mut columns: Vec<&str> = events
+        .iter()
+        .filter_map(|(_, event)| match &event.fields {
+            tss::SerializeRecordFields::De(fields) => Some(fields.keys().map(|k| k.as_str())),
+            tss::SerializeRecordFields::Ser(_) => None,
+        })
+        .flatten()
+        .collect();
+    columns.sort_unstable();
+    columns.dedup();
+    if messages {
+        if let Some(i) = columns.iter().position(|c| *c == "message") {
+            columns.remove(i);
+            columns.insert(0, "message");
+        }
+    }
+
+    let mut header = vec!["tick", "thread_id", "file", "line", "level"];
+    header.extend(columns.iter());
+    println!("{}", header.iter().map(|h| escape(h)).collect::<Vec<_>>().join(","));
+
+    for (rpt, event) in events.iter() {
+        let mut row = vec![
+            rpt.tick.to_string(),
+            rpt.thread_key().to_string(),
+            escape(event.metadata.file.as_deref().unwrap_or("")),
+            event.metadata.line.map(|l| l.to_string()).unwrap_or_default(),
+            level_str(event.metadata.level).to_string(),
+        ];
+        for column in columns.iter() {
+            let value = match &event.fields {
+                tss::SerializeRecordFields::De(fields) => fields.get(&tss::CowString::Borrowed(column)),
+                tss::SerializeRecordFields::Ser(_) => None,
+            };
+            row.push(value.map(|v| escape(&v.display().to_string())).unwrap_or_default());
+        }
+        println!("{}", row.join(","));
+    }
+}
+
+/// Each thread is a participant, with its entered spans drawn as activations.
+/// Follows-from edges go from the thread that last entered the followed span
+/// to the thread that last entered the follower.
+fn write_mermaid<W: Write>(elements: &Elements, w: &mut W) -> io::Result<()> {
+    fn participant(key: ThreadKey) -> String {
+        format!("T{}_{}", key.source, key.thread_id)
+    }
+
+    // `;` and `#` end a statement and start an entity code, respectively.
+    fn label(name: &str) -> String {
+        name.replace([';', '#', '\n'], " ")
+    }
+
+    let mut threads: Vec<ThreadKey> = elements.rpts.iter().map(Element::thread_key).collect();
+    threads.sort_unstable();
+    threads.dedup();
+
+    writeln!(w, "sequenceDiagram")?;
+    for key in threads.iter() {
+        writeln!(w, "    participant {} as thread {}", participant(*key), key)?;
+    }
+
+    let mut names: HashMap<SpanKey, String> = HashMap::new();
+    let mut owners: HashMap<SpanKey, ThreadKey> = HashMap::new();
+    let mut active: HashMap<ThreadKey, usize> = HashMap::new();
+    for rpt in elements.rpts.iter() {
+        let thread = rpt.thread_key();
+        let me = participant(thread);
+        let name = |id: &tss::SerializeId| {
+            names.get(&(rpt.source, id.id)).cloned().unwrap_or_else(|| format!("span {}", id.id))
+        };
+        match &rpt.payload {
+            ReportPayload::OnNewSpan { attrs, id } => {
+                names.insert((rpt.source, id.id), label(attrs.metadata.name.as_str()));
+                owners.insert((rpt.source, id.id), thread);
+            },
+            ReportPayload::OnEnter { span } => {
+                writeln!(w, "    {}->>+{}: enter {}", me, me, name(span))?;
+                owners.insert((rpt.source, span.id), thread);
+                *active.entry(thread).or_default() += 1;
+            },
+            ReportPayload::OnExit { span } => {
+                // Mermaid rejects deactivating a participant that isn't active.
+                let depth = active.entry(thread).or_default();
+                if *depth > 0 {
+                    *depth -= 1;
+                    writeln!(w, "    {}-->>-{}: exit {}", me, me, name(span))?;
+                } else {
+                    writeln!(w, "    {}-->>{}: exit {}", me, me, name(span))?;
+                }
+            },
+            ReportPayload::OnFollowsFrom { span, follows } => {
+                let from = owners.get(&(rpt.source, follows.id)).copied().unwrap_or(thread);
+                let to = owners.get(&(rpt.source, span.id)).copied().unwrap_or(thread);
+                writeln!(
+                    w,
+                    "    {}-){}: {} follows from {}",
+                    participant(from),
+                    participant(to),
+                    name(span),
+                    name(follows),
+                )?;
+            },
+            _ => {},
+        }
+    }
+    w.flush()
+}
+
+fn level_str(level: tss::SerializeLevel) -> &'static str {
+    match level {
+        tss::SerializeLevel::Trace => "TRACE",
+        tss::SerializeLevel::Debug => "DEBUG",
+        tss::SerializeLevel::Info => "INFO",
+        tss::SerializeLevel::Warn => "WARN",
+        tss::SerializeLevel::Error => "ERROR",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_need_a_unit() {
+        assert_eq!(parse_duration("250ns"), Ok(Duration::from_nanos(250)));
+        assert_eq!(parse_duration("3us"), Ok(Duration::from_micros(3)));
+        assert_eq!(parse_duration("10ms"), Ok(Duration::from_millis(10)));
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        for invalid in ["", "10", "ms", "1.5s", "-1s", "10m", "10 ms"] {
+            assert!(parse_duration(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn categories() {
+        assert_eq!(parse_category("target"), Ok(CategoryBy::Target));
+        assert_eq!(parse_category("location"), Ok(CategoryBy::Location));
+        assert_eq!(parse_category("field:kind"), Ok(CategoryBy::Field("kind".to_string())));
+        assert_eq!(parse_category("field:a:b"), Ok(CategoryBy::Field("a:b".to_string())));
+        for invalid in ["", "field", "field:", "target:x", "module"] {
+            assert!(parse_category(invalid).is_err(), "{:?}", invalid);
+        }
+    }
 }
@@ -0,0 +1,151 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::fd::AsRawFd,
+    path::Path,
+    ptr, slice,
+};
+
+/// What an mmap sink does when its region is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MmapOverflow {
+    /// Double the file, and map it again. Nothing is lost.
+    #[default]
+    Grow,
+    /// Start over right after the header, overwriting the oldest frames.
+    /// The file then holds the newest frames first, followed by what's left
+    /// of the older ones; `Elements::timeline` puts them back in order.
+    Wrap,
+}
+
+/// Smallest region mapped, so the header and callsites always fit.
+const MIN_LEN: usize = 64 * 1024;
+
+/// A file mapped into memory, which frames are copied into. The kernel
+/// writes the pages back on its own schedule.
+pub(crate) struct Mmap {
+    file: File,
+    ptr: *mut u8,
+    len: usize,
+    pos: usize,
+    /// Where frames start, after the header.
+    start: usize,
+    overflow: MmapOverflow,
+    /// Whether `Wrap` has happened, so frames overwrite older ones.
+    wrapped: bool,
+}
+
+// The mapping is only ever accessed through `&mut self`.
+unsafe impl Send for Mmap {}
+
+impl Mmap {
+    /// Creates (or truncates) the file, sized to `len`, and writes the header.
+    pub(crate) fn create(path: &Path, len: usize, overflow: MmapOverflow, header: &[u8]) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let len = len.max(MIN_LEN);
+        let mut map = Self {
+            ptr: map(&file, len)?,
+            file,
+            len,
+            pos: 0,
+            start: header.len(),
+            overflow,
+            wrapped: false,
+        };
+        map.put(header);
+        Ok(map)
+    }
+
+    /// Copies in one or more whole frames. On `Wrap`, `callsites` gives all
+    /// callsite frames, which are written again first, since the originals
+    /// are about to be overwritten. Returns `false` if `frames` can't fit.
+    pub(crate) fn append(&mut self, frames: &[u8], callsites: impl FnOnce() -> io::Result<Vec<u8>>) -> io::Result<bool> {
+        if self.pos + frames.len() > self.len {
+            match self.overflow {
+                MmapOverflow::Grow => self.grow(self.pos + frames.len())?,
+                MmapOverflow::Wrap => {
+                    let callsites = callsites()?;
+                    if self.start + callsites.len() > self.len {
+                        return Err(io::Error::other("mmap region is too small for the callsites"));
+                    }
+                    self.wrap();
+                    self.put(&callsites);
+                    if self.pos + frames.len() > self.len {
+                        return Ok(false);
+                    }
+                },
+            }
+        }
+        self.put(frames);
+        Ok(true)
+    }
+
+    fn put(&mut self, bytes: &[u8]) {
+        let end = self.pos + bytes.len();
+        let region = unsafe { slice::from_raw_parts_mut(self.ptr, self.len) };
+        // Whatever is left of a frame cut in two is zeroed, leaving empty
+        // frames that readers skip, rather than a corrupt one.
+        let cut = self.wrapped && end < self.len && end > 0 && region[end - 1] != 0;
+        region[self.pos..end].copy_from_slice(bytes);
+        if cut {
+            let rest = &mut region[end..];
+            let n = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+            rest[..n].fill(0);
+        }
+        self.pos = end;
+    }
+
+    fn wrap(&mut self) {
+        let region = unsafe { slice::from_raw_parts_mut(self.ptr, self.len) };
+        region[self.pos..].fill(0);
+        self.pos = self.start;
+        self.wrapped = true;
+    }
+
+    fn grow(&mut self, min: usize) -> io::Result<()> {
+        let len = (self.len * 2).max(min);
+        let ptr = map(&self.file, len)?;
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+        self.ptr = ptr;
+        self.len = len;
+        Ok(())
+    }
+
+    /// Starts writing back dirty pages, without waiting for it.
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        match unsafe { libc::msync(self.ptr.cast(), self.len, libc::MS_ASYNC) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+/// Sizes `file` to `len`, and maps all of it.
+fn map(file: &File, len: usize) -> io::Result<*mut u8> {
+    file.set_len(len as u64)?;
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    match ptr {
+        libc::MAP_FAILED => Err(io::Error::last_os_error()),
+        ptr => Ok(ptr.cast()),
+    }
+}
@@ -0,0 +1,121 @@
+//! Deserializers for the `tss` fields of analysis results. `tss` can only
+//! deserialize strings it can borrow, so these read into owned stand-ins
+//! instead.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing_serde_structured::{self as tss, CowString, DebugRecord, SerializeValue};
+
+#[derive(Deserialize)]
+struct Metadata {
+    name: String,
+    target: String,
+    level: tss::SerializeLevel,
+    module_path: Option<String>,
+    file: Option<String>,
+    line: Option<u32>,
+    fields: Vec<String>,
+    is_span: bool,
+    is_event: bool,
+}
+
+impl From<Metadata> for tss::SerializeMetadata<'static> {
+    fn from(m: Metadata) -> Self {
+        tss::SerializeMetadata {
+            name: CowString::Owned(m.name),
+            target: CowString::Owned(m.target),
+            level: m.level,
+            module_path: m.module_path.map(CowString::Owned),
+            file: m.file.map(CowString::Owned),
+            line: m.line,
+            fields: tss::SerializeFieldSet::De(m.fields.into_iter().map(CowString::Owned).collect()),
+            is_span: m.is_span,
+            is_event: m.is_event,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Attributes {
+    metadata: Metadata,
+    parent: Option<tss::SerializeId>,
+    is_root: bool,
+}
+
+#[derive(Deserialize)]
+enum Value {
+    Debug(String),
+    Str(String),
+    F64(f64),
+    I64(i64),
+    U64(u64),
+    Bool(bool),
+}
+
+impl From<Value> for SerializeValue<'static> {
+    fn from(v: Value) -> Self {
+        match v {
+            Value::Debug(s) => SerializeValue::Debug(DebugRecord::De(CowString::Owned(s))),
+            Value::Str(s) => SerializeValue::Str(CowString::Owned(s)),
+            Value::F64(x) => SerializeValue::F64(x),
+            Value::I64(x) => SerializeValue::I64(x),
+            Value::U64(x) => SerializeValue::U64(x),
+            Value::Bool(x) => SerializeValue::Bool(x),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Event {
+    fields: HashMap<String, Value>,
+    metadata: Metadata,
+    parent: Option<tss::SerializeId>,
+}
+
+impl From<Event> for tss::SerializeEvent<'static> {
+    fn from(e: Event) -> Self {
+        tss::SerializeEvent {
+            fields: tss::SerializeRecordFields::De(
+                e.fields.into_iter().map(|(k, v)| (CowString::Owned(k), v.into())).collect(),
+            ),
+            metadata: e.metadata.into(),
+            parent: e.parent,
+        }
+    }
+}
+
+pub(crate) mod attrs {
+    use super::*;
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<tss::SerializeAttributes<'static>, D::Error> {
+        let a = Attributes::deserialize(d)?;
+        Ok(tss::SerializeAttributes {
+            metadata: a.metadata.into(),
+            parent: a.parent,
+            is_root: a.is_root,
+        })
+    }
+}
+
+pub(crate) mod events {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(events: &[tss::SerializeEvent<'static>], s: S) -> Result<S::Ok, S::Error> {
+        events.serialize(s)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<tss::SerializeEvent<'static>>, D::Error> {
+        let events = Vec::<Event>::deserialize(d)?;
+        Ok(events.into_iter().map(Into::into).collect())
+    }
+}
+
+pub(crate) mod callsites {
+    use super::*;
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<(u64, tss::SerializeMetadata<'static>)>, D::Error> {
+        let callsites = Vec::<(u64, Metadata)>::deserialize(d)?;
+        Ok(callsites.into_iter().map(|(id, m)| (id, m.into())).collect())
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::VecDeque,
+    sync::{mpsc::RecvTimeoutError, Condvar, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+/// What to do with a new report when the queue to the writer thread is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    /// Wait for the writer to catch up, so nothing is lost. The instrumented
+    /// thread stalls in the meantime.
+    #[default]
+    Block,
+    /// Discard the new report.
+    DropNewest,
+    /// Discard the oldest queued report to make room, keeping the freshest data.
+    DropOldest,
+}
+
+/// A bounded multi-producer, single-consumer queue that supports all three
+/// drop policies. It's bounded by item count, or by the total length of the
+/// items queued.
+pub(crate) struct Queue<T> {
+    state: Mutex<State<T>>,
+    readable: Condvar,
+    writable: Condvar,
+    capacity: Capacity,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Capacity {
+    Items(usize),
+    Bytes(usize),
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    bytes: usize,
+    closed: bool,
+}
+
+impl<T: AsRef<[u8]>> State<T> {
+    /// Whether an item of `len` bytes has to wait. An item larger than the
+    /// whole byte capacity still goes into an empty queue.
+    fn is_full(&self, capacity: Capacity, len: usize) -> bool {
+        match capacity {
+            Capacity::Items(max) => self.items.len() >= max,
+            Capacity::Bytes(max) => !self.items.is_empty() && self.bytes + len > max,
+        }
+    }
+
+    fn pop_front(&mut self) {
+        if let Some(item) = self.items.pop_front() {
+            self.bytes -= item.as_ref().len();
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> Queue<T> {
+    pub(crate) fn new(capacity: Capacity) -> Self {
+        let items = match capacity {
+            Capacity::Items(max) => VecDeque::with_capacity(max),
+            Capacity::Bytes(_) => VecDeque::new(),
+        };
+        Self {
+            state: Mutex::new(State {
+                items,
+                bytes: 0,
+                closed: false,
+            }),
+            readable: Condvar::new(),
+            writable: Condvar::new(),
+            capacity,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns how many reports were dropped: `item`, or (with
+    /// `DropOldest`) as many already queued as it took to make room.
+    pub(crate) fn push(&self, item: T, policy: DropPolicy) -> usize {
+        let len = item.as_ref().len();
+        let mut state = self.lock();
+        let mut dropped = 0;
+        if state.is_full(self.capacity, len) {
+            match policy {
+                DropPolicy::Block => {
+                    state = self
+                        .writable
+                        .wait_while(state, |s| s.is_full(self.capacity, len) && !s.closed)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+                DropPolicy::DropNewest => return 1,
+                DropPolicy::DropOldest => {
+                    while state.is_full(self.capacity, len) {
+                        state.pop_front();
+                        dropped += 1;
+                    }
+                }
+            }
+        }
+        state.bytes += len;
+        state.items.push_back(item);
+        drop(state);
+        self.readable.notify_one();
+        dropped
+    }
+
+    /// Waits up to `timeout` for something to be queued, then moves as many
+    /// items as are queued, up to `max` in total, into `batch`.
+    pub(crate) fn recv_batch(&self, batch: &mut Vec<T>, max: usize, timeout: Duration) -> Result<(), RecvTimeoutError> {
+        let (mut state, _) = self
+            .readable
+            .wait_timeout_while(self.lock(), timeout, |s| s.items.is_empty() && !s.closed)
+            .unwrap_or_else(PoisonError::into_inner);
+        if state.items.is_empty() {
+            return match state.closed {
+                true => Err(RecvTimeoutError::Disconnected),
+                false => Err(RecvTimeoutError::Timeout),
+            };
+        }
+        let n = state.items.len().min(max.saturating_sub(batch.len()));
+        let start = batch.len();
+        batch.extend(state.items.drain(..n));
+        state.bytes -= batch[start..].iter().map(|item| item.as_ref().len()).sum::<usize>();
+        drop(state);
+        self.writable.notify_all();
+        Ok(())
+    }
+
+    /// Called when the producer goes away. The consumer drains what's left,
+    /// then sees `Disconnected`.
+    pub(crate) fn close(&self) {
+        self.lock().closed = true;
+        self.readable.notify_all();
+        self.writable.notify_all();
+    }
+}
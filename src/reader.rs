@@ -0,0 +1,516 @@
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom};
+
+use serde::{Deserialize, Serialize};
+use tracing_serde_structured as tss;
+
+use crate::{
+    callsite::Frame,
+    compat::{ReportV1, ReportV2, ReportV3},
+    format::{Compression, Encoding, Framing, Header, Source, VERSION},
+    registry::Callsites,
+    Report, ReportError,
+};
+
+/// Decodes a stream of reports, one frame at a time.
+pub struct ReportReader<'a> {
+    inner: Box<dyn BufRead + 'a>,
+    header: Option<Header>,
+    buf: Vec<u8>,
+    frames: u64,
+    callsites: Callsites,
+    /// Bytes read from the input so far, for uncompressed streams.
+    offset: Option<u64>,
+    /// Bytes the last frame read took, length included.
+    last_frame: u64,
+}
+
+/// Where a `ReportReader` is in an uncompressed stream, and the callsites
+/// defined before that point, which later frames can refer to. It
+/// serializes, so e.g. a tail tool can save it and `resume` after a restart.
+#[derive(Serialize, Deserialize)]
+pub struct Position {
+    offset: u64,
+    frames: u64,
+    framing: Framing,
+    /// Bytes the frame right before `offset` took, to check on `resume`
+    /// that `offset` is still where a frame ends.
+    last_frame: u64,
+    #[serde(deserialize_with = "crate::owned::callsites::deserialize")]
+    callsites: Vec<(u64, tss::SerializeMetadata<'static>)>,
+}
+
+impl Position {
+    /// Bytes from the start of the stream, header included.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl<'a> ReportReader<'a> {
+    /// Reads the stream header (if any) and sets up decompression. A
+    /// gzipped stream, e.g. an archived `report.bin.gz`, is unzipped first.
+    pub fn new<R: Read + 'a>(mut inner: R) -> Result<Self, ReportError> {
+        let mut prefix = vec![];
+        (&mut inner)
+            .take(Header::LEN as u64)
+            .read_to_end(&mut prefix)?;
+
+        if prefix.starts_with(&GZIP_MAGIC) {
+            let mut reader = Self::new(gunzip(Cursor::new(prefix).chain(inner))?)?;
+            reader.offset = None;
+            return Ok(reader);
+        }
+
+        let (inner, header, offset): (Box<dyn BufRead + 'a>, _, _) = match Header::parse(&prefix) {
+            Some(header) => {
+                let mut header = header?;
+                let mut rest = Counting { inner: &mut inner, n: 0 };
+                header.read_rest(&mut rest)?;
+                let offset = match header.compression {
+                    Compression::None => Some(prefix.len() as u64 + rest.n),
+                    _ => None,
+                };
+                (open_body(inner, header.compression)?, Some(header), offset)
+            }
+            None => (Box::new(BufReader::new(Cursor::new(prefix).chain(inner))), None, Some(0)),
+        };
+
+        Ok(Self {
+            inner,
+            header,
+            buf: Vec::new(),
+            frames: 0,
+            callsites: Callsites::default(),
+            offset,
+            last_frame: 0,
+        })
+    }
+
+    /// Picks up reading another reader of the same stream left off at
+    /// `position`, e.g. after a restart: the header is read again, and then
+    /// the input is seeked straight to `position`, with nothing in between
+    /// read. If the stream was rewritten since, so that the position falls
+    /// inside a frame, what's left of that frame is yielded as a decode
+    /// error with COBS framing, which readers skip anyway. With length
+    /// prefixes, the frame before the position is read again to check that
+    /// it still ends there, and resuming fails if it doesn't, since a
+    /// length read from the middle of a frame would throw off every frame
+    /// after it.
+    ///
+    /// Compressed streams can't be resumed, nor can a stream with different
+    /// framing than the one `position` is from.
+    pub fn resume<R: Read + Seek + 'a>(mut inner: R, position: &Position) -> Result<Self, ReportError> {
+        inner.seek(SeekFrom::Start(0))?;
+        let (header, header_end) = {
+            let reader = ReportReader::new(&mut inner)?;
+            (reader.header, reader.offset)
+        };
+        let Some(header_end) = header_end else {
+            return Err(ReportError::Io(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "a compressed stream can't be resumed",
+            )));
+        };
+        let framing = header.as_ref().map_or(Framing::Cobs, |h| h.framing);
+        if framing != position.framing {
+            return Err(ReportError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the position is from a stream with different framing",
+            )));
+        }
+        let offset = position.offset.max(header_end);
+        if framing == Framing::LengthPrefixed && offset > header_end {
+            let at_boundary = match offset.checked_sub(position.last_frame) {
+                Some(start) if start >= header_end => {
+                    inner.seek(SeekFrom::Start(start))?;
+                    matches!(
+                        read_prefixed(&mut BufReader::new(&mut inner), &mut vec![]),
+                        Ok(n) if n as u64 == position.last_frame
+                    )
+                }
+                _ => false,
+            };
+            if !at_boundary {
+                return Err(ReportError::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the position isn't where a frame ends; the stream was rewritten",
+                )));
+            }
+        }
+        inner.seek(SeekFrom::Start(offset))?;
+        Ok(Self {
+            inner: Box::new(BufReader::new(inner)),
+            header,
+            buf: Vec::new(),
+            frames: position.frames,
+            callsites: Callsites::from_entries(position.callsites.iter().map(|(id, m)| (*id, m.to_owned()))),
+            offset: Some(offset),
+            last_frame: position.last_frame,
+        })
+    }
+
+    /// Where in the input the frames read so far end. `None` for compressed
+    /// streams.
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    /// Where the frames read so far end, to `resume` from later. `None` for
+    /// compressed streams.
+    pub fn position(&self) -> Option<Position> {
+        Some(Position {
+            offset: self.offset?,
+            frames: self.frames,
+            framing: self.header.as_ref().map_or(Framing::Cobs, |h| h.framing),
+            last_frame: self.last_frame,
+            callsites: self.callsites.entries(),
+        })
+    }
+
+    /// The stream header, or `None` for streams written before headers existed.
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    /// Whether the stream was written by a newer version of the format than
+    /// this reader's. It may contain frames this reader can't decode.
+    pub fn is_newer(&self) -> bool {
+        self.header.as_ref().is_some_and(|h| h.version > VERSION)
+    }
+
+    /// The process that wrote the stream, for streams of version 5 or later.
+    pub fn source(&self) -> Option<&Source> {
+        self.header.as_ref()?.source.as_ref()
+    }
+}
+
+fn open_body<'a, R: Read + 'a>(inner: R, compression: Compression) -> io::Result<Box<dyn BufRead + 'a>> {
+    match compression {
+        Compression::None => Ok(Box::new(BufReader::new(inner))),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok(Box::new(BufReader::new(zstd::Decoder::new(inner)?))),
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "stream is zstd-compressed, but the `zstd` feature is disabled",
+        )),
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[cfg(feature = "gzip")]
+fn gunzip<'a, R: Read + 'a>(inner: R) -> io::Result<Box<dyn Read + 'a>> {
+    // `gzip` appends a member per invocation, so read them all.
+    Ok(Box::new(flate2::read::MultiGzDecoder::new(inner)))
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gunzip<'a, R: Read + 'a>(_inner: R) -> io::Result<Box<dyn Read + 'a>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "stream is gzip-compressed, but the `gzip` feature is disabled",
+    ))
+}
+
+/// Counts the bytes read through it.
+struct Counting<R> {
+    inner: R,
+    n: u64,
+}
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.n += n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads a varint length and that many bytes into `buf`. Returns how many
+/// bytes that took in all, or 0 at the end of the stream.
+fn read_prefixed(r: &mut impl BufRead, buf: &mut Vec<u8>) -> Result<usize, ReportError> {
+    let mut len = 0u64;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0];
+        if r.read(&mut byte)? == 0 {
+            return match shift {
+                0 => Ok(0),
+                _ => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            };
+        }
+        let bits = u64::from(byte[0] & 0x7f);
+        // Past 64 bits, or bits of the tenth byte that don't fit.
+        if shift >= 64 || bits << shift >> shift != bits {
+            return Err(ReportError::Decode("frame length overflows".into()));
+        }
+        len |= bits << shift;
+        shift += 7;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    // Not `read_exact`, so a corrupt length doesn't allocate it all up front.
+    if r.take(len).read_to_end(buf)? as u64 != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(shift / 7 + len as usize)
+}
+
+pub(crate) fn decode(buf: &mut [u8], framing: Framing, encoding: Encoding) -> Result<Frame<'_>, ReportError> {
+    match encoding {
+        Encoding::Postcard => Ok(match framing {
+            Framing::Cobs => postcard::from_bytes_cobs(buf)?,
+            Framing::LengthPrefixed => postcard::from_bytes(buf)?,
+        }),
+        #[cfg(feature = "bincode")]
+        Encoding::Bincode => {
+            let len = match framing {
+                Framing::Cobs => cobs::decode_in_place(buf).map_err(|_| ReportError::Decode("invalid COBS frame".into()))?,
+                Framing::LengthPrefixed => buf.len(),
+            };
+            let buf: &[u8] = buf;
+            bincode::deserialize(&buf[..len]).map_err(|e| ReportError::Decode(e.to_string()))
+        }
+    }
+}
+
+impl Iterator for ReportReader<'_> {
+    /// Frames that fail to decode are yielded as `ReportError::Decode`, so
+    /// callers can skip them and keep reading.
+    type Item = Result<Report<'static>, ReportError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let framing = self.header.as_ref().map_or(Framing::Cobs, |h| h.framing);
+        let encoding = self.header.as_ref().map_or(Encoding::Postcard, |h| h.encoding);
+        loop {
+            self.buf.clear();
+            let read = match framing {
+                Framing::Cobs => self.inner.read_until(0, &mut self.buf).map_err(Into::into),
+                Framing::LengthPrefixed => read_prefixed(&mut self.inner, &mut self.buf),
+            };
+            match read {
+                Ok(0) => return None,
+                Ok(n) => {
+                    if let Some(offset) = self.offset.as_mut() {
+                        *offset += n as u64;
+                    }
+                    self.last_frame = n as u64;
+                }
+                // A compressed stream whose writer never got to `finish` (e.g. the
+                // process exited) ends mid-frame; everything before that is intact.
+                Err(ReportError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+                Err(e) => return Some(Err(e)),
+            }
+
+            // Every COBS frame, including the last, is followed by a `0`.
+            // Empty frames (two `0`s in a row, or a zero length) carry
+            // nothing, so skip them without counting them.
+            if framing == Framing::Cobs && self.buf.last() == Some(&0) {
+                self.buf.pop();
+            }
+            if self.buf.is_empty() {
+                continue;
+            }
+
+            let seq = self.frames;
+            self.frames += 1;
+
+            let version = self.header.as_ref().map(|h| h.version).unwrap_or(1);
+
+            let rpt = match version {
+                1 => postcard::from_bytes_cobs::<ReportV1>(&mut self.buf).map(|rpt| Some(rpt.upgrade(seq))).map_err(Into::into),
+                2 => postcard::from_bytes_cobs::<ReportV2>(&mut self.buf).map(|rpt| Some(rpt.upgrade())).map_err(Into::into),
+                3 => postcard::from_bytes_cobs::<ReportV3>(&mut self.buf).map(|rpt| Some(rpt.upgrade())).map_err(Into::into),
+                4 | 5 => postcard::from_bytes_cobs::<Report>(&mut self.buf).map(|rpt| Some(rpt.to_owned())).map_err(Into::into),
+                _ => match decode(&mut self.buf, framing, encoding) {
+                    Ok(frame) => match self.callsites.resolve(frame) {
+                        Ok(rpt) => Ok(rpt),
+                        Err(e) => return Some(Err(ReportError::Decode(e))),
+                    },
+                    Err(e) => Err(e),
+                },
+            };
+            match rpt {
+                Ok(Some(rpt)) => return Some(Ok(rpt)),
+                // A callsite definition; keep going.
+                Ok(None) => continue,
+                // Most likely a variant added after this reader's version.
+                Err(ReportError::Decode(e)) if self.is_newer() => {
+                    return Some(Err(ReportError::Decode(format!(
+                        "{} (the stream is from a newer format version)",
+                        e,
+                    ))))
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::{format::push_frame, Clock, ReportLayer, ReportPayload};
+
+    #[test]
+    fn prefixed_lengths_round_trip() {
+        for len in [1, 127, 128, 300, 16_383, 16_384, 100_000] {
+            let frame = vec![0xaa; len];
+            let mut buf = vec![];
+            push_frame(&mut buf, &frame, Framing::LengthPrefixed);
+            let mut read = vec![];
+            assert_eq!(read_prefixed(&mut &buf[..], &mut read).unwrap(), buf.len());
+            assert_eq!(read, frame);
+
+            let mut read = vec![];
+            let err = read_prefixed(&mut &buf[..buf.len() - 1], &mut read).unwrap_err();
+            assert!(matches!(err, ReportError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof));
+        }
+        assert_eq!(read_prefixed(&mut &[][..], &mut vec![]).unwrap(), 0);
+        for overflow in [&[0xff; 11][..], &[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x02]] {
+            assert!(matches!(read_prefixed(&mut &overflow[..], &mut vec![]), Err(ReportError::Decode(_))));
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Stopped;
+
+    impl Clock for Stopped {
+        fn now_nanos(&self) -> u64 {
+            0
+        }
+    }
+
+    fn trace() {
+        let outer = tracing::info_span!("outer", n = 1);
+        outer.in_scope(|| {
+            tracing::info!(k = "v", "inside");
+            tracing::debug_span!("inner", s = "x".repeat(200)).in_scope(|| tracing::warn!(big = 1u64 << 40));
+        });
+    }
+
+    fn written(framing: Framing, encoding: Encoding) -> Vec<u8> {
+        let out = SharedBuf::default();
+        let layer = ReportLayer::builder()
+            .sink(out.clone())
+            .framing(framing)
+            .encoding(encoding)
+            .synchronous()
+            .clock(Stopped)
+            .build();
+        let handle = layer.handle();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), trace);
+        handle.flush();
+        let bytes = out.0.lock().unwrap().clone();
+        bytes
+    }
+
+    /// Writes `trace` with `framing` and `encoding`, and checks it reads
+    /// back as what a capturing layer sees.
+    fn round_trip(framing: Framing, encoding: Encoding) {
+        let bytes = written(framing, encoding);
+        let (capturing, captured) = ReportLayer::builder().clock(Stopped).build_capturing();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(capturing), trace);
+
+        let mut reader = ReportReader::new(&bytes[..]).unwrap();
+        let header = reader.header().unwrap();
+        assert_eq!((header.framing, header.encoding), (framing, encoding));
+        let read: Vec<_> = reader.by_ref().map(|rpt| rpt.unwrap()).collect();
+        let expected = captured.take();
+        assert!(expected.len() > 10);
+        assert_eq!(read.len(), expected.len());
+        for (read, expected) in read.iter().zip(&expected) {
+            assert_eq!(read.thread_id, expected.thread_id);
+            assert_eq!(serde_json::to_value(&read.payload).unwrap(), serde_json::to_value(&expected.payload).unwrap());
+        }
+    }
+
+    #[test]
+    fn cobs_round_trips() {
+        round_trip(Framing::Cobs, Encoding::Postcard);
+    }
+
+    #[test]
+    fn length_prefixed_round_trips() {
+        round_trip(Framing::LengthPrefixed, Encoding::Postcard);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_round_trips() {
+        round_trip(Framing::Cobs, Encoding::Bincode);
+        round_trip(Framing::LengthPrefixed, Encoding::Bincode);
+    }
+
+    /// Reads a few reports, then resumes at the position right after them
+    /// and one byte further on, into the next frame.
+    fn resume_mid_frame(framing: Framing) -> Result<Vec<Result<Report<'static>, ReportError>>, ReportError> {
+        let bytes = written(framing, Encoding::Postcard);
+        let mut reader = ReportReader::new(&bytes[..]).unwrap();
+        reader.by_ref().take(3).for_each(|rpt| drop(rpt.unwrap()));
+        let mut position = reader.position().unwrap();
+        let json = |rpt: Result<Report, _>| serde_json::to_value(rpt.unwrap().payload).unwrap();
+        let rest: Vec<_> = reader.map(json).collect();
+
+        let resumed: Vec<_> = ReportReader::resume(Cursor::new(&bytes), &position).unwrap().map(json).collect();
+        assert_eq!(resumed, rest);
+
+        position.offset += 1;
+        Ok(ReportReader::resume(Cursor::new(bytes), &position)?.collect())
+    }
+
+    #[test]
+    fn resuming_mid_frame_skips_the_rest_of_a_cobs_frame() {
+        let resumed = resume_mid_frame(Framing::Cobs).unwrap();
+        assert!(matches!(resumed[0], Err(ReportError::Decode(_))));
+        assert!(resumed.iter().filter(|rpt| rpt.is_ok()).count() > 1);
+    }
+
+    #[test]
+    fn resuming_mid_frame_fails_with_length_prefixes() {
+        let err = resume_mid_frame(Framing::LengthPrefixed).unwrap_err();
+        assert!(matches!(err, ReportError::Io(e) if e.kind() == io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn resuming_with_other_framing_fails() {
+        let cobs = written(Framing::Cobs, Encoding::Postcard);
+        let position = ReportReader::new(&cobs[..]).unwrap().position().unwrap();
+        let prefixed = written(Framing::LengthPrefixed, Encoding::Postcard);
+        let err = ReportReader::resume(Cursor::new(prefixed), &position).err().unwrap();
+        assert!(matches!(err, ReportError::Io(e) if e.kind() == io::ErrorKind::InvalidInput));
+    }
+
+    #[test]
+    fn full_frames_decode_on_their_own() {
+        let rpt = Report {
+            tick: 5,
+            seq: 6,
+            thread_id: 7,
+            payload: ReportPayload::Marker { name: "m".to_string() },
+        };
+        let mut frame = rpt.to_cobs_frame();
+        assert_eq!(frame.pop(), Some(0));
+        let read = Report::from_cobs_frame(&mut frame).unwrap();
+        assert_eq!(format!("{:?}", read), format!("{:?}", rpt));
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        PoisonError, RwLock,
+    },
+};
+
+use tracing::{callsite::Identifier, Metadata};
+use tracing_serde_structured::{self as tss, AsSerde};
+
+use crate::{
+    callsite::{Frame, WirePayload},
+    format::{to_frame, Encoding, Framing},
+    Report, ReportPayload,
+};
+
+/// Assigns ids to callsites as the layer first sees them, and keeps their
+/// encoded `Callsite` frames so every new stream can be given all of them.
+#[derive(Default)]
+pub(crate) struct Registry {
+    inner: RwLock<RegistryInner>,
+    len: AtomicUsize,
+}
+
+#[derive(Default)]
+struct RegistryInner {
+    ids: HashMap<Identifier, u64>,
+    metadata: Vec<&'static Metadata<'static>>,
+    /// Each callsite's frame, as postcard with COBS.
+    frames: Vec<Vec<u8>>,
+    /// The same frames as plain postcard, for length-prefixed streams.
+    plain: Vec<Vec<u8>>,
+}
+
+impl Registry {
+    pub(crate) fn id(&self, metadata: &'static Metadata<'static>) -> u64 {
+        let key = metadata.callsite();
+        if let Some(id) = self.inner.read().unwrap_or_else(PoisonError::into_inner).ids.get(&key) {
+            return *id;
+        }
+
+        let mut inner = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(id) = inner.ids.get(&key) {
+            return *id;
+        }
+        let id = inner.frames.len() as u64;
+        let frame = callsite_frame(id, metadata);
+        inner.frames.push(to_frame(&frame, Framing::Cobs, Encoding::Postcard));
+        inner.plain.push(to_frame(&frame, Framing::LengthPrefixed, Encoding::Postcard));
+        inner.metadata.push(metadata);
+        inner.ids.insert(key, id);
+        self.len.store(inner.frames.len(), Ordering::Release);
+        id
+    }
+
+    /// Number of callsites registered so far.
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// Calls `f` with the postcard COBS frames of callsites `from..`.
+    pub(crate) fn frames_from(&self, from: usize, f: impl FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+        self.frames_from_in(from, Framing::Cobs, Encoding::Postcard, f)
+    }
+
+    /// Like `frames_from`, with the frames encoded as `report_frame` would
+    /// for `framing` and `encoding`. Only postcard frames are kept; others
+    /// are encoded on each call.
+    pub(crate) fn frames_from_in(
+        &self,
+        from: usize,
+        framing: Framing,
+        encoding: Encoding,
+        mut f: impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let inner = self.inner.read().unwrap_or_else(PoisonError::into_inner);
+        let frames = match (encoding, framing) {
+            (Encoding::Postcard, Framing::Cobs) => &inner.frames,
+            (Encoding::Postcard, Framing::LengthPrefixed) => &inner.plain,
+            #[cfg(feature = "bincode")]
+            (Encoding::Bincode, _) => {
+                for (id, metadata) in inner.metadata.iter().enumerate().skip(from) {
+                    f(&to_frame(&callsite_frame(id as u64, metadata), framing, encoding))?;
+                }
+                return Ok(());
+            }
+        };
+        for frame in frames.iter().skip(from) {
+            f(frame)?;
+        }
+        Ok(())
+    }
+}
+
+fn callsite_frame(id: u64, metadata: &'static Metadata<'static>) -> Frame<'static> {
+    Frame::Callsite {
+        id,
+        metadata: metadata.as_serde(),
+    }
+}
+
+/// The reader's side of the registry.
+#[derive(Default)]
+pub(crate) struct Callsites {
+    metadata: HashMap<u64, tss::SerializeMetadata<'static>>,
+}
+
+impl Callsites {
+    pub(crate) fn from_entries(entries: impl Iterator<Item = (u64, tss::SerializeMetadata<'static>)>) -> Self {
+        Self {
+            metadata: entries.collect(),
+        }
+    }
+
+    /// The callsites defined so far, by id.
+    pub(crate) fn entries(&self) -> Vec<(u64, tss::SerializeMetadata<'static>)> {
+        let mut entries: Vec<_> = self.metadata.iter().map(|(id, m)| (*id, m.to_owned())).collect();
+        entries.sort_unstable_by_key(|(id, _)| *id);
+        entries
+    }
+
+    /// Returns `None` for `Callsite` frames, which are only recorded.
+    pub(crate) fn resolve(&mut self, frame: Frame<'_>) -> Result<Option<Report<'static>>, String> {
+        let rpt = match frame {
+            Frame::Callsite { id, metadata } => {
+                self.metadata.insert(id, metadata.to_owned());
+                return Ok(None);
+            }
+            Frame::Report(rpt) => rpt,
+            Frame::Full(rpt) => return Ok(Some(rpt.to_owned())),
+        };
+
+        let metadata = |callsite: u64| {
+            self.metadata
+                .get(&callsite)
+                .map(|m| m.to_owned())
+                .ok_or_else(|| format!("unknown callsite {}", callsite))
+        };
+        let payload = match rpt.payload {
+            WirePayload::OnNewSpan {
+                callsite,
+                parent,
+                is_root,
+                id,
+            } => ReportPayload::OnNewSpan {
+                attrs: tss::SerializeAttributes {
+                    metadata: metadata(callsite)?,
+                    parent,
+                    is_root,
+                },
+                id,
+            },
+            WirePayload::OnEvent {
+                callsite,
+                fields,
+                parent,
+            } => ReportPayload::OnEvent {
+                event: tss::SerializeEvent {
+                    fields: fields.to_owned(),
+                    metadata: metadata(callsite)?,
+                    parent,
+                },
+            },
+            WirePayload::Other(payload) => payload.to_owned(),
+        };
+
+        Ok(Some(Report {
+            tick: rpt.tick,
+            seq: rpt.seq,
+            thread_id: rpt.thread_id,
+            payload,
+        }))
+    }
+}
@@ -0,0 +1,44 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+};
+
+use crate::{format::Compression, Shared};
+
+/// Keeps the most recent frames, up to `capacity` bytes in total.
+pub(crate) struct RingBuffer {
+    frames: VecDeque<Vec<u8>>,
+    bytes: usize,
+    capacity: usize,
+}
+
+impl RingBuffer {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            frames: VecDeque::new(),
+            bytes: 0,
+            capacity,
+        }
+    }
+
+    pub(crate) fn push(&mut self, frame: Vec<u8>) {
+        self.bytes += frame.len();
+        self.frames.push_back(frame);
+        while self.bytes > self.capacity {
+            match self.frames.pop_front() {
+                Some(old) => self.bytes -= old.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Writes the retained frames as a complete, uncompressed report stream.
+    pub(crate) fn dump_to<W: Write>(&self, w: &mut W, shared: &Shared) -> io::Result<()> {
+        shared.header(Compression::None).write_to(w)?;
+        shared.callsites.frames_from(0, |frame| w.write_all(frame))?;
+        for frame in self.frames.iter() {
+            w.write_all(frame)?;
+        }
+        w.flush()
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+};
+
+use tracing::field::{Field, Visit};
+use tracing_serde_structured::{CowString, DebugRecord, RecordMap, SerializeValue};
+use tracing_subscriber::field::RecordFields;
+
+/// Appended to values cut short by `max_value_len`.
+const ELLIPSIS: &str = "…";
+
+/// A field value that owns what it holds, unlike `SerializeValue`, which
+/// can borrow `Debug` arguments, so it can be kept in a span's extensions.
+pub(crate) enum OwnedValue {
+    F64(f64),
+    I64(i64),
+    U64(u64),
+    Bool(bool),
+    Str(String),
+    Debug(String),
+}
+
+impl From<OwnedValue> for SerializeValue<'static> {
+    fn from(value: OwnedValue) -> Self {
+        match value {
+            OwnedValue::F64(v) => SerializeValue::F64(v),
+            OwnedValue::I64(v) => SerializeValue::I64(v),
+            OwnedValue::U64(v) => SerializeValue::U64(v),
+            OwnedValue::Bool(v) => SerializeValue::Bool(v),
+            OwnedValue::Str(v) => SerializeValue::Str(CowString::Owned(v)),
+            OwnedValue::Debug(v) => SerializeValue::Debug(DebugRecord::De(CowString::Owned(v))),
+        }
+    }
+}
+
+/// Field values by name, as collected by `truncated_owned`.
+pub(crate) type OwnedFields = BTreeMap<&'static str, OwnedValue>;
+
+/// Collects `fields` into an owned map, cutting string and `Debug` values
+/// down to `max` bytes. `Debug` values are never formatted past that.
+pub(crate) fn truncated(fields: &impl RecordFields, max: usize) -> RecordMap<'static> {
+    to_record_map(truncated_owned(fields, max))
+}
+
+/// Like `truncated`, keeping the values as `OwnedValue`s.
+pub(crate) fn truncated_owned(fields: &impl RecordFields, max: usize) -> OwnedFields {
+    let mut visitor = Truncating {
+        max,
+        map: OwnedFields::new(),
+    };
+    fields.record(&mut visitor);
+    visitor.map
+}
+
+pub(crate) fn to_record_map(fields: OwnedFields) -> RecordMap<'static> {
+    let mut map = RecordMap::new();
+    for (name, value) in fields {
+        map.insert(CowString::Borrowed(name), value.into());
+    }
+    map
+}
+
+struct Truncating {
+    max: usize,
+    map: OwnedFields,
+}
+
+impl Truncating {
+    fn insert(&mut self, field: &Field, value: OwnedValue) {
+        self.map.insert(field.name(), value);
+    }
+}
+
+impl Visit for Truncating {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, OwnedValue::F64(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, OwnedValue::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, OwnedValue::U64(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, OwnedValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let mut buf = Bounded::new(self.max);
+        let _ = buf.write_str(value);
+        self.insert(field, OwnedValue::Str(buf.finish()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let mut buf = Bounded::new(self.max);
+        let _ = write!(buf, "{:?}", value);
+        self.insert(field, OwnedValue::Debug(buf.finish()));
+    }
+}
+
+/// A `String` that fails further writes once it's full, which stops the
+/// formatting early.
+struct Bounded {
+    buf: String,
+    max: usize,
+    truncated: bool,
+}
+
+impl Bounded {
+    fn new(max: usize) -> Self {
+        Self {
+            buf: String::new(),
+            max,
+            truncated: false,
+        }
+    }
+
+    fn finish(mut self) -> String {
+        if self.truncated {
+            self.buf.push_str(ELLIPSIS);
+        }
+        self.buf
+    }
+}
+
+impl Write for Bounded {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = self.max - self.buf.len();
+        if s.len() <= room {
+            self.buf.push_str(s);
+            return Ok(());
+        }
+        let mut end = room;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.buf.push_str(&s[..end]);
+        self.truncated = true;
+        Err(fmt::Error)
+    }
+}
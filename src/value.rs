@@ -0,0 +1,138 @@
+use std::fmt;
+
+use tracing_serde_structured::{DebugRecord, SerializeEvent, SerializeRecordFields, SerializeValue};
+
+/// Accessors for field values, so callers don't have to match every
+/// `SerializeValue` variant themselves.
+pub trait ValueExt {
+    /// Renders the value the way `tracing_subscriber::fmt` would. Use
+    /// `.display().to_string()` to get a `String`.
+    fn display(&self) -> DisplayValue<'_>;
+
+    /// Strings and already-formatted `Debug` values.
+    fn as_str(&self) -> Option<&str>;
+
+    /// Signed values, and unsigned values that fit.
+    fn as_i64(&self) -> Option<i64>;
+
+    /// Unsigned values, and signed values that aren't negative.
+    fn as_u64(&self) -> Option<u64>;
+
+    /// Floats, and integers converted to floats.
+    fn as_f64(&self) -> Option<f64>;
+
+    fn as_bool(&self) -> Option<bool>;
+}
+
+pub struct DisplayValue<'a>(&'a SerializeValue<'a>);
+
+impl fmt::Display for DisplayValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            SerializeValue::Debug(DebugRecord::Ser(args)) => write!(f, "{}", args),
+            SerializeValue::Debug(DebugRecord::De(x)) => f.write_str(x.as_str()),
+            SerializeValue::Str(x) => f.write_str(x.as_str()),
+            SerializeValue::F64(x) => write!(f, "{}", x),
+            SerializeValue::I64(x) => write!(f, "{}", x),
+            SerializeValue::U64(x) => write!(f, "{}", x),
+            SerializeValue::Bool(x) => write!(f, "{}", x),
+            _ => f.write_str("???"),
+        }
+    }
+}
+
+impl<'a> ValueExt for SerializeValue<'a> {
+    fn display(&self) -> DisplayValue<'_> {
+        DisplayValue(self)
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            SerializeValue::Str(x) => Some(x.as_str()),
+            SerializeValue::Debug(DebugRecord::De(x)) => Some(x.as_str()),
+            _ => None,
+        }
+    }
+
+    fn as_i64(&self) -> Option<i64> {
+        match *self {
+            SerializeValue::I64(x) => Some(x),
+            SerializeValue::U64(x) => i64::try_from(x).ok(),
+            _ => None,
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match *self {
+            SerializeValue::U64(x) => Some(x),
+            SerializeValue::I64(x) => u64::try_from(x).ok(),
+            _ => None,
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match *self {
+            SerializeValue::F64(x) => Some(x),
+            SerializeValue::I64(x) => Some(x as f64),
+            SerializeValue::U64(x) => Some(x as f64),
+            _ => None,
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match *self {
+            SerializeValue::Bool(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+/// Accessors for events that treat the `message` field specially, the way
+/// `tracing_subscriber::fmt` does.
+pub trait EventExt {
+    /// The `message` field, usually the text given to `info!` and friends.
+    fn message(&self) -> Option<DisplayValue<'_>>;
+
+    /// Every field but `message`, sorted by name.
+    fn attributes(&self) -> Vec<(&str, &SerializeValue<'_>)>;
+
+    /// The message followed by ` key=value` for each attribute, like a
+    /// `fmt` log line.
+    fn label(&self) -> String;
+}
+
+impl<'a> EventExt for SerializeEvent<'a> {
+    fn message(&self) -> Option<DisplayValue<'_>> {
+        match &self.fields {
+            SerializeRecordFields::De(fields) => fields
+                .iter()
+                .find(|(key, _)| key.as_str() == "message")
+                .map(|(_, val)| val.display()),
+            _ => None,
+        }
+    }
+
+    fn attributes(&self) -> Vec<(&str, &SerializeValue<'_>)> {
+        let mut attrs: Vec<(&str, &SerializeValue<'_>)> = match &self.fields {
+            SerializeRecordFields::De(fields) => fields
+                .iter()
+                .map(|(key, val)| (key.as_str(), val))
+                .filter(|(key, _)| *key != "message")
+                .collect(),
+            _ => vec![],
+        };
+        attrs.sort_unstable_by_key(|(key, _)| *key);
+        attrs
+    }
+
+    fn label(&self) -> String {
+        let mut label = self.message().map(|m| m.to_string()).unwrap_or_default();
+        for (key, val) in self.attributes() {
+            if !label.is_empty() {
+                label.push(' ');
+            }
+            label.push_str(&format!("{}={}", key, val.display()));
+        }
+        label
+    }
+}
@@ -0,0 +1,730 @@
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+    borrow::Cow,
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    net::{SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    process,
+    sync::{atomic::Ordering, mpsc::RecvTimeoutError, Arc},
+    thread::spawn,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    format::{push_frame, to_cobs, Compression, Encoding, Framing},
+    callsite::{Frame, WirePayload, WireReport},
+    encode::report_frame,
+    queue::{Capacity, Queue},
+    reader::decode,
+    registry::{Callsites, Registry},
+    Clock, Report, ReportPayload, Shared, SEQUENCE,
+};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use crate::mmap::{Mmap, MmapOverflow};
+
+pub(crate) type ErrorCallback = Box<dyn Fn(&io::Error) + Send>;
+
+/// How the writer holds on to frames before writing them to a file, socket
+/// or `sink`, for `ReportLayerBuilder::buffering`. UDP, `shared_append` and
+/// `mmap` outputs always take each frame as it comes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Buffering {
+    /// Each frame is written on its own, as soon as the writer has it.
+    None,
+    /// Like `None`, and the output is flushed after each frame, so that
+    /// someone following a pipe or a buffered `sink` sees it right away.
+    Line,
+    /// Frames are held until there are at least this many bytes of them,
+    /// or the output is synced, and then written at once.
+    Full(usize),
+}
+
+pub(crate) enum Target {
+    File(PathBuf),
+    /// A file other processes append to as well.
+    Append(PathBuf),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Udp(SocketAddr),
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    Mmap(PathBuf, usize, MmapOverflow),
+    /// A caller-supplied sink. It can't be reopened, so it's taken on open
+    /// and gone for good after its first error.
+    Writer(Option<Box<dyn Write + Send>>),
+}
+
+pub(crate) struct WriterConfig {
+    pub targets: Vec<Target>,
+    pub zstd: Option<i32>,
+    pub framing: Framing,
+    pub encoding: Encoding,
+    pub on_error: Option<ErrorCallback>,
+    pub batch_size: usize,
+    pub batch_timeout: Duration,
+    /// `None` writes a batch at a time.
+    pub buffering: Option<Buffering>,
+    /// Bounds the queue to the writer thread by total frame size, instead of
+    /// by `QUEUE_CAPACITY` frames.
+    pub queue_capacity_bytes: Option<usize>,
+    /// How long the queue has to be empty before the writer thread writes a
+    /// heartbeat, and the layer's clock to stamp it with.
+    pub heartbeat: Option<(Duration, Arc<dyn Clock>)>,
+}
+
+impl WriterConfig {
+    fn report(&self, err: &io::Error) {
+        if let Some(on_error) = self.on_error.as_ref() {
+            on_error(err);
+        }
+    }
+}
+
+enum Dest {
+    File(File),
+    /// Reports are written with their callsites inline, so `Callsites`
+    /// keeps the metadata of the ones registered so far.
+    Append(File, Box<Callsites>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Udp(UdpSocket),
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    Mmap(Mmap),
+    Writer(Box<dyn Write + Send>),
+}
+
+impl Write for Dest {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Dest::File(f) | Dest::Append(f, _) => f.write(buf),
+            #[cfg(unix)]
+            Dest::Unix(s) => s.write(buf),
+            Dest::Udp(s) => s.send(buf),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Dest::Mmap(m) => m.append(buf, || Ok(vec![])).map(|_| buf.len()),
+            Dest::Writer(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Dest::File(f) | Dest::Append(f, _) => f.sync_all(),
+            #[cfg(unix)]
+            Dest::Unix(s) => s.flush(),
+            Dest::Udp(_) => Ok(()),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Dest::Mmap(m) => m.flush(),
+            Dest::Writer(w) => w.flush(),
+        }
+    }
+}
+
+struct Output {
+    dest: Dest,
+    /// Length of the file up to the end of the last frame known to be
+    /// written. Unused for sockets, where every connection is a new stream.
+    committed: u64,
+    /// Frames accepted since `committed` was last advanced.
+    pending: u64,
+    /// Callsite frames written to this stream so far.
+    callsites: usize,
+    framing: Framing,
+    /// How the frames given to `write_reports` are encoded: in the writer's
+    /// framing, which is `framing` unless this output always uses COBS.
+    wire: Framing,
+    /// How the frames given to `write_reports` are serialized. A shared
+    /// file is always postcard, and re-encodes them if need be.
+    encoding: Encoding,
+    buffering: Option<Buffering>,
+    /// Frames held back by `Buffering::Full`, which count as pending.
+    held: Vec<u8>,
+    /// With zstd, each flush interval is compressed in memory and written as
+    /// one complete zstd frame, so a failed write never leaves a half-written
+    /// zstd frame in front of later data.
+    #[cfg(feature = "zstd")]
+    zstd: Option<(i32, zstd::Encoder<'static, Vec<u8>>)>,
+}
+
+impl Output {
+    /// Creates the file or connects the socket. When `resume_at` is set, a
+    /// file is reopened instead, and anything after the last committed frame
+    /// is truncated.
+    fn open(target: &mut Target, cfg: &WriterConfig, resume_at: Option<u64>, shared: &Shared) -> io::Result<Self> {
+        // Datagrams are sent one frame at a time, so there's nothing to
+        // compress, and a wrapping mmap overwrites frames one at a time,
+        // relying on COBS to skip what's left of them, as does a reader of a
+        // shared file between other processes' frames.
+        let whole_stream = match target {
+            Target::Udp(_) | Target::Append(_) => false,
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Target::Mmap(..) => false,
+            _ => true,
+        };
+        let zstd = cfg.zstd.filter(|_| whole_stream);
+        let framing = match whole_stream {
+            true => cfg.framing,
+            false => Framing::Cobs,
+        };
+        let header = shared
+            .header(match zstd {
+                None => Compression::None,
+                Some(_) => Compression::Zstd,
+            })
+            .with_framing(framing)
+            .with_encoding(match target {
+                Target::Append(_) => Encoding::Postcard,
+                _ => cfg.encoding,
+            });
+
+        let (dest, committed) = match target {
+            Target::File(path) => {
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(path)?;
+
+                // If the file was removed or replaced in the meantime, start over.
+                let resume_at = resume_at.filter(|&len| file.metadata().map(|m| m.len() >= len).unwrap_or(false));
+                let committed = match resume_at {
+                    Some(len) if len > 0 => {
+                        file.set_len(len)?;
+                        file.seek(SeekFrom::End(0))?;
+                        len
+                    }
+                    _ => {
+                        let header = header.encode();
+                        file.set_len(0)?;
+                        file.write_all(&header)?;
+                        header.len() as u64
+                    }
+                };
+                (Dest::File(file), committed)
+            }
+            Target::Append(path) => (Dest::Append(open_shared(path, &header.encode())?, Box::default()), 0),
+            #[cfg(unix)]
+            Target::Unix(path) => {
+                let mut stream = UnixStream::connect(path)?;
+                header.write_to(&mut stream)?;
+                (Dest::Unix(stream), 0)
+            }
+            Target::Udp(addr) => {
+                let bind: SocketAddr = match addr {
+                    SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                    SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+                };
+                let socket = UdpSocket::bind(bind)?;
+                socket.connect(*addr)?;
+                socket.send(&header.encode())?;
+                (Dest::Udp(socket), 0)
+            }
+            // Always starts over: what was mapped before may not have been
+            // written back.
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            Target::Mmap(path, len, overflow) => {
+                (Dest::Mmap(Mmap::create(path, *len, *overflow, &header.encode())?), 0)
+            }
+            Target::Writer(w) => {
+                let mut w = w.take().ok_or_else(|| io::Error::other("sink can't be reopened"))?;
+                header.write_to(&mut w)?;
+                (Dest::Writer(w), 0)
+            }
+        };
+
+        Ok(Self {
+            dest,
+            committed,
+            pending: 0,
+            callsites: 0,
+            framing,
+            wire: cfg.framing,
+            encoding: cfg.encoding,
+            buffering: cfg.buffering,
+            held: Vec::new(),
+            #[cfg(feature = "zstd")]
+            zstd: match zstd {
+                Some(level) => Some((level, zstd::Encoder::new(Vec::new(), level)?)),
+                None => None,
+            },
+        })
+    }
+
+    /// Writes report frames, preceded by any callsites they might refer to
+    /// that this stream doesn't have yet, in a single write. Over UDP, each
+    /// frame is sent as its own datagram instead. Returns how many reports
+    /// were left out: too large for a datagram or the mmap region, or, for a
+    /// shared file, undecodable.
+    fn write_reports(&mut self, batch: &[Vec<u8>], registry: &Registry) -> io::Result<u64> {
+        if let Dest::Udp(socket) = &self.dest {
+            if registry.len() > self.callsites {
+                registry.frames_from_in(self.callsites, Framing::Cobs, self.encoding, |frame| {
+                    self.callsites += 1;
+                    send_datagram(socket, frame).map(drop)
+                })?;
+            }
+            let mut oversized = 0;
+            for frame in batch {
+                if !send_datagram(socket, &cobs(frame, self.wire))? {
+                    oversized += 1;
+                }
+            }
+            return Ok(oversized);
+        }
+
+        if let Dest::Append(file, callsites) = &mut self.dest {
+            registry.frames_from(self.callsites, |frame| {
+                self.callsites += 1;
+                resolve(callsites, frame, Framing::Cobs, Encoding::Postcard).map(drop)
+            })?;
+            self.pending += batch.len() as u64;
+            let mut undecodable = 0;
+            for frame in batch {
+                // Callsite ids are this process's own, so reports that refer
+                // to one get the metadata inline; the rest go as they are,
+                // if they're postcard already.
+                let mut buf = frame.to_vec();
+                let frame = match decode(&mut buf, self.wire, self.encoding).map_err(|e| e.to_string()) {
+                    Ok(Frame::Report(WireReport {
+                        payload: WirePayload::Other(_),
+                        ..
+                    })) if self.encoding == Encoding::Postcard => Ok(Some(cobs(frame, self.wire))),
+                    Ok(decoded) => callsites
+                        .resolve(decoded)
+                        .map(|rpt| rpt.map(|rpt| Cow::Owned(rpt.to_cobs_frame()))),
+                    Err(e) => Err(e),
+                };
+                let frame = match frame {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => {
+                        self.pending -= 1;
+                        continue;
+                    },
+                    Err(_) => {
+                        undecodable += 1;
+                        self.pending -= 1;
+                        continue;
+                    },
+                };
+                // One `write` each, so it's appended whole or not at all.
+                if file.write(&frame)? != frame.len() {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "frame was only partly appended"));
+                }
+                self.pending -= 1;
+            }
+            return Ok(undecodable);
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        if let Dest::Mmap(map) = &mut self.dest {
+            let mut frames = vec![];
+            registry.frames_from_in(self.callsites, Framing::Cobs, self.encoding, |frame| {
+                self.callsites += 1;
+                frames.extend_from_slice(frame);
+                Ok(())
+            })?;
+            let all_callsites = || {
+                let mut all = vec![];
+                registry.frames_from_in(0, Framing::Cobs, self.encoding, |frame| {
+                    all.extend_from_slice(frame);
+                    Ok(())
+                })?;
+                Ok(all)
+            };
+            map.append(&frames, all_callsites)?;
+            let mut oversized = 0;
+            for frame in batch {
+                if !map.append(&cobs(frame, self.wire), all_callsites)? {
+                    oversized += 1;
+                }
+            }
+            return Ok(oversized);
+        }
+
+        let mut buf = Vec::new();
+        if registry.len() > self.callsites {
+            registry.frames_from_in(self.callsites, self.framing, self.encoding, |frame| {
+                self.callsites += 1;
+                push_frame(&mut buf, frame, self.framing);
+                Ok(())
+            })?;
+        }
+        match self.buffering {
+            Some(Buffering::None | Buffering::Line) => {
+                for frame in batch {
+                    push_frame(&mut buf, frame, self.framing);
+                    self.pending += 1;
+                    self.write_all(&buf)?;
+                    if self.buffering == Some(Buffering::Line) {
+                        self.flush_line()?;
+                    }
+                    buf.clear();
+                }
+                Ok(0)
+            }
+            Some(Buffering::Full(size)) => {
+                self.held.append(&mut buf);
+                for frame in batch {
+                    push_frame(&mut self.held, frame, self.framing);
+                }
+                self.pending += batch.len() as u64;
+                match self.held.len() >= size {
+                    true => self.write_held().map(|()| 0),
+                    false => Ok(0),
+                }
+            }
+            None => {
+                for frame in batch {
+                    push_frame(&mut buf, frame, self.framing);
+                }
+                self.pending += batch.len() as u64;
+                self.write_all(&buf).map(|()| 0)
+            }
+        }
+    }
+
+    fn write_held(&mut self) -> io::Result<()> {
+        let held = std::mem::take(&mut self.held);
+        self.write_all(&held)?;
+        // Keep the allocation for the next round.
+        self.held = held;
+        self.held.clear();
+        Ok(())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        #[cfg(feature = "zstd")]
+        if let Some((_, enc)) = self.zstd.as_mut() {
+            return enc.write_all(buf);
+        }
+
+        self.dest.write_all(buf)?;
+        self.committed += buf.len() as u64;
+        self.pending = 0;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if !self.held.is_empty() {
+            self.write_held()?;
+        }
+        self.finish_zstd()?;
+        self.dest.flush()
+    }
+
+    /// Writes out the frame after each report, for `Buffering::Line`,
+    /// without waiting for a file to reach the disk.
+    fn flush_line(&mut self) -> io::Result<()> {
+        self.finish_zstd()?;
+        match &mut self.dest {
+            #[cfg(unix)]
+            Dest::Unix(s) => s.flush(),
+            Dest::Writer(w) => w.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Ends the zstd frame compressed so far, and writes it.
+    fn finish_zstd(&mut self) -> io::Result<()> {
+        #[cfg(feature = "zstd")]
+        if let Some((level, enc)) = self.zstd.as_mut() {
+            if self.pending > 0 {
+                let enc = std::mem::replace(enc, zstd::Encoder::new(Vec::new(), *level)?);
+                let frame = enc.finish()?;
+                self.dest.write_all(&frame)?;
+                self.committed += frame.len() as u64;
+                self.pending = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes a frame from the layer back into a report.
+fn resolve(callsites: &mut Callsites, frame: &[u8], framing: Framing, encoding: Encoding) -> io::Result<Option<Report<'static>>> {
+    let mut frame = frame.to_vec();
+    let frame = decode(&mut frame, framing, encoding).map_err(io::Error::other)?;
+    callsites.resolve(frame).map_err(io::Error::other)
+}
+
+/// A frame from the layer, as COBS.
+fn cobs(frame: &[u8], framing: Framing) -> Cow<'_, [u8]> {
+    match framing {
+        Framing::Cobs => Cow::Borrowed(frame),
+        Framing::LengthPrefixed => Cow::Owned(to_cobs(frame)),
+    }
+}
+
+/// Opens `path` for appending, creating it with `header` first if it doesn't
+/// exist yet. The header is written to a file of its own, which is then
+/// linked into place, so no other process can append to `path` before the
+/// header is in.
+fn open_shared(path: &Path, header: &[u8]) -> io::Result<File> {
+    if !path.exists() {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}.tmp", process::id()));
+        let tmp = path.with_file_name(name);
+        fs::write(&tmp, header)?;
+        let linked = fs::hard_link(&tmp, path);
+        let _ = fs::remove_file(&tmp);
+        match linked {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+            _ => {}
+        }
+    }
+    OpenOptions::new().append(true).open(path)
+}
+
+/// Largest frame sent over UDP: a 1500 byte Ethernet MTU, less the IPv4 and
+/// UDP headers. Larger frames are dropped rather than fragmented.
+const MAX_DATAGRAM: usize = 1472;
+
+/// Returns `false` if `frame` was too large to send.
+fn send_datagram(socket: &UdpSocket, frame: &[u8]) -> io::Result<bool> {
+    if frame.len() > MAX_DATAGRAM {
+        return Ok(false);
+    }
+    socket.send(frame)?;
+    Ok(true)
+}
+
+/// Delay between attempts to reopen the file, or reconnect the socket, after
+/// an I/O error.
+struct Backoff {
+    delay: Duration,
+    retry_at: Instant,
+}
+
+impl Backoff {
+    const MIN: Duration = Duration::from_millis(100);
+    const MAX: Duration = Duration::from_secs(10);
+
+    fn new() -> Self {
+        Self {
+            delay: Self::MIN,
+            retry_at: Instant::now(),
+        }
+    }
+
+    fn ready(&self) -> bool {
+        Instant::now() >= self.retry_at
+    }
+
+    fn failed(&mut self) {
+        self.retry_at = Instant::now() + self.delay;
+        self.delay = (self.delay * 2).min(Self::MAX);
+    }
+
+    fn reset(&mut self) {
+        self.delay = Self::MIN;
+    }
+}
+
+/// One output target and its reopen state, so that a failing sink doesn't
+/// hold up the others.
+struct Slot {
+    target: Target,
+    out: Option<Output>,
+    resume_at: Option<u64>,
+    backoff: Backoff,
+}
+
+impl Slot {
+    fn new(target: Target) -> Self {
+        Self {
+            target,
+            out: None,
+            resume_at: None,
+            backoff: Backoff::new(),
+        }
+    }
+
+    /// A caller-supplied sink that has already failed.
+    fn is_dead(&self) -> bool {
+        matches!(self.target, Target::Writer(None)) && self.out.is_none()
+    }
+
+    fn open(&mut self, cfg: &WriterConfig, shared: &Shared) {
+        if self.out.is_some() || self.is_dead() || !self.backoff.ready() {
+            return;
+        }
+        match Output::open(&mut self.target, cfg, self.resume_at, shared) {
+            Ok(opened) => {
+                self.out = Some(opened);
+                self.backoff.reset();
+            }
+            Err(e) => self.fail(cfg, shared, e),
+        }
+    }
+
+    /// Runs `f` on the output, if it's open. On error, drops the output so
+    /// it's reopened on the next attempt.
+    fn with(&mut self, cfg: &WriterConfig, shared: &Shared, f: impl FnOnce(&mut Output) -> io::Result<()>) {
+        if let Some(Err(e)) = self.out.as_mut().map(f) {
+            self.fail(cfg, shared, e);
+        }
+    }
+
+    /// Frames that never made it to the output count as dropped.
+    fn fail(&mut self, cfg: &WriterConfig, shared: &Shared, err: io::Error) {
+        cfg.report(&err);
+        if let Some(out) = self.out.take() {
+            shared.dropped.fetch_add(out.pending, Ordering::Relaxed);
+            self.resume_at = Some(out.committed);
+        }
+        self.backoff.failed();
+    }
+}
+
+const QUEUE_CAPACITY: usize = 128;
+
+/// How often the outputs are synced, when nothing asks for it sooner.
+const SYNC_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The outputs, and what's needed to write to them. Runs on the writer
+/// thread, or inline with `synchronous`.
+///
+/// With more than one target, a report lost on several of them is counted
+/// as dropped once for each.
+pub(crate) struct Writer {
+    cfg: WriterConfig,
+    slots: Vec<Slot>,
+    last_sync: Instant,
+}
+
+impl Writer {
+    pub(crate) fn new(mut cfg: WriterConfig) -> Self {
+        Self {
+            slots: cfg.targets.drain(..).map(Slot::new).collect(),
+            cfg,
+            last_sync: Instant::now(),
+        }
+    }
+
+    /// Opens outputs that aren't open yet, and are due for another try.
+    fn open(&mut self, shared: &Shared) {
+        for slot in self.slots.iter_mut() {
+            slot.open(&self.cfg, shared);
+        }
+    }
+
+    fn deliver(&mut self, batch: &[Vec<u8>], shared: &Shared) {
+        let cfg = &self.cfg;
+        for slot in self.slots.iter_mut() {
+            if slot.out.is_none() && !slot.is_dead() {
+                shared.dropped.fetch_add(batch.len() as u64, Ordering::Relaxed);
+            }
+            slot.with(cfg, shared, |out| {
+                let oversized = out.write_reports(batch, &shared.callsites)?;
+                if oversized > 0 {
+                    shared.dropped.fetch_add(oversized, Ordering::Relaxed);
+                    cfg.report(&io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("dropped {} reports the output can't take", oversized),
+                    ));
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Returns whether every output is open, and synced.
+    pub(crate) fn sync(&mut self, shared: &Shared) -> bool {
+        for slot in self.slots.iter_mut() {
+            slot.with(&self.cfg, shared, Output::sync);
+        }
+        self.last_sync = Instant::now();
+        self.slots.iter().all(|slot| slot.out.is_some())
+    }
+
+    fn sync_if_due(&mut self, shared: &Shared) {
+        if self.last_sync.elapsed() > SYNC_INTERVAL {
+            self.sync(shared);
+        }
+    }
+
+    /// Writes one frame from the calling thread, for `synchronous` layers.
+    pub(crate) fn write_inline(&mut self, frame: Vec<u8>, shared: &Shared) {
+        self.open(shared);
+        self.deliver(std::slice::from_ref(&frame), shared);
+        self.sync_if_due(shared);
+    }
+}
+
+fn heartbeat_frame(interval: Duration, clock: &dyn Clock, framing: Framing, encoding: Encoding) -> Vec<u8> {
+    let payload = ReportPayload::Heartbeat {
+        interval_ns: u64::try_from(interval.as_nanos()).unwrap_or(u64::MAX),
+    };
+    report_frame(clock.now_nanos(), SEQUENCE.fetch_add(1, Ordering::Relaxed), 0, payload.into(), framing, encoding)
+}
+
+pub(crate) fn spawn_writer(cfg: WriterConfig, shared: Arc<Shared>) -> Arc<Queue<Vec<u8>>> {
+    let capacity = match cfg.queue_capacity_bytes {
+        Some(bytes) => Capacity::Bytes(bytes),
+        None => Capacity::Items(QUEUE_CAPACITY),
+    };
+    let queue = Arc::new(Queue::<Vec<u8>>::new(capacity));
+    let rx = queue.clone();
+    spawn(move || {
+        let batch_size = cfg.batch_size;
+        // Frames go out as they come, unless they're to be buffered.
+        let batch_timeout = match cfg.buffering {
+            Some(Buffering::None | Buffering::Line) => Duration::ZERO,
+            _ => cfg.batch_timeout,
+        };
+        let heartbeat = cfg.heartbeat.clone();
+        let (framing, encoding) = (cfg.framing, cfg.encoding);
+        let mut writer = Writer::new(cfg);
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut quiet_since = Instant::now();
+
+        loop {
+            writer.open(&shared);
+
+            // Write out everything queued so far, and sync, before acking.
+            let acks = shared.take_flush_requests();
+            if !acks.is_empty() {
+                while rx.recv_batch(&mut batch, batch_size, Duration::ZERO).is_ok() {
+                    writer.deliver(&batch, &shared);
+                    batch.clear();
+                }
+                let synced = writer.sync(&shared);
+                for ack in acks {
+                    let _ = ack.send(synced);
+                }
+            }
+
+            writer.sync_if_due(&shared);
+
+            if let Some((interval, clock)) = heartbeat.as_ref() {
+                if quiet_since.elapsed() >= *interval {
+                    writer.deliver(&[heartbeat_frame(*interval, clock.as_ref(), framing, encoding)], &shared);
+                    quiet_since = Instant::now();
+                }
+            }
+
+            match rx.recv_batch(&mut batch, batch_size, Duration::from_millis(25)) {
+                Ok(()) => {
+                    // Give the batch up to `batch_timeout` to fill up.
+                    let deadline = Instant::now() + batch_timeout;
+                    while batch.len() < batch_size {
+                        let left = deadline.saturating_duration_since(Instant::now());
+                        if left.is_zero() || rx.recv_batch(&mut batch, batch_size, left).is_err() {
+                            break;
+                        }
+                    }
+                    writer.deliver(&batch, &shared);
+                    batch.clear();
+                    quiet_since = Instant::now();
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    let synced = writer.sync(&shared);
+                    shared.writer_done(synced);
+                    return;
+                }
+            }
+        }
+    });
+    queue
+}